@@ -12,11 +12,13 @@ use tokio::runtime::Runtime;
 use tokio::sync::broadcast::{self, Sender};
 use tokio::task::JoinSet;
 
-use rust::bundler::{Bundler, Flashloan};
+use rust::bundler::{AccessListSource, Bundler, Flashloan, OrderParams};
 use rust::constants::{Env, ZERO_ADDRESS};
 use rust::multi::{batch_get_uniswap_v2_reserves, get_uniswap_v2_reserves};
 use rust::paths::generate_triangular_paths;
-use rust::pools::load_all_pools_from_v2;
+use rust::policy::Policy;
+use rust::pools::{load_all_pools_from_v2, PoolRegistry, RouterRegistry};
+use rust::selection::{estimate_gas_usage, gas_limit};
 use rust::streams::{stream_new_blocks, stream_pending_transactions, Event};
 use rust::utils::{calculate_next_block_base_fee, get_touched_pool_reserves};
 
@@ -35,6 +37,7 @@ pub async fn logging_event_handler(_: Arc<Provider<Ws>>, event_sender: Sender<Ev
                     writer.serialize((tx.hash, now)).unwrap();
                 }
                 Event::Log(_) => {}
+                Event::MevShare(_) => {}
             },
             Err(_) => {}
         }
@@ -66,6 +69,7 @@ pub async fn touched_pools_event_handler(provider: Arc<Provider<Ws>>, event_send
                 }
                 Event::PendingTx(_) => {}
                 Event::Log(_) => {}
+                Event::MevShare(_) => {}
             },
             Err(_) => {}
         }
@@ -151,7 +155,8 @@ pub fn benchmark_function(_: &mut Criterion) {
         let usdc_address = H160::from_str("0xA0b86991c6218b36c1d19D4a2e9Eb0cE3606eB48").unwrap();
 
         let s = Instant::now();
-        let paths = generate_triangular_paths(&pools, usdc_address);
+        let pool_registry = PoolRegistry::new(&pools);
+        let paths = generate_triangular_paths(&pool_registry, usdc_address, &Policy::default());
         let took = s.elapsed().as_millis();
         println!(
             "4. Generated {:?} 3-hop paths | Took: {:?} ms",
@@ -277,13 +282,15 @@ pub fn benchmark_function(_: &mut Criterion) {
             .unwrap();
         let usdc_address = H160::from_str("0xA0b86991c6218b36c1d19D4a2e9Eb0cE3606eB48").unwrap();
 
-        let paths = generate_triangular_paths(&pools, usdc_address);
+        let pool_registry = PoolRegistry::new(&pools);
+        let paths = generate_triangular_paths(&pool_registry, usdc_address, &Policy::default());
         let reserves = batch_get_uniswap_v2_reserves(env.https_url.clone(), pools).await;
+        let reserves = pool_registry.dense_reserves(&reserves);
 
         let took = paths.iter().map(|path| {
             let s = Instant::now();
-            let amount_in = U256::from(1);
-            match path.simulate_v2_path(amount_in, &reserves) {
+            let amount_in = U256::from(1_000_000);
+            match path.simulate_v2_path(amount_in, &pool_registry, &reserves) {
                 Some(_) => {}
                 None => {}
             };
@@ -308,7 +315,8 @@ pub fn benchmark_function(_: &mut Criterion) {
         let usdc_address = H160::from_str("0xA0b86991c6218b36c1d19D4a2e9Eb0cE3606eB48").unwrap();
         let usdc_decimals = 6;
 
-        let paths = generate_triangular_paths(&pools, usdc_address);
+        let pool_registry = PoolRegistry::new(&pools);
+        let paths = generate_triangular_paths(&pool_registry, usdc_address, &Policy::default());
 
         let unit = U256::from(10).pow(U256::from(usdc_decimals));
         let gwei = U256::from(10).pow(U256::from(9));
@@ -317,25 +325,24 @@ pub fn benchmark_function(_: &mut Criterion) {
         let block_number = bundler.provider.get_block_number().await.unwrap();
 
         let s = Instant::now();
-        let router_address = "0xd9e1cE17f2641f24aE83637ab66a2cca9C378B9F";
-        let routers = vec![
-            H160::from_str(router_address).unwrap(),
-            H160::from_str(router_address).unwrap(),
-            H160::from_str(router_address).unwrap(),
-        ];
+        let registry = RouterRegistry::with_known_dexes();
         let path = &paths[0];
-        let path_params = path.to_path_params(&routers);
-        let amount_in = U256::from(1) * unit;
-        let flashloan = Flashloan::NotUsed;
-        let loan_from = *ZERO_ADDRESS;
+        let path_params = path.to_path_params(&pool_registry, &registry).unwrap();
+        let order = OrderParams {
+            paths: path_params,
+            amount_in: U256::from(1) * unit,
+            flashloan: Flashloan::NotUsed,
+            loan_from: *ZERO_ADDRESS,
+            gas_limit: U256::from(gas_limit(estimate_gas_usage(path))),
+        };
+        let nonce = bundler._common_fields().await.unwrap().1;
         let max_priority_fee_per_gas = U256::from(1) * gwei;
         let max_fee_per_gas = U256::from(50) * gwei;
         let order_tx = bundler
             .order_tx(
-                path_params,
-                amount_in,
-                flashloan,
-                loan_from,
+                &order,
+                nonce,
+                AccessListSource::NotUsed,
                 max_priority_fee_per_gas,
                 max_fee_per_gas,
             )
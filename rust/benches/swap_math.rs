@@ -0,0 +1,107 @@
+use criterion::{black_box, criterion_group, criterion_main, Criterion};
+use ethers::types::U256;
+
+use rust::simulator::UniswapV2Simulator;
+
+/*
+Compares the u128 fast path against the U256 simulator on swaps recorded in
+benches/fixtures/v2_swaps.csv, so no node connection is needed.
+*/
+static FIXTURE: &str = "benches/fixtures/v2_swaps.csv";
+
+struct Swap {
+    reserve_in: u128,
+    reserve_out: u128,
+    amount_in: u128,
+    fee: u32,
+}
+
+fn load_swaps() -> Vec<Swap> {
+    let mut reader = csv::Reader::from_path(FIXTURE).unwrap();
+    reader
+        .records()
+        .map(|record| {
+            let record = record.unwrap();
+            Swap {
+                reserve_in: record[0].parse().unwrap(),
+                reserve_out: record[1].parse().unwrap(),
+                amount_in: record[2].parse().unwrap(),
+                fee: record[3].parse().unwrap(),
+            }
+        })
+        .collect()
+}
+
+pub fn swap_math_benchmark(c: &mut Criterion) {
+    let swaps = load_swaps();
+    let wide_swaps: Vec<(U256, U256, U256, U256)> = swaps
+        .iter()
+        .map(|swap| {
+            (
+                U256::from(swap.amount_in),
+                U256::from(swap.reserve_in),
+                U256::from(swap.reserve_out),
+                U256::from(swap.fee),
+            )
+        })
+        .collect();
+
+    // both implementations have to agree before timing them means anything
+    for (swap, wide) in swaps.iter().zip(wide_swaps.iter()) {
+        let fast = UniswapV2Simulator::get_amount_out_u128(
+            swap.amount_in,
+            swap.reserve_in,
+            swap.reserve_out,
+            swap.fee,
+        );
+        let slow = UniswapV2Simulator::get_amount_out_u256(wide.0, wide.1, wide.2, wide.3);
+        assert_eq!(fast.map(U256::from), slow);
+    }
+
+    let mut group = c.benchmark_group("get_amount_out");
+
+    group.bench_function("u256", |b| {
+        b.iter(|| {
+            for (amount_in, reserve_in, reserve_out, fee) in &wide_swaps {
+                black_box(UniswapV2Simulator::get_amount_out_u256(
+                    black_box(*amount_in),
+                    black_box(*reserve_in),
+                    black_box(*reserve_out),
+                    black_box(*fee),
+                ));
+            }
+        })
+    });
+
+    group.bench_function("u128", |b| {
+        b.iter(|| {
+            for swap in &swaps {
+                black_box(UniswapV2Simulator::get_amount_out_u128(
+                    black_box(swap.amount_in),
+                    black_box(swap.reserve_in),
+                    black_box(swap.reserve_out),
+                    black_box(swap.fee),
+                ));
+            }
+        })
+    });
+
+    // what simulate_v2_path calls, including the conversions from U256
+    group.bench_function("dispatch", |b| {
+        b.iter(|| {
+            for (amount_in, reserve_in, reserve_out, fee) in &wide_swaps {
+                black_box(UniswapV2Simulator::get_amount_out(
+                    black_box(*amount_in),
+                    black_box(*reserve_in),
+                    black_box(*reserve_out),
+                    black_box(*fee),
+                ));
+            }
+        })
+    });
+
+    group.finish();
+}
+
+criterion_group!(benches, swap_math_benchmark);
+criterion_main!(benches);
@@ -0,0 +1,189 @@
+use ethers::types::{H160, I256, U256};
+use std::fmt;
+
+// An amount of a token in its raw base units
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TokenAmount {
+    pub token: H160,
+    pub decimals: u8,
+    pub raw: U256,
+}
+
+impl TokenAmount {
+    pub fn new(token: H160, decimals: u8, raw: U256) -> Self {
+        Self {
+            token,
+            decimals,
+            raw,
+        }
+    }
+
+    pub fn zero(token: H160, decimals: u8) -> Self {
+        Self::new(token, decimals, U256::zero())
+    }
+
+    // Whole token units, e.g. 1000 USDC is 1000 * 10^6 base units
+    pub fn from_units(token: H160, decimals: u8, units: u64) -> Self {
+        Self::new(token, decimals, U256::from(units) * unit(decimals))
+    }
+
+    pub fn is_zero(&self) -> bool {
+        self.raw.is_zero()
+    }
+
+    pub fn with_raw(&self, raw: U256) -> Self {
+        Self::new(self.token, self.decimals, raw)
+    }
+
+    pub fn checked_add(&self, other: &TokenAmount) -> Option<TokenAmount> {
+        if self.token != other.token {
+            return None;
+        }
+        Some(self.with_raw(self.raw.checked_add(other.raw)?))
+    }
+
+    pub fn checked_sub(&self, other: &TokenAmount) -> Option<TokenAmount> {
+        if self.token != other.token {
+            return None;
+        }
+        Some(self.with_raw(self.raw.checked_sub(other.raw)?))
+    }
+}
+
+impl fmt::Display for TokenAmount {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", format_units(self.raw, self.decimals, false))
+    }
+}
+
+/*
+A signed amount of a token, what a path returns minus what it took in.
+Costs paid in other tokens must be converted before they're subtracted.
+*/
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Profit {
+    pub token: H160,
+    pub decimals: u8,
+    pub value: I256,
+}
+
+impl Profit {
+    pub fn zero(token: H160, decimals: u8) -> Self {
+        Self {
+            token,
+            decimals,
+            value: I256::zero(),
+        }
+    }
+
+    // None if the amounts are of different tokens
+    pub fn from_amounts(amount_out: &TokenAmount, amount_in: &TokenAmount) -> Option<Self> {
+        if amount_out.token != amount_in.token {
+            return None;
+        }
+        let value = to_signed(amount_out.raw).saturating_sub(to_signed(amount_in.raw));
+        Some(Self {
+            token: amount_in.token,
+            decimals: amount_in.decimals,
+            value,
+        })
+    }
+
+    pub fn is_positive(&self) -> bool {
+        self.value.is_positive()
+    }
+
+    // Subtract a cost already expressed in this token's base units
+    pub fn sub_cost(&self, cost: U256) -> Self {
+        Self {
+            token: self.token,
+            decimals: self.decimals,
+            value: self.value.saturating_sub(to_signed(cost)),
+        }
+    }
+
+    // The profit in base units, None unless it's positive
+    pub fn to_amount(&self) -> Option<TokenAmount> {
+        if !self.is_positive() {
+            return None;
+        }
+        Some(TokenAmount::new(
+            self.token,
+            self.decimals,
+            self.value.into_raw(),
+        ))
+    }
+}
+
+impl fmt::Display for Profit {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let magnitude = self.value.unsigned_abs();
+        write!(
+            f,
+            "{}",
+            format_units(magnitude, self.decimals, self.value.is_negative())
+        )
+    }
+}
+
+pub fn unit(decimals: u8) -> U256 {
+    U256::from(10).pow(U256::from(decimals))
+}
+
+fn to_signed(amount: U256) -> I256 {
+    I256::try_from(amount).unwrap_or(I256::MAX)
+}
+
+fn format_units(raw: U256, decimals: u8, negative: bool) -> String {
+    let unit = unit(decimals);
+    let whole = raw / unit;
+    let fraction = raw % unit;
+    let sign = if negative { "-" } else { "" };
+    if decimals == 0 {
+        return format!("{}{}", sign, whole);
+    }
+    format!(
+        "{}{}.{:0>width$}",
+        sign,
+        whole,
+        fraction.to_string(),
+        width = decimals as usize
+    )
+}
+
+#[cfg(test)]
+mod amounts_tests {
+    use super::*;
+
+    #[test]
+    fn token_amount_test() {
+        let usdc = H160::from_low_u64_be(1);
+        let amount = TokenAmount::from_units(usdc, 6, 1000);
+        assert_eq!(amount.raw, U256::from(1_000_000_000u64));
+        assert_eq!(
+            amount.with_raw(U256::from(1_500_001)).to_string(),
+            "1.500001"
+        );
+
+        let weth = TokenAmount::from_units(H160::from_low_u64_be(2), 18, 1);
+        assert_eq!(amount.checked_add(&weth), None);
+        assert_eq!(amount.checked_sub(&amount.with_raw(U256::MAX)), None);
+    }
+
+    #[test]
+    fn profit_test() {
+        let usdc = H160::from_low_u64_be(1);
+        let amount_in = TokenAmount::from_units(usdc, 6, 100);
+        let amount_out = amount_in.with_raw(U256::from(100_250_000u64));
+
+        let profit = Profit::from_amounts(&amount_out, &amount_in).unwrap();
+        assert!(profit.is_positive());
+        assert_eq!(profit.to_string(), "0.250000");
+        assert_eq!(profit.to_amount().unwrap().raw, U256::from(250_000u64));
+
+        let loss = profit.sub_cost(U256::from(1_000_000u64));
+        assert!(!loss.is_positive());
+        assert_eq!(loss.to_string(), "-0.750000");
+        assert_eq!(loss.to_amount(), None);
+    }
+}
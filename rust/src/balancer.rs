@@ -0,0 +1,239 @@
+use anyhow::Result;
+use ethers::{
+    abi,
+    prelude::abigen,
+    providers::{Http, Middleware, Provider},
+    types::{Filter, H160, H256, U256},
+};
+use ethers_contract::Multicall;
+use log::info;
+use std::{
+    collections::{HashMap, HashSet},
+    sync::Arc,
+    time::Instant,
+};
+
+use crate::pools::WeightedPool;
+
+abigen!(
+    BalancerPoolsVault,
+    r#"[
+        event PoolRegistered(bytes32 indexed poolId, address indexed poolAddress, uint8 specialization)
+        function getPoolTokens(bytes32 poolId) external view returns (address[] tokens, uint256[] balances, uint256 lastChangeBlock)
+    ]"#,
+);
+
+abigen!(
+    BalancerWeightedPool,
+    r#"[
+        function getNormalizedWeights() external view returns (uint256[])
+        function getSwapFeePercentage() external view returns (uint256)
+    ]"#,
+);
+
+abigen!(
+    Erc20Decimals,
+    r#"[
+        function decimals() external view returns (uint8)
+    ]"#,
+);
+
+// the block the vault was deployed in, no pool was registered before it
+pub const BALANCER_VAULT_BLOCK: u64 = 12272146;
+
+// pools per multicall, each takes a few calls
+const POOLS_PER_BATCH: usize = 50;
+// blocks per PoolRegistered log query
+const LOG_BLOCK_RANGE: u64 = 500_000;
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct WeightedState {
+    pub balances: Vec<U256>,
+    pub swap_fee: U256,
+}
+
+fn uint_list(token: &Result<abi::Token, ethers::types::Bytes>) -> Vec<U256> {
+    match token {
+        Ok(abi::Token::Array(tokens)) => tokens
+            .iter()
+            .filter_map(|t| t.clone().into_uint())
+            .collect(),
+        _ => Vec::new(),
+    }
+}
+
+// getPoolTokens' tokens and balances, empty if the call failed
+fn pool_tokens(token: &Result<abi::Token, ethers::types::Bytes>) -> (Vec<H160>, Vec<U256>) {
+    match token {
+        Ok(abi::Token::Tuple(values)) if values.len() == 3 => {
+            let tokens = match &values[0] {
+                abi::Token::Array(tokens) => tokens
+                    .iter()
+                    .filter_map(|t| t.clone().into_address())
+                    .collect(),
+                _ => Vec::new(),
+            };
+            (tokens, uint_list(&Ok(values[1].clone())))
+        }
+        _ => (Vec::new(), Vec::new()),
+    }
+}
+
+// (poolId, pool address) of every pool registered with the vault since from_block
+async fn registered_pools(
+    client: Arc<Provider<Http>>,
+    vault: H160,
+    from_block: u64,
+) -> Result<Vec<(H256, H160)>> {
+    let latest_block = client.get_block_number().await?.as_u64();
+    let mut registered = Vec::new();
+
+    for start in (from_block..=latest_block).step_by(LOG_BLOCK_RANGE as usize) {
+        let filter = Filter::new()
+            .address(vault)
+            .event("PoolRegistered(bytes32,address,uint8)")
+            .from_block(start)
+            .to_block((start + LOG_BLOCK_RANGE - 1).min(latest_block));
+        let logs = client.get_logs(&filter).await?;
+        registered.extend(
+            logs.iter()
+                .filter(|log| log.topics.len() == 3)
+                .map(|log| (log.topics[1], H160::from(log.topics[2]))),
+        );
+    }
+    Ok(registered)
+}
+
+/*
+Weighted pools registered with the vault, found from its PoolRegistered events.
+Only weighted pools answer getNormalizedWeights, so every other kind of pool drops out there,
+as do pools holding tokens with more than 18 decimals.
+Weights are read once: the pools that change them over time (LBPs, managed pools) are
+simulated with the weights as of the start.
+*/
+pub async fn load_weighted_pools(
+    https_url: String,
+    vault: H160,
+    from_block: u64,
+) -> Result<Vec<WeightedPool>> {
+    let start_time = Instant::now();
+    let client = Arc::new(Provider::<Http>::try_from(https_url)?);
+    let vault_contract = BalancerPoolsVault::new(vault, client.clone());
+    let mut multicall = Multicall::new(client.clone(), None).await?;
+
+    let registered = registered_pools(client.clone(), vault, from_block).await?;
+
+    let mut pools = Vec::new();
+    for batch in registered.chunks(POOLS_PER_BATCH) {
+        multicall.clear_calls();
+        for (pool_id, address) in batch {
+            let contract = BalancerWeightedPool::new(*address, client.clone());
+            multicall.add_call(contract.get_normalized_weights(), true);
+            multicall.add_call(contract.get_swap_fee_percentage(), true);
+            multicall.add_call(vault_contract.get_pool_tokens(pool_id.0), true);
+        }
+        let results = multicall.call_raw().await?;
+
+        for ((pool_id, address), result) in batch.iter().zip(results.chunks(3)) {
+            let weights = uint_list(&result[0]);
+            let swap_fee = match &result[1] {
+                Ok(abi::Token::Uint(fee)) => *fee,
+                _ => continue,
+            };
+            let (tokens, _) = pool_tokens(&result[2]);
+            if tokens.len() < 2 || weights.len() != tokens.len() {
+                continue;
+            }
+            pools.push(WeightedPool {
+                address: *address,
+                pool_id: *pool_id,
+                tokens,
+                decimals: Vec::new(),
+                weights,
+                swap_fee,
+                vault,
+            });
+        }
+    }
+
+    // decimals come from the tokens, the vault only knows addresses
+    let tokens: Vec<H160> = pools
+        .iter()
+        .flat_map(|pool| pool.tokens.iter().copied())
+        .collect::<HashSet<H160>>()
+        .into_iter()
+        .collect();
+    let mut decimals = HashMap::new();
+    for batch in tokens.chunks(POOLS_PER_BATCH * 3) {
+        multicall.clear_calls();
+        for token in batch {
+            let contract = Erc20Decimals::new(*token, client.clone());
+            multicall.add_call(contract.decimals(), true);
+        }
+        let results = multicall.call_raw().await?;
+        for (token, result) in batch.iter().zip(results) {
+            if let Some(value) = result.ok().and_then(|t| t.into_uint()) {
+                if value <= U256::from(18) {
+                    decimals.insert(*token, value.as_u32() as u8);
+                }
+            }
+        }
+    }
+
+    let pool_count = registered.len();
+    let pools: Vec<WeightedPool> = pools
+        .into_iter()
+        .filter_map(|pool| {
+            let decimals = pool
+                .tokens
+                .iter()
+                .map(|token| decimals.get(token).copied())
+                .collect::<Option<Vec<u8>>>()?;
+            Some(WeightedPool { decimals, ..pool })
+        })
+        .collect();
+
+    info!(
+        "Loaded {} Balancer weighted pools of {} registered in {} seconds",
+        pools.len(),
+        pool_count,
+        start_time.elapsed().as_secs()
+    );
+    Ok(pools)
+}
+
+/*
+Balances from the vault's getPoolTokens and the swap fee of every pool.
+Pools with a failed call, or whose tokens no longer match, are left out of the result.
+*/
+pub async fn sync_weighted_pools(
+    client: Arc<Provider<Http>>,
+    pools: &[WeightedPool],
+) -> Result<HashMap<H160, WeightedState>> {
+    let mut multicall = Multicall::new(client.clone(), None).await?;
+    let mut states = HashMap::new();
+
+    for batch in pools.chunks(POOLS_PER_BATCH) {
+        multicall.clear_calls();
+        for pool in batch {
+            let vault = BalancerPoolsVault::new(pool.vault, client.clone());
+            let contract = BalancerWeightedPool::new(pool.address, client.clone());
+            multicall.add_call(vault.get_pool_tokens(pool.pool_id.0), true);
+            multicall.add_call(contract.get_swap_fee_percentage(), true);
+        }
+        let results = multicall.call_raw().await?;
+
+        for (pool, result) in batch.iter().zip(results.chunks(2)) {
+            let (tokens, balances) = pool_tokens(&result[0]);
+            let swap_fee = match &result[1] {
+                Ok(abi::Token::Uint(fee)) => *fee,
+                _ => continue,
+            };
+            if tokens != pool.tokens || balances.len() != tokens.len() {
+                continue;
+            }
+            states.insert(pool.address, WeightedState { balances, swap_fee });
+        }
+    }
+    Ok(states)
+}
@@ -0,0 +1,190 @@
+use ethers::types::U256;
+
+use crate::amounts::Profit;
+use crate::bundler::{Flashloan, OrderParams, PathParam};
+use crate::flashloan::LoanSource;
+use crate::multi::Reserve;
+use crate::pools::{PoolId, PoolRegistry};
+use crate::selection::{gas_limit, Candidate};
+
+/*
+One of our orders in a bundle of several.
+profit is what it makes after the loan fee, on the reserves the orders before it leave.
+*/
+#[derive(Debug, Clone)]
+pub struct BatchOrder {
+    pub candidate: Candidate,
+    pub loan: LoanSource,
+    pub paths: Vec<PathParam>,
+    pub profit: Profit,
+}
+
+impl BatchOrder {
+    pub fn new(candidate: Candidate, loan: LoanSource, paths: Vec<PathParam>) -> Self {
+        let profit = candidate.score.profit.sub_cost(loan.fee);
+        Self {
+            candidate,
+            loan,
+            paths,
+            profit,
+        }
+    }
+
+    pub fn excess_profit(&self) -> Profit {
+        self.profit.sub_cost(self.candidate.gas_cost)
+    }
+
+    pub fn to_order_params(&self) -> OrderParams {
+        OrderParams {
+            paths: self.paths.clone(),
+            amount_in: self.candidate.score.amount_in.raw,
+            flashloan: self.loan.flashloan,
+            loan_from: self.loan.loan_from,
+            gas_limit: U256::from(gas_limit(self.candidate.gas_usage)),
+        }
+    }
+
+    /*
+    Runs the order on reserves, returns what its path gives out.
+    A V2 flashswap pair gets the borrowed amount back with the fee on top, so it keeps the fee.
+    */
+    pub fn apply(&self, pools: &PoolRegistry, reserves: &mut [Reserve]) -> Option<U256> {
+        let path = self.candidate.score.path;
+        let amount_out = path.apply_v2_path(self.candidate.score.amount_in.raw, pools, reserves)?;
+        if self.loan.flashloan == Flashloan::UniswapV2 {
+            let id = pools.id(&self.loan.loan_from)?;
+            let reserve = reserves.get_mut(id.index())?;
+            if pools[id].token0 == self.candidate.score.amount_in.token {
+                reserve.reserve0 += self.loan.fee;
+            } else {
+                reserve.reserve1 += self.loan.fee;
+            }
+        }
+        Some(amount_out)
+    }
+
+    // The pools the order trades through, and the pair it borrows from if any
+    pub fn pools(&self, pools: &PoolRegistry) -> Vec<PoolId> {
+        let path = self.candidate.score.path;
+        let mut used = path.pools[..path.nhop as usize].to_vec();
+        used.extend(pools.id(&self.loan.loan_from));
+        used
+    }
+}
+
+/*
+Simulates the orders one after another, each on the reserves the previous ones leave.
+An order that's no longer profitable after gas is dropped and doesn't move the reserves,
+the rest keep their order with profit updated.
+*/
+pub fn sequence_orders(
+    orders: Vec<BatchOrder>,
+    pools: &PoolRegistry,
+    reserves: &[Reserve],
+) -> Vec<BatchOrder> {
+    let mut reserves = reserves.to_vec();
+    orders
+        .into_iter()
+        .filter_map(|mut order| {
+            let amount_in = order.candidate.score.amount_in;
+            let mut after = reserves.clone();
+            let amount_out = order.apply(pools, &mut after)?;
+            let profit = Profit::from_amounts(&amount_in.with_raw(amount_out), &amount_in)?;
+            order.profit = profit.sub_cost(order.loan.fee);
+            if !order.excess_profit().is_positive() {
+                return None;
+            }
+            reserves = after;
+            Some(order)
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod batch_tests {
+    use super::*;
+    use crate::paths::ArbPath;
+    use crate::pools::PoolId;
+    use crate::scoring::PathScore;
+    use crate::test_utils::{pool, token, triangle};
+    use ethers::types::{H160, U256};
+
+    fn loan(fee: U256) -> LoanSource {
+        LoanSource {
+            flashloan: Flashloan::Balancer,
+            loan_from: H160::from_low_u64_be(99),
+            fee,
+        }
+    }
+
+    #[test]
+    fn sequence_orders_test() {
+        let (usdc, weth) = (token(0), token(1));
+        // pool 3 is a second usdc/weth pair to borrow from
+        let (mut pools, mut reserves) = triangle(usdc, weth, token(2));
+        pools.push(pool(3, usdc, weth));
+        reserves.push(reserves[0].clone());
+        let registry = PoolRegistry::new(&pools);
+        let e18 = U256::exp10(18);
+
+        let path = ArbPath::from_hops(&[(PoolId(0), true), (PoolId(1), true), (PoolId(2), false)])
+            .unwrap();
+        let (amount_in, profit) = path.optimize_amount_in(
+            U256::from(100_000u64) * e18,
+            U256::from(1_000u64) * e18,
+            &registry,
+            &reserves,
+        );
+        let score = PathScore {
+            path_id: 0,
+            path,
+            spread: profit,
+            amount_in,
+            profit,
+        };
+        let order = |fee: U256| {
+            BatchOrder::new(
+                Candidate::new(score, U256::from(10u64) * e18),
+                loan(fee),
+                Vec::new(),
+            )
+        };
+
+        let ordered = sequence_orders(vec![order(U256::zero())], &registry, &reserves);
+        assert_eq!(ordered.len(), 1);
+        assert_eq!(ordered[0].profit, profit);
+
+        // the second order would trade on reserves the first already moved
+        let ordered = sequence_orders(
+            vec![order(U256::zero()), order(U256::zero())],
+            &registry,
+            &reserves,
+        );
+        assert_eq!(ordered.len(), 1);
+
+        // a dropped order leaves the reserves for the next one
+        let ordered = sequence_orders(
+            vec![order(profit.value.into_raw()), order(U256::from(1))],
+            &registry,
+            &reserves,
+        );
+        assert_eq!(ordered.len(), 1);
+        assert_eq!(ordered[0].profit, profit.sub_cost(U256::from(1)));
+        assert_eq!(ordered[0].to_order_params().amount_in, amount_in.raw);
+
+        // a V2 flashswap pays its fee into the pair it borrowed from
+        let flashswap = BatchOrder::new(
+            Candidate::new(score, U256::from(10u64) * e18),
+            LoanSource {
+                flashloan: Flashloan::UniswapV2,
+                loan_from: registry[PoolId(3)].address,
+                fee: U256::from(7),
+            },
+            Vec::new(),
+        );
+        let mut after = reserves.clone();
+        flashswap.apply(&registry, &mut after).unwrap();
+        assert_eq!(after[3].reserve0, reserves[3].reserve0 + U256::from(7));
+        assert_eq!(after[3].reserve1, reserves[3].reserve1);
+    }
+}
@@ -1,9 +1,13 @@
 use anyhow::{anyhow, Result};
 use ethers::prelude::*;
 use ethers::types::{
-    transaction::{eip2718::TypedTransaction, eip2930::AccessList},
-    Address, Eip1559TransactionRequest, U256,
+    transaction::{
+        eip2718::TypedTransaction,
+        eip2930::{AccessList, AccessListItem, Eip2930TransactionRequest},
+    },
+    Address, Eip1559TransactionRequest, TransactionRequest, U256,
 };
+use ethers::utils::keccak256;
 use ethers::{
     abi,
     middleware::MiddlewareBuilder,
@@ -11,10 +15,15 @@ use ethers::{
     signers::{LocalWallet, Signer},
 };
 use ethers_flashbots::*;
+use log::info;
 use std::{str::FromStr, sync::Arc};
 use url::Url;
 
 use crate::constants::Env;
+use crate::mev_share::{
+    MevShareBundle, MevShareBundleItem, MevShareInclusion, MevSharePrivacy, MevShareRefund,
+    MevShareValidity, MEV_SHARE_RELAY_URL,
+};
 
 abigen!(
     ArbBot,
@@ -27,6 +36,7 @@ abigen!(
 #[derive(Debug, Clone)]
 pub struct PathParam {
     pub router: Address,
+    pub pair: Address,
     pub token_in: Address,
     pub token_out: Address,
 }
@@ -41,18 +51,139 @@ impl PathParam {
     }
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum Flashloan {
     NotUsed = 0,
     Balancer = 1,
     UniswapV2 = 2,
 }
 
+// Everything order_tx needs for one arbitrage apart from the fees
+#[derive(Debug, Clone)]
+pub struct OrderParams {
+    pub paths: Vec<PathParam>,
+    pub amount_in: U256,
+    pub flashloan: Flashloan,
+    pub loan_from: Address,
+    pub gas_limit: U256,
+}
+
+#[derive(Debug, Clone)]
+pub enum AccessListSource {
+    NotUsed,
+    Static,
+    Rpc,
+}
+
+#[derive(Debug, Clone)]
+pub struct AccessListGas {
+    pub without_list: U256,
+    pub with_list: U256,
+}
+
+// Uniswap V2 pair slots touched by a router swap:
+// token0 (6), token1 (7), reserves (8), price cumulatives (9, 10), unlocked (12)
+static V2_PAIR_SLOTS: [u64; 6] = [6, 7, 8, 9, 10, 12];
+
+pub fn static_access_list(paths: &Vec<PathParam>) -> AccessList {
+    let mut items: Vec<AccessListItem> = Vec::new();
+
+    let mut push = |address: Address, storage_keys: Vec<H256>| match items
+        .iter_mut()
+        .find(|item| item.address == address)
+    {
+        Some(item) => {
+            for key in storage_keys {
+                if !item.storage_keys.contains(&key) {
+                    item.storage_keys.push(key);
+                }
+            }
+        }
+        None => items.push(AccessListItem {
+            address,
+            storage_keys,
+        }),
+    };
+
+    for path in paths {
+        let pair_slots = V2_PAIR_SLOTS
+            .iter()
+            .map(|slot| H256::from_low_u64_be(*slot))
+            .collect();
+        push(path.router, Vec::new());
+        push(path.pair, pair_slots);
+        push(path.token_in, Vec::new());
+        push(path.token_out, Vec::new());
+    }
+
+    AccessList::from(items)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TxType {
+    Legacy,
+    Eip2930,
+    Eip1559,
+}
+
+impl FromStr for TxType {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.to_lowercase().as_str() {
+            "legacy" | "0" => Ok(TxType::Legacy),
+            "eip2930" | "1" => Ok(TxType::Eip2930),
+            "eip1559" | "2" => Ok(TxType::Eip1559),
+            _ => Err(anyhow!("Unknown tx type: {}", s)),
+        }
+    }
+}
+
+impl TxType {
+    pub fn default_for_chain(chain_id: U64) -> Self {
+        match chain_id.as_u64() {
+            // BNB Chain has no base fee
+            56 => TxType::Legacy,
+            _ => TxType::Eip1559,
+        }
+    }
+
+    pub fn from_env(env: &Env) -> Result<Self> {
+        match &env.tx_type {
+            Some(tx_type) => TxType::from_str(tx_type),
+            None => Ok(TxType::default_for_chain(env.chain_id)),
+        }
+    }
+}
+
+// Where legacy and EIP-2930 txs get their gas price from
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum GasPriceSource {
+    MaxFee,
+    Node,
+    Fixed(U256),
+}
+
+impl FromStr for GasPriceSource {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.to_lowercase().as_str() {
+            "max_fee" => Ok(GasPriceSource::MaxFee),
+            "node" => Ok(GasPriceSource::Node),
+            _ => Ok(GasPriceSource::Fixed(U256::from_dec_str(s)?)),
+        }
+    }
+}
+
 type SignerProvider = SignerMiddleware<Provider<Http>, LocalWallet>;
 
 pub struct Bundler {
     pub env: Env,
     pub sender: LocalWallet,
+    pub signer: LocalWallet,
+    pub tx_type: TxType,
+    pub gas_price_source: GasPriceSource,
     pub bot: ArbBot<SignerProvider>,
     pub provider: SignerProvider,
     pub flashbots: SignerMiddleware<FlashbotsMiddleware<SignerProvider, LocalWallet>, LocalWallet>,
@@ -81,17 +212,26 @@ impl Bundler {
             FlashbotsMiddleware::new(
                 provider.clone(),
                 Url::parse("https://relay.flashbots.net").unwrap(),
-                signer,
+                signer.clone(),
             ),
             sender.clone(),
         );
 
+        let tx_type = TxType::from_env(&env).unwrap();
+        let gas_price_source = match &env.gas_price_source {
+            Some(source) => GasPriceSource::from_str(source).unwrap(),
+            None => GasPriceSource::MaxFee,
+        };
+
         let client = Arc::new(provider.clone());
         let bot = ArbBot::new(env.bot_address.parse::<Address>().unwrap(), client.clone());
 
         Self {
             env,
             sender,
+            signer,
+            tx_type,
+            gas_price_source,
             bot,
             provider: provider,
             flashbots: flashbots,
@@ -106,8 +246,39 @@ impl Bundler {
         Ok((self.sender.address(), U256::from(nonce), self.env.chain_id))
     }
 
-    pub async fn sign_tx(&self, tx: Eip1559TransactionRequest) -> Result<Bytes> {
-        let typed = TypedTransaction::Eip1559(tx);
+    pub async fn to_typed_tx(&self, tx: Eip1559TransactionRequest) -> Result<TypedTransaction> {
+        if self.tx_type == TxType::Eip1559 {
+            return Ok(TypedTransaction::Eip1559(tx));
+        }
+
+        let gas_price = match &self.gas_price_source {
+            GasPriceSource::MaxFee => tx.max_fee_per_gas.unwrap_or_default(),
+            GasPriceSource::Node => self.provider.get_gas_price().await?,
+            GasPriceSource::Fixed(gas_price) => *gas_price,
+        };
+
+        let mut legacy = TransactionRequest::new();
+        legacy.from = tx.from;
+        legacy.to = tx.to;
+        legacy.gas = tx.gas;
+        legacy.gas_price = Some(gas_price);
+        legacy.value = tx.value;
+        legacy.data = tx.data;
+        legacy.nonce = tx.nonce;
+        legacy.chain_id = tx.chain_id;
+
+        if self.tx_type == TxType::Eip2930 {
+            Ok(TypedTransaction::Eip2930(Eip2930TransactionRequest {
+                tx: legacy,
+                access_list: tx.access_list,
+            }))
+        } else {
+            Ok(TypedTransaction::Legacy(legacy))
+        }
+    }
+
+    pub async fn sign_tx<T: Into<TypedTransaction>>(&self, tx: T) -> Result<Bytes> {
+        let typed: TypedTransaction = tx.into();
         let signature = self.sender.sign_transaction(&typed).await?;
         let signed = typed.rlp_signed(&signature);
         Ok(signed)
@@ -148,10 +319,92 @@ impl Bundler {
         Ok(bundle_hash)
     }
 
-    pub async fn send_tx(&self, tx: Eip1559TransactionRequest) -> Result<TxHash> {
+    pub fn to_mev_share_bundle(
+        &self,
+        hint_hashes: Vec<TxHash>,
+        signed_txs: Vec<Bytes>,
+        block_number: U64,
+        refund_percent: u64,
+        privacy: Option<MevSharePrivacy>,
+    ) -> MevShareBundle {
+        let mut body = Vec::new();
+
+        for hash in &hint_hashes {
+            body.push(MevShareBundleItem::Hash { hash: *hash });
+        }
+        for tx in signed_txs {
+            body.push(MevShareBundleItem::Tx {
+                tx,
+                can_revert: false,
+            });
+        }
+
+        // the refund is split evenly between the hint txs we backrun
+        let validity = if refund_percent > 0 && !hint_hashes.is_empty() {
+            let percent = refund_percent / hint_hashes.len() as u64;
+            Some(MevShareValidity {
+                refund: (0..hint_hashes.len())
+                    .map(|idx| MevShareRefund {
+                        body_idx: idx as u64,
+                        percent,
+                    })
+                    .collect(),
+                refund_config: Vec::new(),
+            })
+        } else {
+            None
+        };
+
+        MevShareBundle {
+            version: "v0.1".to_string(),
+            inclusion: MevShareInclusion {
+                block: block_number + 1,
+                max_block: None,
+            },
+            body,
+            validity,
+            privacy,
+        }
+    }
+
+    pub async fn send_mev_share_bundle(&self, bundle: MevShareBundle) -> Result<H256> {
+        let request = serde_json::json!({
+            "jsonrpc": "2.0",
+            "id": 1,
+            "method": "mev_sendBundle",
+            "params": [bundle],
+        });
+        let body = serde_json::to_string(&request)?;
+
+        // same auth scheme ethers_flashbots uses for eth_sendBundle
+        let message = format!("0x{}", hex::encode(keccak256(body.as_bytes())));
+        let signature = self.signer.sign_message(message).await?;
+        let flashbots_signature = format!("{:?}:0x{}", self.signer.address(), signature);
+
+        let response = reqwest::Client::new()
+            .post(MEV_SHARE_RELAY_URL)
+            .header("Content-Type", "application/json")
+            .header("X-Flashbots-Signature", flashbots_signature)
+            .body(body)
+            .send()
+            .await?
+            .text()
+            .await?;
+
+        let response: serde_json::Value = serde_json::from_str(&response)?;
+        if let Some(e) = response.get("error") {
+            return Err(anyhow!("mev_sendBundle error: {:?}", e));
+        }
+        let bundle_hash = response["result"]["bundleHash"]
+            .as_str()
+            .ok_or_else(|| anyhow!("mev_sendBundle returned no bundle hash"))?;
+        Ok(H256::from_str(bundle_hash)?)
+    }
+
+    // Returns once the node accepts the tx, waiting for inclusion is up to the caller
+    pub async fn send_tx<T: Into<TypedTransaction> + Send + Sync>(&self, tx: T) -> Result<TxHash> {
         let pending_tx = self.provider.send_transaction(tx, None).await?;
-        let receipt = pending_tx.await?.ok_or_else(|| anyhow!("Tx dropped"))?;
-        Ok(receipt.transaction_hash)
+        Ok(pending_tx.tx_hash())
     }
 
     pub async fn transfer_in_tx(
@@ -159,10 +412,10 @@ impl Bundler {
         amount_in: U256,
         max_priority_fee_per_gas: U256,
         max_fee_per_gas: U256,
-    ) -> Result<Eip1559TransactionRequest> {
+    ) -> Result<TypedTransaction> {
         let common = self._common_fields().await?;
         let to = NameOrAddress::Address(H160::from_str(&self.env.bot_address).unwrap());
-        Ok(Eip1559TransactionRequest {
+        let tx = Eip1559TransactionRequest {
             to: Some(to),
             from: Some(common.0),
             data: Some(Bytes(bytes::Bytes::new())),
@@ -173,7 +426,32 @@ impl Bundler {
             gas: Some(U256::from(60000)),
             nonce: Some(common.1),
             access_list: AccessList::default(),
-        })
+        };
+        self.to_typed_tx(tx).await
+    }
+
+    pub async fn cancel_tx(
+        &self,
+        nonce: U256,
+        max_priority_fee_per_gas: U256,
+        max_fee_per_gas: U256,
+    ) -> Result<TypedTransaction> {
+        // zero value transfer to ourselves, replacing whatever is pending with the same nonce
+        let common = self._common_fields().await?;
+        let to = NameOrAddress::Address(common.0);
+        let tx = Eip1559TransactionRequest {
+            to: Some(to),
+            from: Some(common.0),
+            data: Some(Bytes(bytes::Bytes::new())),
+            value: Some(U256::zero()),
+            chain_id: Some(common.2),
+            max_priority_fee_per_gas: Some(max_priority_fee_per_gas),
+            max_fee_per_gas: Some(max_fee_per_gas),
+            gas: Some(U256::from(21000)),
+            nonce: Some(nonce),
+            access_list: AccessList::default(),
+        };
+        self.to_typed_tx(tx).await
     }
 
     pub async fn transfer_out_tx(
@@ -181,13 +459,13 @@ impl Bundler {
         token: &str,
         max_priority_fee_per_gas: U256,
         max_fee_per_gas: U256,
-    ) -> Result<Eip1559TransactionRequest> {
+    ) -> Result<TypedTransaction> {
         let token_address = Address::from_str(token).unwrap();
         let calldata = self.bot.encode("recoverToken", (token_address,))?;
 
         let common = self._common_fields().await?;
         let to = NameOrAddress::Address(H160::from_str(&self.env.bot_address).unwrap());
-        Ok(Eip1559TransactionRequest {
+        let tx = Eip1559TransactionRequest {
             to: Some(to),
             from: Some(common.0),
             data: Some(calldata),
@@ -198,7 +476,8 @@ impl Bundler {
             gas: Some(U256::from(50000)),
             nonce: Some(common.1),
             access_list: AccessList::default(),
-        })
+        };
+        self.to_typed_tx(tx).await
     }
 
     pub async fn approve_tx(
@@ -208,7 +487,7 @@ impl Bundler {
         force: bool,
         max_priority_fee_per_gas: U256,
         max_fee_per_gas: U256,
-    ) -> Result<Eip1559TransactionRequest> {
+    ) -> Result<TypedTransaction> {
         let router_address = Address::from_str(router).unwrap();
         let token_addresses: Vec<Address> = tokens
             .iter()
@@ -221,7 +500,7 @@ impl Bundler {
         let token_cnt = tokens.len();
         let common = self._common_fields().await?;
         let to = NameOrAddress::Address(H160::from_str(&self.env.bot_address).unwrap());
-        Ok(Eip1559TransactionRequest {
+        let tx = Eip1559TransactionRequest {
             to: Some(to),
             from: Some(common.0),
             data: Some(calldata),
@@ -232,25 +511,27 @@ impl Bundler {
             gas: Some(U256::from(55000) * U256::from(token_cnt)),
             nonce: Some(common.1),
             access_list: AccessList::default(),
-        })
+        };
+        self.to_typed_tx(tx).await
     }
 
+    // nonce is passed in so orders signed together don't each look it up
     pub async fn order_tx(
         &self,
-        paths: Vec<PathParam>,
-        amount_in: U256,
-        flashloan: Flashloan,
-        loan_from: Address,
+        order: &OrderParams,
+        nonce: U256,
+        access_list_source: AccessListSource,
         max_priority_fee_per_gas: U256,
         max_fee_per_gas: U256,
-    ) -> Result<Eip1559TransactionRequest> {
+    ) -> Result<TypedTransaction> {
+        let paths = &order.paths;
         let nhop = paths.len();
 
         let mut params = Vec::new();
         params.extend(vec![
-            abi::Token::Uint(amount_in),
-            abi::Token::Uint(U256::from(flashloan as u64)),
-            abi::Token::Address(loan_from),
+            abi::Token::Uint(order.amount_in),
+            abi::Token::Uint(U256::from(order.flashloan as u64)),
+            abi::Token::Address(order.loan_from),
         ]);
 
         for i in 0..nhop {
@@ -260,19 +541,88 @@ impl Bundler {
         let encoded = abi::encode(&params);
         let calldata = Bytes::from(encoded);
 
-        let common = self._common_fields().await?;
         let to = NameOrAddress::Address(H160::from_str(&self.env.bot_address).unwrap());
-        Ok(Eip1559TransactionRequest {
+        let tx = Eip1559TransactionRequest {
             to: Some(to),
-            from: Some(common.0),
+            from: Some(self.sender.address()),
             data: Some(calldata),
             value: Some(U256::zero()),
-            chain_id: Some(common.2),
+            chain_id: Some(self.env.chain_id),
             max_priority_fee_per_gas: Some(max_priority_fee_per_gas),
             max_fee_per_gas: Some(max_fee_per_gas),
-            gas: Some(U256::from(600000)),
-            nonce: Some(common.1),
+            gas: Some(order.gas_limit),
+            nonce: Some(nonce),
             access_list: AccessList::default(),
+        };
+
+        let tx = self.to_typed_tx(tx).await?;
+
+        let access_list = match access_list_source {
+            AccessListSource::NotUsed => return Ok(tx),
+            // legacy txs can't carry an access list
+            _ if self.tx_type == TxType::Legacy => return Ok(tx),
+            AccessListSource::Static => static_access_list(paths),
+            AccessListSource::Rpc => {
+                self.provider
+                    .create_access_list(&tx, None)
+                    .await?
+                    .access_list
+            }
+        };
+
+        // only keep the access list if it actually makes the order cheaper
+        let gas = self.compare_access_list_gas(&tx, &access_list).await?;
+        info!("Order gas usage with access list: {:?}", gas);
+        if gas.with_list < gas.without_list {
+            let mut tx = tx;
+            tx.set_access_list(access_list);
+            Ok(tx)
+        } else {
+            Ok(tx)
+        }
+    }
+
+    /*
+    Signs the orders back to back from the sender's next nonce, so they can go in one bundle.
+    They execute in the given order, each on the state the previous one leaves.
+    */
+    pub async fn sign_orders(
+        &self,
+        orders: &[OrderParams],
+        access_list_source: AccessListSource,
+        max_priority_fee_per_gas: U256,
+        max_fee_per_gas: U256,
+    ) -> Result<Vec<Bytes>> {
+        let (_, nonce, _) = self._common_fields().await?;
+
+        let mut signed_txs = Vec::new();
+        for (i, order) in orders.iter().enumerate() {
+            let tx = self
+                .order_tx(
+                    order,
+                    nonce + i,
+                    access_list_source.clone(),
+                    max_priority_fee_per_gas,
+                    max_fee_per_gas,
+                )
+                .await?;
+            signed_txs.push(self.sign_tx(tx).await?);
+        }
+        Ok(signed_txs)
+    }
+
+    pub async fn compare_access_list_gas(
+        &self,
+        tx: &TypedTransaction,
+        access_list: &AccessList,
+    ) -> Result<AccessListGas> {
+        let mut without_list = tx.clone();
+        without_list.set_access_list(AccessList::default());
+        let mut with_list = tx.clone();
+        with_list.set_access_list(access_list.clone());
+        Ok(AccessListGas {
+            without_list: self.provider.estimate_gas(&without_list, None).await?,
+            with_list: self.provider.estimate_gas(&with_list, None).await?,
         })
     }
 }
@@ -323,15 +673,23 @@ mod bundler_tests {
 
         let paths = vec![PathParam {
             router: Address::from_str("0x1b02dA8Cb0d097eB8D57A175b88c7D8b47997506").unwrap(),
+            pair: Address::from_str("0x604229c960e5CACF2aaEAc8Be68Ac07BA9dF81c3").unwrap(),
             token_in: Address::from_str("0x0d500B1d8E8eF31E21C99d1Db9A6444d3ADf1270").unwrap(),
             token_out: Address::from_str("0xc2132D05D31c914a87C6611C10748AEb04B58e8F").unwrap(),
         }];
+        let order = OrderParams {
+            paths,
+            amount_in: U256::from(1) * *WEI,
+            flashloan: Flashloan::Balancer,
+            loan_from: Address::from_str("0xBA12222222228d8Ba445958a75a0704d566BF2C8").unwrap(),
+            gas_limit: U256::from(600000),
+        };
+        let nonce = bundler._common_fields().await.unwrap().1;
         let tx = bundler
             .order_tx(
-                paths,
-                U256::from(1) * *WEI,
-                Flashloan::Balancer,
-                Address::from_str("0xBA12222222228d8Ba445958a75a0704d566BF2C8").unwrap(),
+                &order,
+                nonce,
+                AccessListSource::NotUsed,
                 U256::from(100) * *GWEI,
                 U256::from(300) * *GWEI,
             )
@@ -340,4 +698,48 @@ mod bundler_tests {
         // let tx_hash = bundler.send_tx(tx).await?;
         // println!("{:?}", tx_hash);
     }
+
+    #[test]
+    fn static_access_list_test() {
+        let router = Address::from_str("0xd9e1cE17f2641f24aE83637ab66a2cca9C378B9F").unwrap();
+        let usdc = Address::from_str("0xA0b86991c6218b36c1d19D4a2e9Eb0cE3606eB48").unwrap();
+        let weth = Address::from_str("0xC02aaA39b223FE8D0A0e5C4F27eAD9083C756Cc2").unwrap();
+        let usdc_weth = Address::from_str("0x397FF1542f962076d0BFE58eA045FfA2d347ACa0").unwrap();
+        let paths = vec![
+            PathParam {
+                router,
+                pair: usdc_weth,
+                token_in: usdc,
+                token_out: weth,
+            },
+            PathParam {
+                router,
+                pair: usdc_weth,
+                token_in: weth,
+                token_out: usdc,
+            },
+        ];
+
+        let access_list = static_access_list(&paths);
+        let addresses: Vec<Address> = access_list.0.iter().map(|item| item.address).collect();
+        assert_eq!(addresses, vec![router, usdc_weth, usdc, weth]);
+        assert_eq!(access_list.0[1].storage_keys.len(), V2_PAIR_SLOTS.len());
+        assert!(access_list.0[0].storage_keys.is_empty());
+    }
+
+    #[test]
+    fn tx_type_test() {
+        assert_eq!(TxType::default_for_chain(U64::from(137)), TxType::Eip1559);
+        assert_eq!(TxType::default_for_chain(U64::from(56)), TxType::Legacy);
+        assert_eq!(TxType::from_str("EIP2930").unwrap(), TxType::Eip2930);
+        assert_eq!(
+            GasPriceSource::from_str("node").unwrap(),
+            GasPriceSource::Node
+        );
+        assert_eq!(
+            GasPriceSource::from_str("30000000000").unwrap(),
+            GasPriceSource::Fixed(U256::from(30) * *GWEI)
+        );
+        assert!(GasPriceSource::from_str("fast").is_err());
+    }
 }
@@ -1,6 +1,6 @@
 use ethers::{
     prelude::Lazy,
-    types::{Address, H160, U256, U64},
+    types::{Address, U256, U64},
 };
 use std::str::FromStr;
 
@@ -10,18 +10,39 @@ pub static GWEI: Lazy<U256> = Lazy::new(|| U256::from(10).pow(U256::from(9)));
 pub static ZERO_ADDRESS: Lazy<Address> =
     Lazy::new(|| Address::from_str("0x0000000000000000000000000000000000000000").unwrap());
 
+// Denied on top of whatever the policy file says, so a missing policy.json can't let them through
+pub static BLACKLIST_TOKENS: Lazy<Vec<Address>> = Lazy::new(|| {
+    ["0x9469603F3Efbcf17e4A5868d81C701BDbD222555"]
+        .into_iter()
+        .map(|addr| Address::from_str(addr).unwrap())
+        .collect()
+});
+
 pub fn get_env(key: &str) -> String {
     std::env::var(key).unwrap()
 }
 
+pub fn get_env_opt(key: &str) -> Option<String> {
+    std::env::var(key).ok().filter(|value| !value.is_empty())
+}
+
 #[derive(Debug, Clone)]
 pub struct Env {
     pub https_url: String,
     pub wss_url: String,
     pub chain_id: U64,
+    pub blocknative_token: Option<String>,
     pub private_key: String,
     pub signing_key: String,
     pub bot_address: String,
+    pub execution_route: Option<String>,
+    pub private_rpc_url: Option<String>,
+    // blocks a private tx stays valid for after the one it targets
+    pub private_rpc_max_block_delta: u64,
+    pub tx_type: Option<String>,
+    pub gas_price_source: Option<String>,
+    pub policy_path: String,
+    pub fork_url: Option<String>,
 }
 
 impl Env {
@@ -30,20 +51,23 @@ impl Env {
             https_url: get_env("HTTPS_URL"),
             wss_url: get_env("WSS_URL"),
             chain_id: U64::from_str(&get_env("CHAIN_ID")).unwrap(),
+            blocknative_token: get_env_opt("BLOCKNATIVE_TOKEN"),
             private_key: get_env("PRIVATE_KEY"),
             signing_key: get_env("SIGNING_KEY"),
             bot_address: get_env("BOT_ADDRESS"),
+            execution_route: get_env_opt("EXECUTION_ROUTE"),
+            private_rpc_url: get_env_opt("PRIVATE_RPC_URL"),
+            private_rpc_max_block_delta: get_env_opt("PRIVATE_RPC_MAX_BLOCK_DELTA")
+                .map(|delta| delta.parse().unwrap())
+                .unwrap_or(25),
+            tx_type: get_env_opt("TX_TYPE"),
+            gas_price_source: get_env_opt("GAS_PRICE_SOURCE"),
+            policy_path: get_env_opt("POLICY_PATH").unwrap_or_else(|| "policy.json".to_string()),
+            fork_url: get_env_opt("FORK_URL"),
         }
     }
 }
 
-pub fn get_blacklist_tokens() -> Vec<H160> {
-    vec!["0x9469603F3Efbcf17e4A5868d81C701BDbD222555"]
-        .into_iter()
-        .map(|addr| H160::from_str(addr).unwrap())
-        .collect()
-}
-
 // Use later for broadcasting to multiple builders
 // static BUILDER_URLS: &[&str] = &[
 //     "https://builder0x69.io",
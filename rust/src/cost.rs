@@ -0,0 +1,258 @@
+use anyhow::Result;
+use ethers::{
+    prelude::abigen,
+    providers::Middleware,
+    types::{Address, Bytes, U256, U64},
+};
+use std::{str::FromStr, sync::Arc};
+
+abigen!(
+    OpGasPriceOracle,
+    r#"[
+        function l1BaseFee() external view returns (uint256)
+        function overhead() external view returns (uint256)
+        function scalar() external view returns (uint256)
+        function decimals() external view returns (uint256)
+        function isEcotone() external view returns (bool)
+        function baseFeeScalar() external view returns (uint32)
+        function blobBaseFee() external view returns (uint256)
+        function blobBaseFeeScalar() external view returns (uint32)
+    ]"#,
+);
+
+abigen!(
+    ArbGasInfo,
+    r#"[
+        function getPricesInWei() external view returns (uint256, uint256, uint256, uint256, uint256, uint256)
+    ]"#,
+);
+
+pub static OP_GAS_PRICE_ORACLE: &str = "0x420000000000000000000000000000000000000F";
+pub static ARB_GAS_INFO: &str = "0x000000000000000000000000000000000000006C";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum L1FeeModel {
+    NotUsed,
+    OpStack,
+    Arbitrum,
+}
+
+impl L1FeeModel {
+    pub fn for_chain(chain_id: U64) -> Self {
+        match chain_id.as_u64() {
+            // Optimism, Base, Zora, Mode
+            10 | 8453 | 7777777 | 34443 => L1FeeModel::OpStack,
+            // Arbitrum One, Arbitrum Nova
+            42161 | 42170 => L1FeeModel::Arbitrum,
+            _ => L1FeeModel::NotUsed,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum L1FeeParams {
+    NotUsed,
+    Bedrock {
+        l1_base_fee: U256,
+        overhead: U256,
+        scalar: U256,
+        decimals: U256,
+    },
+    Ecotone {
+        l1_base_fee: U256,
+        base_fee_scalar: U256,
+        blob_base_fee: U256,
+        blob_base_fee_scalar: U256,
+    },
+    Arbitrum {
+        price_per_l1_byte: U256,
+    },
+}
+
+// The calldata gas L1 charges for posting these bytes: 4 per zero byte, 16 per non-zero byte
+pub fn calldata_gas(data: &[u8]) -> U256 {
+    let gas: u64 = data
+        .iter()
+        .map(|byte| if *byte == 0 { 4 } else { 16 })
+        .sum();
+    U256::from(gas)
+}
+
+impl L1FeeParams {
+    pub fn l1_fee(&self, signed_tx: &Bytes) -> U256 {
+        let l1_gas = calldata_gas(signed_tx);
+        match self {
+            L1FeeParams::NotUsed => U256::zero(),
+            L1FeeParams::Bedrock {
+                l1_base_fee,
+                overhead,
+                scalar,
+                decimals,
+            } => (l1_gas + overhead) * l1_base_fee * scalar / U256::from(10).pow(*decimals),
+            L1FeeParams::Ecotone {
+                l1_base_fee,
+                base_fee_scalar,
+                blob_base_fee,
+                blob_base_fee_scalar,
+            } => {
+                let scaled_base_fee = base_fee_scalar * U256::from(16) * l1_base_fee;
+                let scaled_blob_base_fee = blob_base_fee_scalar * blob_base_fee;
+                l1_gas * (scaled_base_fee + scaled_blob_base_fee) / U256::from(16_000_000)
+            }
+            L1FeeParams::Arbitrum { price_per_l1_byte } => {
+                // Arbitrum prices the brotli compressed tx, weighting bytes like calldata
+                // gas is a close enough stand-in without running the compressor
+                price_per_l1_byte * l1_gas / U256::from(16)
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct CostModel {
+    pub l1_fee_model: L1FeeModel,
+    pub l1_fee_params: L1FeeParams,
+}
+
+impl CostModel {
+    pub fn new(chain_id: U64) -> Self {
+        Self {
+            l1_fee_model: L1FeeModel::for_chain(chain_id),
+            l1_fee_params: L1FeeParams::NotUsed,
+        }
+    }
+
+    pub fn has_l1_fee(&self) -> bool {
+        self.l1_fee_model != L1FeeModel::NotUsed
+    }
+
+    // Refresh the chain's L1 fee parameters, these move with the L1 base fee
+    pub async fn update<M: Middleware + 'static>(&mut self, provider: Arc<M>) -> Result<()> {
+        self.l1_fee_params = match self.l1_fee_model {
+            L1FeeModel::NotUsed => L1FeeParams::NotUsed,
+            L1FeeModel::OpStack => {
+                let oracle = OpGasPriceOracle::new(
+                    Address::from_str(OP_GAS_PRICE_ORACLE).unwrap(),
+                    provider,
+                );
+                // isEcotone doesn't exist before the Ecotone upgrade and reverts
+                let is_ecotone = oracle.is_ecotone().call().await.unwrap_or(false);
+                if is_ecotone {
+                    let calls = (
+                        oracle.l_1_base_fee(),
+                        oracle.base_fee_scalar(),
+                        oracle.blob_base_fee(),
+                        oracle.blob_base_fee_scalar(),
+                    );
+                    let (l1_base_fee, base_fee_scalar, blob_base_fee, blob_base_fee_scalar) = tokio::try_join!(
+                        calls.0.call(),
+                        calls.1.call(),
+                        calls.2.call(),
+                        calls.3.call(),
+                    )?;
+                    L1FeeParams::Ecotone {
+                        l1_base_fee,
+                        base_fee_scalar: U256::from(base_fee_scalar),
+                        blob_base_fee,
+                        blob_base_fee_scalar: U256::from(blob_base_fee_scalar),
+                    }
+                } else {
+                    let calls = (
+                        oracle.l_1_base_fee(),
+                        oracle.overhead(),
+                        oracle.scalar(),
+                        oracle.decimals(),
+                    );
+                    let (l1_base_fee, overhead, scalar, decimals) = tokio::try_join!(
+                        calls.0.call(),
+                        calls.1.call(),
+                        calls.2.call(),
+                        calls.3.call(),
+                    )?;
+                    L1FeeParams::Bedrock {
+                        l1_base_fee,
+                        overhead,
+                        scalar,
+                        decimals,
+                    }
+                }
+            }
+            L1FeeModel::Arbitrum => {
+                let gas_info = ArbGasInfo::new(Address::from_str(ARB_GAS_INFO).unwrap(), provider);
+                let prices = gas_info.get_prices_in_wei().call().await?;
+                L1FeeParams::Arbitrum {
+                    price_per_l1_byte: prices.1,
+                }
+            }
+        };
+        Ok(())
+    }
+
+    pub fn l1_fee(&self, signed_tx: &Bytes) -> U256 {
+        self.l1_fee_params.l1_fee(signed_tx)
+    }
+
+    pub fn gas_cost(&self, gas_used: U256, gas_price: U256, signed_tx: &Bytes) -> U256 {
+        gas_used * gas_price + self.l1_fee(signed_tx)
+    }
+}
+
+#[cfg(test)]
+mod cost_tests {
+    use super::*;
+
+    #[test]
+    fn calldata_gas_test() {
+        assert_eq!(calldata_gas(&[0, 0, 1, 255]), U256::from(40));
+        assert_eq!(calldata_gas(&[]), U256::zero());
+    }
+
+    #[test]
+    fn l1_fee_test() {
+        // 100 zero bytes and 100 non-zero bytes: 2000 L1 gas
+        let mut signed_tx = vec![0u8; 100];
+        signed_tx.extend(vec![1u8; 100]);
+        let signed_tx = Bytes::from(signed_tx);
+
+        let bedrock = L1FeeParams::Bedrock {
+            l1_base_fee: U256::from(30_000_000_000u64),
+            overhead: U256::from(188),
+            scalar: U256::from(684_000),
+            decimals: U256::from(6),
+        };
+        // (2000 + 188) * 30 gwei * 0.684
+        assert_eq!(
+            bedrock.l1_fee(&signed_tx),
+            U256::from(44_897_760_000_000u64)
+        );
+
+        let ecotone = L1FeeParams::Ecotone {
+            l1_base_fee: U256::from(30_000_000_000u64),
+            base_fee_scalar: U256::from(1368),
+            blob_base_fee: U256::from(1),
+            blob_base_fee_scalar: U256::from(810_949),
+        };
+        // 2000 * (1368 * 16 * 30 gwei + 810949 * 1) / 16e6
+        assert_eq!(ecotone.l1_fee(&signed_tx), U256::from(82_080_000_101u64));
+
+        let arbitrum = L1FeeParams::Arbitrum {
+            price_per_l1_byte: U256::from(480_000_000_000u64),
+        };
+        assert_eq!(
+            arbitrum.l1_fee(&signed_tx),
+            U256::from(60_000_000_000_000u64)
+        );
+
+        assert_eq!(L1FeeParams::NotUsed.l1_fee(&signed_tx), U256::zero());
+    }
+
+    #[test]
+    fn l1_fee_model_test() {
+        assert_eq!(L1FeeModel::for_chain(U64::from(10)), L1FeeModel::OpStack);
+        assert_eq!(
+            L1FeeModel::for_chain(U64::from(42161)),
+            L1FeeModel::Arbitrum
+        );
+        assert_eq!(L1FeeModel::for_chain(U64::from(137)), L1FeeModel::NotUsed);
+    }
+}
@@ -0,0 +1,203 @@
+use anyhow::Result;
+use ethers::{
+    abi,
+    prelude::abigen,
+    providers::{Http, Provider},
+    types::{H160, U256},
+};
+use ethers_contract::Multicall;
+use log::info;
+use std::{collections::HashMap, sync::Arc, time::Instant};
+
+use crate::pools::CurvePool;
+use crate::simulator::A_PRECISION;
+
+abigen!(
+    CurveRegistry,
+    r#"[
+        function pool_count() external view returns (uint256)
+        function pool_list(uint256 i) external view returns (address)
+        function get_coins(address pool) external view returns (address[8])
+        function get_decimals(address pool) external view returns (uint256[8])
+        function get_rates(address pool) external view returns (uint256[8])
+        function is_meta(address pool) external view returns (bool)
+    ]"#,
+);
+
+abigen!(
+    CurveStableSwapPool,
+    r#"[
+        function balances(uint256 i) external view returns (uint256)
+        function A() external view returns (uint256)
+        function fee() external view returns (uint256)
+    ]"#,
+);
+
+// Curve's main registry on Ethereum
+pub static CURVE_REGISTRY: &str = "0x90E00ACe148ca3b23Ac1bC8C240C2a7Dd9c2d7f5";
+
+// pools per multicall, each takes a few calls
+const POOLS_PER_BATCH: usize = 50;
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct CurveState {
+    pub balances: Vec<U256>,
+    pub amp: U256,
+    pub fee: U256,
+}
+
+fn uint_list(token: &Result<abi::Token, ethers::types::Bytes>) -> Vec<U256> {
+    match token {
+        Ok(abi::Token::FixedArray(tokens)) => tokens
+            .iter()
+            .filter_map(|t| t.clone().into_uint())
+            .collect(),
+        _ => Vec::new(),
+    }
+}
+
+fn address_list(token: &Result<abi::Token, ethers::types::Bytes>) -> Vec<H160> {
+    match token {
+        Ok(abi::Token::FixedArray(tokens)) => tokens
+            .iter()
+            .filter_map(|t| t.clone().into_address())
+            .filter(|address| !address.is_zero())
+            .collect(),
+        _ => Vec::new(),
+    }
+}
+
+/*
+Plain pools listed in a Curve registry, with A and the fee as of now.
+Metapools and pools whose coins carry rates (lending pools) need more than the plain invariant,
+so they're left out, as are coins with more than 18 decimals.
+*/
+pub async fn load_curve_pools(https_url: String, registry: H160) -> Result<Vec<CurvePool>> {
+    let start_time = Instant::now();
+    let client = Arc::new(Provider::<Http>::try_from(https_url)?);
+    let contract = CurveRegistry::new(registry, client.clone());
+    let mut multicall = Multicall::new(client.clone(), None).await?;
+
+    let pool_count = contract.pool_count().call().await?.as_usize();
+    let mut addresses = Vec::new();
+    for start in (0..pool_count).step_by(POOLS_PER_BATCH) {
+        multicall.clear_calls();
+        for i in start..(start + POOLS_PER_BATCH).min(pool_count) {
+            multicall.add_call(contract.pool_list(U256::from(i)), true);
+        }
+        let results = multicall.call_raw().await?;
+        addresses.extend(
+            results
+                .into_iter()
+                .flatten()
+                .filter_map(|token| token.into_address()),
+        );
+    }
+
+    let mut pools = Vec::new();
+    for batch in addresses.chunks(POOLS_PER_BATCH) {
+        multicall.clear_calls();
+        for address in batch {
+            multicall.add_call(contract.get_coins(*address), true);
+            multicall.add_call(contract.get_decimals(*address), true);
+            multicall.add_call(contract.get_rates(*address), true);
+            multicall.add_call(contract.is_meta(*address), true);
+        }
+        let results = multicall.call_raw().await?;
+
+        for (address, result) in batch.iter().zip(results.chunks(4)) {
+            let coins = address_list(&result[0]);
+            let decimals = uint_list(&result[1]);
+            let rates = uint_list(&result[2]);
+            let is_meta = !matches!(result[3], Ok(abi::Token::Bool(false)));
+
+            let n = coins.len();
+            if n < 2 || is_meta || decimals.len() < n || rates.len() < n {
+                continue;
+            }
+            let plain_rates = rates[..n].iter().all(|rate| *rate == U256::exp10(18));
+            let decimals_ok = decimals[..n].iter().all(|d| *d <= U256::from(18));
+            if !plain_rates || !decimals_ok {
+                continue;
+            }
+            pools.push(CurvePool {
+                address: *address,
+                coins,
+                decimals: decimals[..n].iter().map(|d| d.as_u32() as u8).collect(),
+                amp: U256::zero(),
+                fee: U256::zero(),
+                registry,
+            });
+        }
+    }
+
+    // A and the fee come from the pools themselves, pools that don't answer are dropped
+    let states = sync_curve_pools(client, &pools).await?;
+    let pools: Vec<CurvePool> = pools
+        .into_iter()
+        .filter_map(|pool| {
+            let state = states.get(&pool.address)?;
+            Some(CurvePool {
+                amp: state.amp,
+                fee: state.fee,
+                ..pool
+            })
+        })
+        .collect();
+
+    info!(
+        "Loaded {} plain Curve pools of {} in {} seconds",
+        pools.len(),
+        pool_count,
+        start_time.elapsed().as_secs()
+    );
+    Ok(pools)
+}
+
+/*
+Balances, A and the fee of every pool, in as few multicalls as the batch size allows.
+A is scaled by A_PRECISION, so a pool ramping A in finer steps is rounded to whole A.
+Pools with a failed call are left out of the result.
+*/
+pub async fn sync_curve_pools(
+    client: Arc<Provider<Http>>,
+    pools: &[CurvePool],
+) -> Result<HashMap<H160, CurveState>> {
+    let mut multicall = Multicall::new(client.clone(), None).await?;
+    let mut states = HashMap::new();
+
+    for batch in pools.chunks(POOLS_PER_BATCH) {
+        multicall.clear_calls();
+        for pool in batch {
+            let contract = CurveStableSwapPool::new(pool.address, client.clone());
+            for i in 0..pool.coins.len() {
+                multicall.add_call(contract.balances(U256::from(i)), true);
+            }
+            multicall.add_call(contract.a(), true);
+            multicall.add_call(contract.fee(), true);
+        }
+        let mut results = multicall.call_raw().await?.into_iter();
+
+        for pool in batch {
+            // all of the pool's results are taken even if one failed, so the next pool lines up
+            let values: Vec<Option<U256>> = results
+                .by_ref()
+                .take(pool.coins.len() + 2)
+                .map(|result| result.ok()?.into_uint())
+                .collect();
+            if let Some(mut values) = values.into_iter().collect::<Option<Vec<U256>>>() {
+                let fee = values.pop().unwrap_or_default();
+                let a = values.pop().unwrap_or_default();
+                states.insert(
+                    pool.address,
+                    CurveState {
+                        balances: values,
+                        amp: a * U256::from(A_PRECISION),
+                        fee,
+                    },
+                );
+            }
+        }
+    }
+    Ok(states)
+}
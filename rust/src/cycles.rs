@@ -0,0 +1,440 @@
+use ethers::types::{H160, U256};
+use std::collections::{HashMap, VecDeque};
+
+use crate::multi::Reserve;
+use crate::paths::{ArbPath, MAX_HOPS};
+use crate::pools::{DexVariant, PoolId, PoolRegistry};
+
+// Relaxations smaller than this are float noise, not opportunities
+const EPSILON: f64 = 1e-12;
+
+/*
+One swap direction through a pool. Every pool has two edges, 2 * id for token0 -> token1
+and 2 * id + 1 for token1 -> token0, so edges never have to be looked up.
+*/
+#[derive(Debug, Clone, Copy)]
+pub struct Edge {
+    pub from: usize,
+    pub to: usize,
+    pub pool: PoolId,
+    pub zero_for_one: bool,
+    // −ln of the marginal rate including the fee, infinite if the pool can't be priced
+    pub weight: f64,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Cycle {
+    // edge ids in swap order, each edge ends where the next one starts
+    pub edges: Vec<usize>,
+    pub weight: f64,
+}
+
+impl Cycle {
+    // What one unit returns at the margin, above 1 for a profitable cycle
+    pub fn rate(&self) -> f64 {
+        (-self.weight).exp()
+    }
+}
+
+fn ln_u256(value: U256) -> f64 {
+    let shift = value.bits().saturating_sub(64);
+    ((value >> shift).as_u64() as f64).ln() + shift as f64 * std::f64::consts::LN_2
+}
+
+// fee is in the same units as Pool.fee, 300 is 0.3%
+pub fn edge_weight(reserve_in: U256, reserve_out: U256, fee: u32) -> f64 {
+    if reserve_in.is_zero() || reserve_out.is_zero() || fee >= 100_000 {
+        return f64::INFINITY;
+    }
+    let fee_factor = 1.0 - fee as f64 / 100_000.0;
+    -(ln_u256(reserve_out) - ln_u256(reserve_in) + fee_factor.ln())
+}
+
+/*
+Finds negative cycles in the token graph weighted by −ln(marginal rate), which are
+the cycles that return more than they take in before price impact.
+
+Distances are kept between blocks (SPFA from a virtual source joined to every token),
+so an update only re-relaxes from the edges whose weights changed:
+- an edge that got cheaper queues its source token
+- an edge that got dearer and that a token's shortest path runs through resets that
+  token's subtree to the virtual source, and queues whatever points into it
+A pred path with as many edges as there are tokens must contain a cycle, which is then
+cut out of the graph for the rest of the round and re-checked on the next update.
+*/
+#[derive(Debug, Clone)]
+pub struct CycleDetector {
+    pub tokens: Vec<H160>,
+    pub token_ids: HashMap<H160, usize>,
+    pub edges: Vec<Edge>,
+    pub out_edges: Vec<Vec<usize>>,
+    pub in_edges: Vec<Vec<usize>>,
+    // cycles longer than this are cut but not reported
+    pub max_hops: usize,
+    dist: Vec<f64>,
+    pred: Vec<Option<usize>>,
+    hops: Vec<usize>,
+    queue: VecDeque<usize>,
+    in_queue: Vec<bool>,
+    blocked: Vec<bool>,
+}
+
+impl CycleDetector {
+    pub fn new(pools: &PoolRegistry, reserves: &[Reserve], max_hops: usize) -> Self {
+        let mut tokens = Vec::new();
+        let mut token_ids = HashMap::new();
+        let mut token_id = |token: H160| {
+            *token_ids.entry(token).or_insert_with(|| {
+                tokens.push(token);
+                tokens.len() - 1
+            })
+        };
+
+        let mut edges = Vec::with_capacity(pools.len() * 2);
+        for (id, pool) in pools.iter() {
+            let (token0, token1) = (token_id(pool.token0), token_id(pool.token1));
+            for (from, to, zero_for_one) in [(token0, token1, true), (token1, token0, false)] {
+                edges.push(Edge {
+                    from,
+                    to,
+                    pool: id,
+                    zero_for_one,
+                    weight: f64::INFINITY,
+                });
+            }
+        }
+
+        let node_count = tokens.len();
+        let mut out_edges = vec![Vec::new(); node_count];
+        let mut in_edges = vec![Vec::new(); node_count];
+        for (i, edge) in edges.iter().enumerate() {
+            out_edges[edge.from].push(i);
+            in_edges[edge.to].push(i);
+        }
+
+        let edge_count = edges.len();
+        let mut detector = Self {
+            tokens,
+            token_ids,
+            edges,
+            out_edges,
+            in_edges,
+            max_hops: max_hops.min(MAX_HOPS),
+            dist: vec![0.0; node_count],
+            pred: vec![None; node_count],
+            hops: vec![0; node_count],
+            queue: (0..node_count).collect(),
+            in_queue: vec![true; node_count],
+            blocked: vec![false; edge_count],
+        };
+        for i in 0..edge_count {
+            detector.edges[i].weight = detector.current_weight(i, pools, reserves);
+        }
+        detector
+    }
+
+    fn current_weight(&self, edge_id: usize, pools: &PoolRegistry, reserves: &[Reserve]) -> f64 {
+        let edge = &self.edges[edge_id];
+        let pool = &pools[edge.pool];
+        if !matches!(pool.version, DexVariant::UniswapV2) {
+            return f64::INFINITY;
+        }
+        let reserve = match reserves.get(edge.pool.index()) {
+            Some(reserve) => reserve,
+            None => return f64::INFINITY,
+        };
+        let (reserve_in, reserve_out) = if edge.zero_for_one {
+            (reserve.reserve0, reserve.reserve1)
+        } else {
+            (reserve.reserve1, reserve.reserve0)
+        };
+        edge_weight(reserve_in, reserve_out, pool.fee)
+    }
+
+    fn push(&mut self, node: usize) {
+        if !self.in_queue[node] {
+            self.in_queue[node] = true;
+            self.queue.push_back(node);
+        }
+    }
+
+    // Sends every token whose shortest path runs through a root back to the virtual source
+    fn reset_subtrees(&mut self, roots: &[usize]) {
+        let node_count = self.tokens.len();
+        let mut children = vec![Vec::new(); node_count];
+        for node in 0..node_count {
+            if let Some(edge_id) = self.pred[node] {
+                children[self.edges[edge_id].from].push(node);
+            }
+        }
+
+        let mut reset = vec![false; node_count];
+        let mut stack: Vec<usize> = roots.to_vec();
+        while let Some(node) = stack.pop() {
+            if reset[node] {
+                continue;
+            }
+            reset[node] = true;
+            stack.extend(children[node].iter().copied());
+        }
+
+        for node in (0..node_count).filter(|node| reset[*node]) {
+            self.dist[node] = 0.0;
+            self.pred[node] = None;
+            self.hops[node] = 0;
+            self.push(node);
+            for i in 0..self.in_edges[node].len() {
+                let from = self.edges[self.in_edges[node][i]].from;
+                self.push(from);
+            }
+        }
+    }
+
+    // Walks back from a token whose pred path is too long to be simple
+    fn find_cycle(&self, node: usize) -> Option<Cycle> {
+        let node_count = self.tokens.len();
+        let mut start = node;
+        for _ in 0..node_count {
+            start = self.edges[self.pred[start]?].from;
+        }
+
+        let mut edges = Vec::new();
+        let mut current = start;
+        loop {
+            let edge_id = self.pred[current]?;
+            edges.push(edge_id);
+            current = self.edges[edge_id].from;
+            if current == start {
+                break;
+            }
+            if edges.len() > node_count {
+                return None;
+            }
+        }
+        edges.reverse();
+
+        let weight = edges
+            .iter()
+            .map(|edge_id| self.edges[*edge_id].weight)
+            .sum();
+        Some(Cycle { edges, weight })
+    }
+
+    /*
+    Re-weights the edges of the touched pools and returns the negative cycles
+    that can be found from there. Pass no pools to only work through what's queued,
+    the first call after new() checks the whole graph.
+    */
+    pub fn update(
+        &mut self,
+        touched: &[PoolId],
+        pools: &PoolRegistry,
+        reserves: &[Reserve],
+    ) -> Vec<Cycle> {
+        // cycles cut last round may still be there
+        for edge_id in 0..self.blocked.len() {
+            if self.blocked[edge_id] {
+                self.blocked[edge_id] = false;
+                let from = self.edges[edge_id].from;
+                self.push(from);
+            }
+        }
+
+        let mut reset_roots = Vec::new();
+        for pool in touched {
+            for edge_id in [pool.index() * 2, pool.index() * 2 + 1] {
+                if edge_id >= self.edges.len() {
+                    continue;
+                }
+                let weight = self.current_weight(edge_id, pools, reserves);
+                let edge = self.edges[edge_id];
+                self.edges[edge_id].weight = weight;
+                if weight < edge.weight {
+                    self.push(edge.from);
+                } else if weight > edge.weight && self.pred[edge.to] == Some(edge_id) {
+                    reset_roots.push(edge.to);
+                }
+            }
+        }
+        if !reset_roots.is_empty() {
+            self.reset_subtrees(&reset_roots);
+        }
+
+        self.relax()
+    }
+
+    fn relax(&mut self) -> Vec<Cycle> {
+        let node_count = self.tokens.len();
+        let mut cycles = Vec::new();
+        // a bound on the work of one round, anything left stays queued for the next
+        let mut budget = node_count.saturating_mul(self.edges.len()).max(1);
+
+        while let Some(node) = self.queue.pop_front() {
+            self.in_queue[node] = false;
+            if budget == 0 {
+                self.push(node);
+                break;
+            }
+
+            for i in 0..self.out_edges[node].len() {
+                budget = budget.saturating_sub(1);
+                let edge_id = self.out_edges[node][i];
+                if self.blocked[edge_id] {
+                    continue;
+                }
+                let edge = self.edges[edge_id];
+                let dist = self.dist[node] + edge.weight;
+                if dist >= self.dist[edge.to] - EPSILON {
+                    continue;
+                }
+
+                self.dist[edge.to] = dist;
+                self.pred[edge.to] = Some(edge_id);
+                self.hops[edge.to] = self.hops[node] + 1;
+
+                if self.hops[edge.to] >= node_count {
+                    if let Some(cycle) = self.find_cycle(edge.to) {
+                        let nodes: Vec<usize> = cycle
+                            .edges
+                            .iter()
+                            .map(|edge_id| self.edges[*edge_id].to)
+                            .collect();
+                        for edge_id in &cycle.edges {
+                            self.blocked[*edge_id] = true;
+                        }
+                        if cycle.weight < -EPSILON && cycle.edges.len() <= self.max_hops {
+                            cycles.push(cycle);
+                        }
+                        self.reset_subtrees(&nodes);
+                        break;
+                    }
+                }
+                self.push(edge.to);
+            }
+        }
+
+        cycles
+    }
+
+    /*
+    The cycle as an ArbPath starting and ending in start_token,
+    None if the cycle doesn't go through it or is too long to execute.
+    */
+    pub fn to_arb_path(&self, cycle: &Cycle, start_token: &H160) -> Option<ArbPath> {
+        let start = *self.token_ids.get(start_token)?;
+        let offset = cycle
+            .edges
+            .iter()
+            .position(|edge_id| self.edges[*edge_id].from == start)?;
+
+        let hops: Vec<(PoolId, bool)> = (0..cycle.edges.len())
+            .map(|i| {
+                let edge = &self.edges[cycle.edges[(offset + i) % cycle.edges.len()]];
+                (edge.pool, edge.zero_for_one)
+            })
+            .collect();
+        ArbPath::from_hops(&hops).ok()
+    }
+}
+
+#[cfg(test)]
+mod cycles_tests {
+    use super::*;
+    use crate::amounts::{Profit, TokenAmount};
+    use crate::policy::Policy;
+    use crate::test_utils::{pool, token};
+
+    fn reserve(reserve0: u64, reserve1: u64) -> Reserve {
+        Reserve {
+            reserve0: U256::from(reserve0) * U256::exp10(18),
+            reserve1: U256::from(reserve1) * U256::exp10(18),
+        }
+    }
+
+    // a square of tokens 0 -> 1 -> 2 -> 3 -> 0 plus a diagonal, all at the same price
+    fn square() -> (PoolRegistry, Vec<Reserve>) {
+        let pools = vec![
+            pool(0, token(0), token(1)),
+            pool(1, token(1), token(2)),
+            pool(2, token(2), token(3)),
+            pool(3, token(3), token(0)),
+            pool(4, token(0), token(2)),
+        ];
+        let reserves = vec![reserve(1_000_000, 1_000_000); pools.len()];
+        (PoolRegistry::new(&pools), reserves)
+    }
+
+    fn assert_profitable(
+        detector: &CycleDetector,
+        cycle: &Cycle,
+        pools: &PoolRegistry,
+        reserves: &[Reserve],
+    ) {
+        let path = detector.to_arb_path(cycle, &token(0)).unwrap();
+        assert_eq!(path.validate(pools, &Policy::default()), Ok(()));
+
+        let amount_in = TokenAmount::from_units(token(0), 18, 10);
+        let amount_out = path.simulate_amount(&amount_in, pools, reserves).unwrap();
+        assert!(Profit::from_amounts(&amount_out, &amount_in)
+            .unwrap()
+            .is_positive());
+    }
+
+    #[test]
+    fn edge_weight_test() {
+        let even = edge_weight(U256::exp10(30), U256::exp10(30), 300);
+        assert!((even - -(0.997f64.ln())).abs() < 1e-12);
+        // 2000 USDC (6 decimals) per WETH (18 decimals), in base units
+        let weth_to_usdc = edge_weight(U256::exp10(21), U256::from(2_000_000_000_000u64), 0);
+        assert!((weth_to_usdc - -(2000.0f64 * 1e6 / 1e18).ln()).abs() < 1e-9);
+        assert_eq!(edge_weight(U256::zero(), U256::one(), 300), f64::INFINITY);
+    }
+
+    #[test]
+    fn cycle_detector_test() {
+        let (pools, mut reserves) = square();
+        let mut detector = CycleDetector::new(&pools, &reserves, MAX_HOPS);
+        assert!(detector.update(&[], &pools, &reserves).is_empty());
+
+        // token 3 gets cheap in the 2/3 pool, which the square and a triangle through
+        // the diagonal both go through, the first one found cuts the other
+        reserves[2] = reserve(1_000_000, 1_100_000);
+        let cycles = detector.update(&[PoolId(2)], &pools, &reserves);
+        assert_eq!(cycles.len(), 1);
+        assert!(cycles[0].rate() > 1.0);
+        assert_profitable(&detector, &cycles[0], &pools, &reserves);
+
+        // a cycle that is still open is found again on the next update
+        let cycles = detector.update(&[], &pools, &reserves);
+        assert!(!cycles.is_empty());
+        for cycle in &cycles {
+            assert!(cycle.edges.len() <= 4);
+            assert_profitable(&detector, cycle, &pools, &reserves);
+        }
+
+        // once the price is back, nothing is found
+        reserves[2] = reserve(1_000_000, 1_000_000);
+        assert!(detector.update(&[PoolId(2)], &pools, &reserves).is_empty());
+        assert!(detector.update(&[], &pools, &reserves).is_empty());
+    }
+
+    #[test]
+    fn to_arb_path_test() {
+        let (pools, reserves) = square();
+        let detector = CycleDetector::new(&pools, &reserves, MAX_HOPS);
+        // 1 -> 2 -> 3 -> 0 -> 1
+        let cycle = Cycle {
+            edges: vec![2, 4, 6, 0],
+            weight: -1.0,
+        };
+
+        let path = detector.to_arb_path(&cycle, &token(0)).unwrap();
+        assert_eq!(path.nhop, 4);
+        assert_eq!(path._get_pool(0), PoolId(0));
+        assert_eq!(path.validate(&pools, &Policy::default()), Ok(()));
+        assert_eq!(
+            detector.to_arb_path(&cycle, &H160::from_low_u64_be(99)),
+            None
+        );
+    }
+}
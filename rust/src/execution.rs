@@ -0,0 +1,635 @@
+use anyhow::{anyhow, Result};
+use async_trait::async_trait;
+use ethers::{
+    providers::{Http, Middleware, Provider},
+    signers::Signer,
+    types::{transaction::eip2718::TypedTransaction, Bytes, TransactionReceipt, H256, U256, U64},
+    utils::rlp::Rlp,
+};
+use log::info;
+use std::{str::FromStr, sync::Arc, time::Duration};
+
+use crate::bundler::Bundler;
+use crate::constants::Env;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RouteKind {
+    Flashbots,
+    Mempool,
+    PrivateRpc,
+}
+
+impl FromStr for RouteKind {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.to_lowercase().as_str() {
+            "flashbots" => Ok(RouteKind::Flashbots),
+            "mempool" => Ok(RouteKind::Mempool),
+            "private" | "private_rpc" => Ok(RouteKind::PrivateRpc),
+            _ => Err(anyhow!("Unknown execution route: {}", s)),
+        }
+    }
+}
+
+impl RouteKind {
+    pub fn default_for_chain(chain_id: U64) -> Self {
+        // Flashbots only runs on Ethereum, everything else goes through the mempool
+        match chain_id.as_u64() {
+            1 => RouteKind::Flashbots,
+            _ => RouteKind::Mempool,
+        }
+    }
+
+    pub fn from_env(env: &Env) -> Result<Self> {
+        match &env.execution_route {
+            Some(route) => RouteKind::from_str(route),
+            None => Ok(RouteKind::default_for_chain(env.chain_id)),
+        }
+    }
+}
+
+#[async_trait]
+pub trait ExecutionRoute: Send + Sync {
+    fn kind(&self) -> RouteKind;
+
+    // Submits signed txs targeting the block after block_number.
+    // Returns the bundle hash for bundle routes and the tx hashes for the others.
+    async fn submit(&self, signed_txs: Vec<Bytes>, block_number: U64) -> Result<Vec<H256>>;
+}
+
+pub struct FlashbotsRoute {
+    pub bundler: Arc<Bundler>,
+}
+
+#[async_trait]
+impl ExecutionRoute for FlashbotsRoute {
+    fn kind(&self) -> RouteKind {
+        RouteKind::Flashbots
+    }
+
+    async fn submit(&self, signed_txs: Vec<Bytes>, block_number: U64) -> Result<Vec<H256>> {
+        let bundle = self.bundler.to_bundle(signed_txs, block_number);
+        let bundle_hash = self.bundler.send_bundle(bundle).await?;
+        Ok(vec![bundle_hash])
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct ReplacementPolicy {
+    pub bump_percent: u64,
+    pub blocks_between_bumps: u64,
+    pub max_fee_cap: Option<U256>,
+    pub deadline_blocks: u64,
+    pub cancel_on_deadline: bool,
+    pub poll_interval: Duration,
+}
+
+impl Default for ReplacementPolicy {
+    fn default() -> Self {
+        Self {
+            bump_percent: 12,
+            blocks_between_bumps: 1,
+            max_fee_cap: None,
+            deadline_blocks: 5,
+            cancel_on_deadline: true,
+            poll_interval: Duration::from_millis(500),
+        }
+    }
+}
+
+impl ReplacementPolicy {
+    pub fn within_cap(&self, max_fee_per_gas: U256) -> bool {
+        match self.max_fee_cap {
+            Some(cap) => max_fee_per_gas <= cap,
+            None => true,
+        }
+    }
+
+    // The bump a cancel can pay under the cap: the configured one, else the minimum 10%
+    pub fn cancel_bump_percent(&self, max_fee_per_gas: U256) -> Option<u64> {
+        [self.bump_percent, 10]
+            .into_iter()
+            .find(|bump_percent| self.within_cap(bump_fee(max_fee_per_gas, *bump_percent)))
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Replacement {
+    pub block_number: U64,
+    pub tx_hash: H256,
+    pub max_priority_fee_per_gas: U256,
+    pub max_fee_per_gas: U256,
+    pub cancel: bool,
+}
+
+#[derive(Debug, Clone)]
+pub enum SubmissionStatus {
+    Included(TransactionReceipt),
+    Cancelled(TransactionReceipt),
+    // the nonce was used by a tx we don't know about
+    NonceTaken,
+    Expired,
+}
+
+#[derive(Debug, Clone)]
+pub struct SubmissionReport {
+    pub status: SubmissionStatus,
+    pub replacements: Vec<Replacement>,
+}
+
+pub struct MempoolRoute {
+    pub bundler: Arc<Bundler>,
+    pub policy: ReplacementPolicy,
+}
+
+impl MempoolRoute {
+    pub async fn broadcast(&self, signed_tx: Bytes) -> Result<H256> {
+        let pending_tx = self
+            .bundler
+            .provider
+            .send_raw_transaction(signed_tx)
+            .await?;
+        Ok(pending_tx.tx_hash())
+    }
+
+    pub async fn replace(
+        &self,
+        tx: &TypedTransaction,
+        bump_percent: u64,
+    ) -> Result<(TypedTransaction, H256)> {
+        let replacement = bump_tx_fees(tx, bump_percent);
+        let signed_tx = self.bundler.sign_tx(replacement.clone()).await?;
+        let tx_hash = self.broadcast(signed_tx).await?;
+        Ok((replacement, tx_hash))
+    }
+
+    pub async fn cancel(
+        &self,
+        tx: &TypedTransaction,
+        bump_percent: u64,
+    ) -> Result<(TypedTransaction, H256)> {
+        let (max_priority_fee_per_gas, max_fee_per_gas) = tx_fees(&bump_tx_fees(tx, bump_percent));
+        let mut cancel = self
+            .bundler
+            .cancel_tx(
+                tx.nonce().copied().unwrap_or_default(),
+                max_priority_fee_per_gas,
+                max_fee_per_gas,
+            )
+            .await?;
+        // legacy cancels would otherwise pick up a fresh gas price that may not outbid the tx
+        set_tx_fees(&mut cancel, max_priority_fee_per_gas, max_fee_per_gas);
+        let signed_tx = self.bundler.sign_tx(cancel.clone()).await?;
+        let tx_hash = self.broadcast(signed_tx).await?;
+        Ok((cancel, tx_hash))
+    }
+
+    pub async fn submit_managed(
+        &self,
+        tx: TypedTransaction,
+        policy: &ReplacementPolicy,
+    ) -> Result<SubmissionReport> {
+        let signed_tx = self.bundler.sign_tx(tx.clone()).await?;
+        let tx_hash = self.broadcast(signed_tx).await?;
+        self.manage(tx, tx_hash, policy).await
+    }
+
+    /*
+    Watches a broadcast tx until its nonce is used: bumps its fees every few blocks and
+    cancels it at the deadline. Once cancelled it waits for the nonce to be used,
+    so Expired is only returned when the policy doesn't cancel.
+    */
+    pub async fn manage(
+        &self,
+        tx: TypedTransaction,
+        tx_hash: H256,
+        policy: &ReplacementPolicy,
+    ) -> Result<SubmissionReport> {
+        let provider = &self.bundler.provider;
+        let nonce = *tx
+            .nonce()
+            .ok_or_else(|| anyhow!("Managed submission needs a nonce"))?;
+        let sender = self.bundler.sender.address();
+
+        let start_block = provider.get_block_number().await?;
+        let mut submission = ManagedSubmission::new(nonce, tx_hash, start_block);
+        let mut last_seen_block = start_block;
+        let mut current = tx;
+        let mut replacements = Vec::new();
+
+        loop {
+            tokio::time::sleep(policy.poll_interval).await;
+
+            let block_number = provider.get_block_number().await?;
+            if block_number == last_seen_block {
+                continue;
+            }
+            last_seen_block = block_number;
+
+            let mined_nonce = provider.get_transaction_count(sender, None).await?;
+            let current_fee = tx_fees(&current).1;
+
+            match submission.next_action(block_number, mined_nonce, current_fee, policy) {
+                ManagedAction::Wait => {}
+                ManagedAction::Resolve => {
+                    // the nonce is used up, by one of ours or someone else's tx
+                    for (hash, cancel) in &submission.broadcast_hashes {
+                        if let Some(receipt) = provider.get_transaction_receipt(*hash).await? {
+                            let status = if *cancel {
+                                SubmissionStatus::Cancelled(receipt)
+                            } else {
+                                SubmissionStatus::Included(receipt)
+                            };
+                            return Ok(SubmissionReport {
+                                status,
+                                replacements,
+                            });
+                        }
+                    }
+                    return Ok(SubmissionReport {
+                        status: SubmissionStatus::NonceTaken,
+                        replacements,
+                    });
+                }
+                ManagedAction::Expire => {
+                    return Ok(SubmissionReport {
+                        status: SubmissionStatus::Expired,
+                        replacements,
+                    });
+                }
+                ManagedAction::Cancel => {
+                    let bump_percent = policy.cancel_bump_percent(current_fee).unwrap_or(10);
+                    let (cancel, cancel_hash) = self.cancel(&current, bump_percent).await?;
+                    info!("Cancelled tx with nonce {:?}: {:?}", nonce, cancel_hash);
+                    let (max_priority_fee_per_gas, max_fee_per_gas) = tx_fees(&cancel);
+                    replacements.push(Replacement {
+                        block_number,
+                        tx_hash: cancel_hash,
+                        max_priority_fee_per_gas,
+                        max_fee_per_gas,
+                        cancel: true,
+                    });
+                    submission.record(cancel_hash, true, block_number);
+                    current = cancel;
+                }
+                ManagedAction::Bump => {
+                    let (replacement, replacement_hash) =
+                        self.replace(&current, policy.bump_percent).await?;
+                    info!("Replaced tx with nonce {:?}: {:?}", nonce, replacement_hash);
+                    let (max_priority_fee_per_gas, max_fee_per_gas) = tx_fees(&replacement);
+                    replacements.push(Replacement {
+                        block_number,
+                        tx_hash: replacement_hash,
+                        max_priority_fee_per_gas,
+                        max_fee_per_gas,
+                        cancel: false,
+                    });
+                    submission.record(replacement_hash, false, block_number);
+                    current = replacement;
+                }
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ManagedAction {
+    Wait,
+    Bump,
+    Cancel,
+    // the nonce was used, find out by which tx
+    Resolve,
+    Expire,
+}
+
+// The state of one managed submission, kept apart from the RPC calls so the loop can be tested
+#[derive(Debug, Clone)]
+pub struct ManagedSubmission {
+    pub nonce: U256,
+    pub start_block: U64,
+    pub last_bump_block: U64,
+    pub cancelled: bool,
+    // every hash we broadcast can still be the one that gets mined
+    pub broadcast_hashes: Vec<(H256, bool)>,
+}
+
+impl ManagedSubmission {
+    pub fn new(nonce: U256, tx_hash: H256, start_block: U64) -> Self {
+        Self {
+            nonce,
+            start_block,
+            last_bump_block: start_block,
+            cancelled: false,
+            broadcast_hashes: vec![(tx_hash, false)],
+        }
+    }
+
+    /*
+    What to do at a new block, current_fee is the max fee of the last tx we broadcast.
+    Bumps and cancels both stay under the fee cap, a cancel the cap can't pay for expires instead.
+    */
+    pub fn next_action(
+        &self,
+        block_number: U64,
+        mined_nonce: U256,
+        current_fee: U256,
+        policy: &ReplacementPolicy,
+    ) -> ManagedAction {
+        if mined_nonce > self.nonce {
+            return ManagedAction::Resolve;
+        }
+        if self.cancelled {
+            return ManagedAction::Wait;
+        }
+        if block_number >= self.start_block + policy.deadline_blocks {
+            return match policy.cancel_bump_percent(current_fee) {
+                Some(_) if policy.cancel_on_deadline => ManagedAction::Cancel,
+                _ => ManagedAction::Expire,
+            };
+        }
+        if block_number >= self.last_bump_block + policy.blocks_between_bumps
+            && policy.within_cap(bump_fee(current_fee, policy.bump_percent))
+        {
+            return ManagedAction::Bump;
+        }
+        ManagedAction::Wait
+    }
+
+    pub fn record(&mut self, tx_hash: H256, cancel: bool, block_number: U64) {
+        self.broadcast_hashes.push((tx_hash, cancel));
+        if cancel {
+            self.cancelled = true;
+        } else {
+            self.last_bump_block = block_number;
+        }
+    }
+}
+
+#[async_trait]
+impl ExecutionRoute for MempoolRoute {
+    fn kind(&self) -> RouteKind {
+        RouteKind::Mempool
+    }
+
+    // Broadcasts in nonce order, then each tx is bumped or cancelled in the background
+    async fn submit(&self, signed_txs: Vec<Bytes>, _block_number: U64) -> Result<Vec<H256>> {
+        let mut tx_hashes = Vec::new();
+        for signed_tx in signed_txs {
+            let (tx, _) = TypedTransaction::decode_signed(&Rlp::new(&signed_tx))?;
+            let tx_hash = self.broadcast(signed_tx).await?;
+            tx_hashes.push(tx_hash);
+
+            let route = MempoolRoute {
+                bundler: self.bundler.clone(),
+                policy: self.policy.clone(),
+            };
+            tokio::spawn(async move {
+                match route.manage(tx, tx_hash, &route.policy).await {
+                    Ok(report) => info!("Managed submission {:?}: {:?}", tx_hash, report.status),
+                    Err(e) => info!("Managed submission {:?} error: {:?}", tx_hash, e),
+                }
+            });
+        }
+        Ok(tx_hashes)
+    }
+}
+
+pub struct PrivateRpcRoute {
+    pub provider: Provider<Http>,
+    pub max_block_delta: u64,
+}
+
+impl PrivateRpcRoute {
+    pub fn new(private_rpc_url: &str, max_block_delta: u64) -> Result<Self> {
+        Ok(Self {
+            provider: Provider::<Http>::try_from(private_rpc_url)?,
+            max_block_delta,
+        })
+    }
+
+    pub fn from_env(env: &Env) -> Result<Self> {
+        let private_rpc_url = env
+            .private_rpc_url
+            .as_ref()
+            .ok_or_else(|| anyhow!("PRIVATE_RPC_URL is required for the private route"))?;
+        Self::new(private_rpc_url, env.private_rpc_max_block_delta)
+    }
+}
+
+#[async_trait]
+impl ExecutionRoute for PrivateRpcRoute {
+    fn kind(&self) -> RouteKind {
+        RouteKind::PrivateRpc
+    }
+
+    async fn submit(&self, signed_txs: Vec<Bytes>, block_number: U64) -> Result<Vec<H256>> {
+        let max_block_number = block_number + self.max_block_delta;
+
+        let mut tx_hashes = Vec::new();
+        for signed_tx in signed_txs {
+            let params = serde_json::json!({
+                "tx": signed_tx,
+                "maxBlockNumber": max_block_number,
+            });
+            let tx_hash: H256 = self
+                .provider
+                .request("eth_sendPrivateTransaction", [params])
+                .await?;
+            tx_hashes.push(tx_hash);
+        }
+        Ok(tx_hashes)
+    }
+}
+
+pub fn build_route(env: &Env, bundler: Arc<Bundler>) -> Result<Box<dyn ExecutionRoute>> {
+    let route: Box<dyn ExecutionRoute> = match RouteKind::from_env(env)? {
+        RouteKind::Flashbots => Box::new(FlashbotsRoute { bundler }),
+        RouteKind::Mempool => Box::new(MempoolRoute {
+            bundler,
+            policy: ReplacementPolicy::default(),
+        }),
+        RouteKind::PrivateRpc => Box::new(PrivateRpcRoute::from_env(env)?),
+    };
+    Ok(route)
+}
+
+pub fn bump_fee(fee: U256, bump_percent: u64) -> U256 {
+    // nodes reject replacements that don't raise both fees by at least 10%
+    let bump_percent = std::cmp::max(bump_percent, 10);
+    fee * U256::from(100 + bump_percent) / U256::from(100) + U256::one()
+}
+
+// Returns (max_priority_fee_per_gas, max_fee_per_gas), legacy txs report their gas price for both
+pub fn tx_fees(tx: &TypedTransaction) -> (U256, U256) {
+    match tx.as_eip1559_ref() {
+        Some(inner) => (
+            inner.max_priority_fee_per_gas.unwrap_or_default(),
+            inner.max_fee_per_gas.unwrap_or_default(),
+        ),
+        None => {
+            let gas_price = tx.gas_price().unwrap_or_default();
+            (gas_price, gas_price)
+        }
+    }
+}
+
+pub fn set_tx_fees(
+    tx: &mut TypedTransaction,
+    max_priority_fee_per_gas: U256,
+    max_fee_per_gas: U256,
+) {
+    if let Some(inner) = tx.as_eip1559_mut() {
+        inner.max_priority_fee_per_gas = Some(max_priority_fee_per_gas);
+        inner.max_fee_per_gas = Some(max_fee_per_gas);
+        return;
+    }
+    tx.set_gas_price(max_fee_per_gas);
+}
+
+pub fn bump_tx_fees(tx: &TypedTransaction, bump_percent: u64) -> TypedTransaction {
+    let (max_priority_fee_per_gas, max_fee_per_gas) = tx_fees(tx);
+    let mut bumped = tx.clone();
+    set_tx_fees(
+        &mut bumped,
+        bump_fee(max_priority_fee_per_gas, bump_percent),
+        bump_fee(max_fee_per_gas, bump_percent),
+    );
+    bumped
+}
+
+#[cfg(test)]
+mod execution_tests {
+    use super::*;
+    use ethers::types::{Eip1559TransactionRequest, TransactionRequest};
+
+    #[test]
+    fn route_kind_test() {
+        assert_eq!(
+            RouteKind::default_for_chain(U64::from(1)),
+            RouteKind::Flashbots
+        );
+        assert_eq!(
+            RouteKind::default_for_chain(U64::from(137)),
+            RouteKind::Mempool
+        );
+        assert_eq!(
+            RouteKind::from_str("Private").unwrap(),
+            RouteKind::PrivateRpc
+        );
+        assert!(RouteKind::from_str("carrier-pigeon").is_err());
+    }
+
+    fn env(execution_route: Option<&str>, private_rpc_url: Option<&str>) -> Env {
+        Env {
+            https_url: String::new(),
+            wss_url: String::new(),
+            chain_id: U64::from(1),
+            blocknative_token: None,
+            private_key: String::new(),
+            signing_key: String::new(),
+            bot_address: String::new(),
+            execution_route: execution_route.map(str::to_string),
+            private_rpc_url: private_rpc_url.map(str::to_string),
+            private_rpc_max_block_delta: 10,
+            tx_type: None,
+            gas_price_source: None,
+            policy_path: String::new(),
+            fork_url: None,
+        }
+    }
+
+    #[test]
+    fn route_from_env_test() {
+        assert_eq!(
+            RouteKind::from_env(&env(None, None)).unwrap(),
+            RouteKind::Flashbots
+        );
+        assert_eq!(
+            RouteKind::from_env(&env(Some("mempool"), None)).unwrap(),
+            RouteKind::Mempool
+        );
+        assert!(RouteKind::from_env(&env(Some("carrier-pigeon"), None)).is_err());
+
+        // the private route needs its url, and takes the block delta from the env
+        assert!(PrivateRpcRoute::from_env(&env(Some("private"), None)).is_err());
+        let route = PrivateRpcRoute::from_env(&env(Some("private"), Some("http://127.0.0.1:8545")))
+            .unwrap();
+        assert_eq!(route.max_block_delta, 10);
+    }
+
+    #[test]
+    fn managed_submission_test() {
+        let policy = ReplacementPolicy {
+            max_fee_cap: Some(U256::from(1000)),
+            deadline_blocks: 4,
+            ..ReplacementPolicy::default()
+        };
+        let (nonce, fee) = (U256::from(7), U256::from(500));
+        let mut submission =
+            ManagedSubmission::new(nonce, H256::from_low_u64_be(1), U64::from(100));
+        let action = |submission: &ManagedSubmission, block: u64, mined_nonce: u64, fee| {
+            submission.next_action(U64::from(block), U256::from(mined_nonce), fee, &policy)
+        };
+
+        assert_eq!(action(&submission, 101, 7, fee), ManagedAction::Bump);
+        submission.record(H256::from_low_u64_be(2), false, U64::from(101));
+        // bumps wait for the next block and stop at the fee cap, 900 bumped by 12% is 1009
+        assert_eq!(action(&submission, 101, 7, fee), ManagedAction::Wait);
+        assert_eq!(
+            action(&submission, 102, 7, U256::from(900)),
+            ManagedAction::Wait
+        );
+        assert_eq!(action(&submission, 102, 7, fee), ManagedAction::Bump);
+
+        // the cancel goes out at the deadline and then the nonce is waited on
+        assert_eq!(action(&submission, 104, 7, fee), ManagedAction::Cancel);
+        submission.record(H256::from_low_u64_be(3), true, U64::from(104));
+        assert_eq!(action(&submission, 105, 7, fee), ManagedAction::Wait);
+        assert_eq!(action(&submission, 106, 8, fee), ManagedAction::Resolve);
+        assert_eq!(
+            submission.broadcast_hashes.last(),
+            Some(&(H256::from_low_u64_be(3), true))
+        );
+
+        // a cancel under the cap falls back to the minimum 10% bump, or expires without one
+        let late = ManagedSubmission::new(nonce, H256::zero(), U64::from(100));
+        assert_eq!(policy.cancel_bump_percent(U256::from(900)), Some(10));
+        assert_eq!(
+            action(&late, 104, 7, U256::from(900)),
+            ManagedAction::Cancel
+        );
+        assert_eq!(policy.cancel_bump_percent(U256::from(950)), None);
+        assert_eq!(
+            action(&late, 104, 7, U256::from(950)),
+            ManagedAction::Expire
+        );
+
+        let no_cancel = ReplacementPolicy {
+            cancel_on_deadline: false,
+            ..policy.clone()
+        };
+        let submission = ManagedSubmission::new(nonce, H256::zero(), U64::from(100));
+        assert_eq!(
+            submission.next_action(U64::from(104), nonce, fee, &no_cancel),
+            ManagedAction::Expire
+        );
+    }
+
+    #[test]
+    fn bump_fee_test() {
+        // anything under the 10% replacement rule gets raised to it
+        assert_eq!(bump_fee(U256::from(100), 5), U256::from(111));
+        assert_eq!(bump_fee(U256::from(100), 25), U256::from(126));
+
+        let tx = Eip1559TransactionRequest::new()
+            .max_priority_fee_per_gas(U256::from(1000))
+            .max_fee_per_gas(U256::from(5000));
+        let bumped = bump_tx_fees(&TypedTransaction::Eip1559(tx), 12);
+        assert_eq!(tx_fees(&bumped), (U256::from(1121), U256::from(5601)));
+
+        let tx = TransactionRequest::new().gas_price(U256::from(5000));
+        let bumped = bump_tx_fees(&TypedTransaction::Legacy(tx), 12);
+        assert_eq!(bumped.gas_price(), Some(U256::from(5601)));
+    }
+}
@@ -0,0 +1,321 @@
+use ethers::{
+    prelude::Lazy,
+    types::{I256, U256},
+};
+
+/*
+Balancer's FixedPoint and LogExpMath, 18 decimal fixed point with the vault's rounding.
+The vault reverts where these return None, so a simulated swap fails where the real one would.
+*/
+
+static ONE_18: Lazy<I256> = Lazy::new(|| I256::exp10(18));
+static ONE_20: Lazy<I256> = Lazy::new(|| I256::exp10(20));
+static ONE_36: Lazy<I256> = Lazy::new(|| I256::exp10(36));
+
+// exp only takes exponents in [-41, 130], the result has to fit 18 decimals
+static MAX_NATURAL_EXPONENT: Lazy<I256> = Lazy::new(|| I256::from(130) * *ONE_18);
+static MIN_NATURAL_EXPONENT: Lazy<I256> = Lazy::new(|| I256::from(-41) * *ONE_18);
+
+// pow computes ln with 36 decimals for bases this close to one
+static LN_36_LOWER_BOUND: Lazy<I256> = Lazy::new(|| *ONE_18 - I256::exp10(17));
+static LN_36_UPPER_BOUND: Lazy<I256> = Lazy::new(|| *ONE_18 + I256::exp10(17));
+
+// 2^254 / ONE_20, so that ln(x) * y can't overflow
+static MILD_EXPONENT_BOUND: Lazy<U256> = Lazy::new(|| (U256::one() << 254) / U256::exp10(20));
+
+/*
+x_n = 2^(7 - n) and a_n = e^(x_n). The first two are 18 decimal exponents with undecimaled
+powers, the rest are in 20 decimals, as in LogExpMath.
+*/
+static X: Lazy<[I256; 12]> = Lazy::new(|| {
+    [
+        "128000000000000000000",
+        "64000000000000000000",
+        "3200000000000000000000",
+        "1600000000000000000000",
+        "800000000000000000000",
+        "400000000000000000000",
+        "200000000000000000000",
+        "100000000000000000000",
+        "50000000000000000000",
+        "25000000000000000000",
+        "12500000000000000000",
+        "6250000000000000000",
+    ]
+    .map(|x| I256::from_dec_str(x).unwrap())
+});
+static A: Lazy<[I256; 12]> = Lazy::new(|| {
+    [
+        "38877084059945950922200000000000000000000000000000000000",
+        "6235149080811616882910000000",
+        "7896296018268069516100000000000000",
+        "888611052050787263676000000",
+        "298095798704172827474000",
+        "5459815003314423907810",
+        "738905609893065022723",
+        "271828182845904523536",
+        "164872127070012814685",
+        "128402541668774148407",
+        "113314845306682631683",
+        "106449445891785942956",
+    ]
+    .map(|a| I256::from_dec_str(a).unwrap())
+});
+
+pub fn one() -> U256 {
+    U256::exp10(18)
+}
+
+pub fn mul_down(a: U256, b: U256) -> Option<U256> {
+    Some(a.checked_mul(b)? / one())
+}
+
+pub fn mul_up(a: U256, b: U256) -> Option<U256> {
+    let product = a.checked_mul(b)?;
+    if product.is_zero() {
+        return Some(product);
+    }
+    Some((product - 1) / one() + 1)
+}
+
+pub fn div_down(a: U256, b: U256) -> Option<U256> {
+    a.checked_mul(one())?.checked_div(b)
+}
+
+pub fn div_up(a: U256, b: U256) -> Option<U256> {
+    if b.is_zero() {
+        return None;
+    }
+    if a.is_zero() {
+        return Some(a);
+    }
+    Some((a.checked_mul(one())? - 1) / b + 1)
+}
+
+pub fn complement(x: U256) -> U256 {
+    one().saturating_sub(x)
+}
+
+// x^y rounded up, with LogExpMath's relative error added on top
+pub fn pow_up(x: U256, y: U256) -> Option<U256> {
+    let one = one();
+    if y == one {
+        return Some(x);
+    }
+    if y == one * 2 {
+        return mul_up(x, x);
+    }
+    if y == one * 4 {
+        let square = mul_up(x, x)?;
+        return mul_up(square, square);
+    }
+    let raw = pow(x, y)?;
+    // MAX_POW_RELATIVE_ERROR is 1e-14
+    let max_error = mul_up(raw, U256::from(10_000u64))? + 1;
+    raw.checked_add(max_error)
+}
+
+// x^y as exp(y * ln(x)), both 18 decimal fixed point
+pub fn pow(x: U256, y: U256) -> Option<U256> {
+    if y.is_zero() {
+        return Some(one());
+    }
+    if x.is_zero() {
+        return Some(x);
+    }
+    if x.bit(255) || y >= *MILD_EXPONENT_BOUND {
+        return None;
+    }
+    let x = I256::from_raw(x);
+    let y = I256::from_raw(y);
+
+    let mut logx_times_y = if *LN_36_LOWER_BOUND < x && x < *LN_36_UPPER_BOUND {
+        let ln_36_x = ln_36(x);
+        // ln_36_x has 36 decimals, split so the product doesn't overflow
+        (ln_36_x / *ONE_18) * y + ((ln_36_x % *ONE_18) * y) / *ONE_18
+    } else {
+        ln(x) * y
+    };
+    logx_times_y /= *ONE_18;
+
+    if logx_times_y < *MIN_NATURAL_EXPONENT || logx_times_y > *MAX_NATURAL_EXPONENT {
+        return None;
+    }
+    Some(exp(logx_times_y)?.into_raw())
+}
+
+/*
+e^x for x in [MIN_NATURAL_EXPONENT, MAX_NATURAL_EXPONENT]. x is decomposed into the x_n,
+whose powers are precomputed, and the rest goes through a Taylor series.
+*/
+pub fn exp(x: I256) -> Option<I256> {
+    if x < *MIN_NATURAL_EXPONENT || x > *MAX_NATURAL_EXPONENT {
+        return None;
+    }
+    if x.is_negative() {
+        return Some((*ONE_18 * *ONE_18) / exp(-x)?);
+    }
+
+    let mut x = x;
+    let first_an = if x >= X[0] {
+        x -= X[0];
+        A[0]
+    } else if x >= X[1] {
+        x -= X[1];
+        A[1]
+    } else {
+        I256::one()
+    };
+
+    // 20 decimals from here on
+    x *= 100;
+
+    let mut product = *ONE_20;
+    for (x_n, a_n) in X[2..10].iter().zip(&A[2..10]) {
+        if x >= *x_n {
+            x -= *x_n;
+            product = (product * *a_n) / *ONE_20;
+        }
+    }
+
+    let mut series_sum = *ONE_20;
+    let mut term = x;
+    series_sum += term;
+    for n in 2..=12 {
+        term = ((term * x) / *ONE_20) / n;
+        series_sum += term;
+    }
+
+    Some((((product * series_sum) / *ONE_20) * first_an) / 100)
+}
+
+// Natural log of an 18 decimal a > 0
+fn ln(a: I256) -> I256 {
+    if a < *ONE_18 {
+        return -ln((*ONE_18 * *ONE_18) / a);
+    }
+
+    let mut a = a;
+    let mut sum = I256::zero();
+    if a >= A[0] * *ONE_18 {
+        a /= A[0];
+        sum += X[0];
+    }
+    if a >= A[1] * *ONE_18 {
+        a /= A[1];
+        sum += X[1];
+    }
+
+    // 20 decimals from here on
+    sum *= 100;
+    a *= 100;
+
+    for (x_n, a_n) in X[2..].iter().zip(&A[2..]) {
+        if a >= *a_n {
+            a = (a * *ONE_20) / *a_n;
+            sum += *x_n;
+        }
+    }
+
+    // ln(a) = 2 * atanh(z) with z = (a - 1) / (a + 1)
+    let z = ((a - *ONE_20) * *ONE_20) / (a + *ONE_20);
+    let z_squared = (z * z) / *ONE_20;
+    let mut num = z;
+    let mut series_sum = num;
+    for n in [3, 5, 7, 9, 11] {
+        num = (num * z_squared) / *ONE_20;
+        series_sum += num / n;
+    }
+    series_sum *= 2;
+
+    (sum + series_sum) / 100
+}
+
+// ln with 36 decimals for x close to one, the same series without the decomposition
+fn ln_36(x: I256) -> I256 {
+    let x = x * *ONE_18;
+
+    let z = ((x - *ONE_36) * *ONE_36) / (x + *ONE_36);
+    let z_squared = (z * z) / *ONE_36;
+    let mut num = z;
+    let mut series_sum = num;
+    for n in [3, 5, 7, 9, 11, 13, 15] {
+        num = (num * z_squared) / *ONE_36;
+        series_sum += num / n;
+    }
+
+    series_sum * 2
+}
+
+#[cfg(test)]
+mod fixed_point_tests {
+    use super::*;
+
+    fn close(value: U256, expected: &str, tolerance: u64) -> bool {
+        let expected = U256::from_dec_str(expected).unwrap();
+        let diff = if value > expected {
+            value - expected
+        } else {
+            expected - value
+        };
+        diff <= U256::from(tolerance)
+    }
+
+    #[test]
+    fn log_exp_test() {
+        let e18 = U256::exp10(18);
+        assert!(close(
+            exp(*ONE_18).unwrap().into_raw(),
+            "2718281828459045235",
+            100
+        ));
+        assert!(close(
+            exp(-*ONE_18).unwrap().into_raw(),
+            "367879441171442321",
+            100
+        ));
+        assert_eq!(exp(*MAX_NATURAL_EXPONENT + 1), None);
+        assert_eq!(exp(*MIN_NATURAL_EXPONENT - 1), None);
+
+        // sqrt(2), through ln and through ln_36
+        assert!(close(
+            pow(e18 * 2, e18 / 2).unwrap(),
+            "1414213562373095048",
+            100
+        ));
+        assert!(close(
+            pow(e18 + e18 / 20, e18 * 3 / 2).unwrap(),
+            "1075929830425757830",
+            100
+        ));
+        assert!(close(
+            pow(e18 / 4, e18 / 2).unwrap(),
+            "500000000000000000",
+            100
+        ));
+        assert_eq!(pow(e18 * 5, U256::zero()), Some(e18));
+        assert_eq!(pow(U256::MAX, e18), None);
+
+        // pow_up only ever rounds up
+        let raw = pow(e18 * 3, e18 * 3 / 2).unwrap();
+        assert!(pow_up(e18 * 3, e18 * 3 / 2).unwrap() > raw);
+        assert_eq!(pow_up(e18 * 3, e18 * 2), Some(e18 * 9));
+    }
+
+    #[test]
+    fn rounding_test() {
+        let (a, b) = (U256::from(3u64), U256::exp10(17));
+        assert_eq!(mul_down(a, b), Some(U256::zero()));
+        assert_eq!(mul_up(a, b), Some(U256::one()));
+        assert_eq!(
+            div_down(U256::one(), U256::from(3u64) * one()),
+            Some(U256::zero())
+        );
+        assert_eq!(
+            div_up(U256::one(), U256::from(3u64) * one()),
+            Some(U256::one())
+        );
+        assert_eq!(div_up(U256::one(), U256::zero()), None);
+        assert_eq!(complement(one() * 2), U256::zero());
+    }
+}
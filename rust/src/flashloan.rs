@@ -0,0 +1,321 @@
+use anyhow::Result;
+use ethers::{
+    prelude::{abigen, Lazy},
+    providers::Middleware,
+    types::{Address, H160, U256},
+};
+use std::sync::Arc;
+
+use crate::amounts::TokenAmount;
+use crate::bundler::Flashloan;
+use crate::multi::Reserve;
+use crate::paths::ArbPath;
+use crate::pools::{DexVariant, PoolId, PoolRegistry};
+
+abigen!(
+    BalanceOf,
+    r#"[
+        function balanceOf(address account) external view returns (uint256)
+    ]"#,
+);
+
+abigen!(
+    BalancerVault,
+    r#"[
+        function getProtocolFeesCollector() external view returns (address)
+    ]"#,
+);
+
+abigen!(
+    BalancerProtocolFeesCollector,
+    r#"[
+        function getFlashLoanFeePercentage() external view returns (uint256)
+    ]"#,
+);
+
+// Balancer fee percentages are 18 decimal fixed point
+pub static BALANCER_FEE_SCALE: Lazy<U256> = Lazy::new(|| U256::from(10).pow(U256::from(18)));
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LoanSource {
+    pub flashloan: Flashloan,
+    pub loan_from: Address,
+    // paid on top of the borrowed amount, in the borrowed token's base units
+    pub fee: U256,
+}
+
+impl LoanSource {
+    pub fn own_capital() -> Self {
+        Self {
+            flashloan: Flashloan::NotUsed,
+            loan_from: Address::zero(),
+            fee: U256::zero(),
+        }
+    }
+}
+
+// What V2ArbBot.uniswapV2Call repays on top of the borrowed amount
+pub fn v2_flashswap_fee(amount: U256) -> U256 {
+    amount * U256::from(3) / U256::from(997) + U256::from(1)
+}
+
+pub fn balancer_flashloan_fee(amount: U256, fee_percentage: U256) -> U256 {
+    if fee_percentage.is_zero() {
+        return U256::zero();
+    }
+    // the vault rounds the fee up
+    let product = amount * fee_percentage;
+    (product + *BALANCER_FEE_SCALE - U256::from(1)) / *BALANCER_FEE_SCALE
+}
+
+/*
+The deepest V2 pair holding the token that the path doesn't trade through.
+Borrowing from a pool in the path would lock it, so those are skipped,
+and so are the pools in used, the ones other orders of the same bundle trade through or borrow from.
+*/
+pub fn find_v2_loan_pool(
+    path: &ArbPath,
+    token: &H160,
+    amount: U256,
+    pools: &PoolRegistry,
+    reserves: &[Reserve],
+    used: &[PoolId],
+) -> Option<H160> {
+    let mut best: Option<(H160, U256)> = None;
+
+    for (id, pool) in pools.iter() {
+        if !matches!(pool.version, DexVariant::UniswapV2) || path.has_pool(id) || used.contains(&id)
+        {
+            continue;
+        }
+        let reserve = match reserves.get(id.index()) {
+            Some(reserve) => reserve,
+            None => continue,
+        };
+        let token_reserve = if pool.token0 == *token {
+            reserve.reserve0
+        } else if pool.token1 == *token {
+            reserve.reserve1
+        } else {
+            continue;
+        };
+        // a V2 pair can't give out its whole reserve
+        if token_reserve <= amount {
+            continue;
+        }
+        let deeper = match best {
+            Some((_, best_reserve)) => token_reserve > best_reserve,
+            None => true,
+        };
+        if deeper {
+            best = Some((pool.address, token_reserve));
+        }
+    }
+
+    best.map(|(address, _)| address)
+}
+
+// The vault is locked while it lends, so a path swapping through a Balancer pool can't borrow from it
+pub fn swaps_through_vault(path: &ArbPath, pools: &PoolRegistry) -> bool {
+    (0..path.nhop).any(|i| {
+        pools
+            .get(path._get_pool(i))
+            .is_some_and(|pool| matches!(pool.version, DexVariant::BalancerWeighted))
+    })
+}
+
+pub struct FlashloanSelector<M> {
+    pub provider: Arc<M>,
+    pub bot: Address,
+    pub balancer_vault: Address,
+    pub balancer_fee_percentage: U256,
+}
+
+impl<M: Middleware + 'static> FlashloanSelector<M> {
+    pub fn new(provider: Arc<M>, bot: Address, balancer_vault: Address) -> Self {
+        Self {
+            provider,
+            bot,
+            balancer_vault,
+            balancer_fee_percentage: U256::zero(),
+        }
+    }
+
+    // Governance can change the flashloan fee, refresh it now and then
+    pub async fn update_balancer_fee(&mut self) -> Result<()> {
+        let vault = BalancerVault::new(self.balancer_vault, self.provider.clone());
+        let collector = vault.get_protocol_fees_collector().call().await?;
+        let collector = BalancerProtocolFeesCollector::new(collector, self.provider.clone());
+        self.balancer_fee_percentage = collector.get_flash_loan_fee_percentage().call().await?;
+        Ok(())
+    }
+
+    pub async fn balance_of(&self, token: H160, account: Address) -> Result<U256> {
+        let token = BalanceOf::new(token, self.provider.clone());
+        Ok(token.balance_of(account).call().await?)
+    }
+
+    /*
+    Picks the cheapest way to fund amount_in, in this order:
+    1. the bot's own balance, no fee and no callback
+    2. a Balancer flashloan, if the vault holds enough of the token and the path stays out of it
+    3. a V2 flashswap from a pair outside the path and outside used
+    Returns None when no source can cover the amount.
+    reserves are the ones the orders before this one in the bundle leave.
+    */
+    pub async fn select(
+        &self,
+        path: &ArbPath,
+        amount_in: &TokenAmount,
+        pools: &PoolRegistry,
+        reserves: &[Reserve],
+        used: &[PoolId],
+    ) -> Result<Option<LoanSource>> {
+        let token = amount_in.token;
+        let amount = amount_in.raw;
+
+        if self.balance_of(token, self.bot).await? >= amount {
+            return Ok(Some(LoanSource::own_capital()));
+        }
+
+        // V2ArbBot only repays the principal to Balancer, so it can't pay a non-zero fee
+        if self.balancer_fee_percentage.is_zero()
+            && !swaps_through_vault(path, pools)
+            && self.balance_of(token, self.balancer_vault).await? >= amount
+        {
+            return Ok(Some(LoanSource {
+                flashloan: Flashloan::Balancer,
+                loan_from: self.balancer_vault,
+                fee: U256::zero(),
+            }));
+        }
+
+        match find_v2_loan_pool(path, &token, amount, pools, reserves, used) {
+            Some(pair) => Ok(Some(LoanSource {
+                flashloan: Flashloan::UniswapV2,
+                loan_from: pair,
+                fee: v2_flashswap_fee(amount),
+            })),
+            None => Ok(None),
+        }
+    }
+}
+
+#[cfg(test)]
+mod flashloan_tests {
+    use super::*;
+    use crate::pools::WeightedPool;
+    use crate::test_utils::{pool, token};
+    use ethers::types::H256;
+    use std::collections::HashMap;
+
+    #[test]
+    fn loan_fee_test() {
+        // 1000 * 3 / 997 = 3.009..., plus one
+        assert_eq!(v2_flashswap_fee(U256::from(1000)), U256::from(4));
+        assert_eq!(
+            v2_flashswap_fee(U256::from(997_000_000u64)),
+            U256::from(3_000_001u64)
+        );
+
+        assert_eq!(
+            balancer_flashloan_fee(U256::from(1000), U256::zero()),
+            U256::zero()
+        );
+        // 0.05% of 1001 rounds up from 0.5005
+        assert_eq!(
+            balancer_flashloan_fee(U256::from(1001), U256::from(500_000_000_000_000u64)),
+            U256::from(1)
+        );
+    }
+
+    #[test]
+    fn swaps_through_vault_test() {
+        let (usdc, weth) = (token(0), token(1));
+        let mut registry = PoolRegistry::new(&vec![pool(100, usdc, weth)]);
+        registry.intern_weighted(&WeightedPool {
+            address: H160::from_low_u64_be(101),
+            pool_id: H256::from_low_u64_be(101),
+            tokens: vec![usdc, weth],
+            decimals: vec![18, 18],
+            weights: vec![U256::exp10(17) * 5, U256::exp10(17) * 5],
+            swap_fee: U256::exp10(15),
+            vault: H160::from_low_u64_be(99),
+        });
+
+        let through_vault = ArbPath::from_hops(&[(PoolId(0), true), (PoolId(1), false)]).unwrap();
+        assert!(swaps_through_vault(&through_vault, &registry));
+        let v2_only = ArbPath::from_hops(&[(PoolId(0), true)]).unwrap();
+        assert!(!swaps_through_vault(&v2_only, &registry));
+    }
+
+    #[test]
+    fn find_v2_loan_pool_test() {
+        let usdc = token(0);
+        let weth = token(1);
+        let token = token(2);
+
+        let usdc_weth = pool(100, usdc, weth);
+        let weth_token = pool(101, weth, token);
+        let token_usdc = pool(102, token, usdc);
+        let deep_usdc_weth = pool(103, weth, usdc);
+        let shallow_usdc_token = pool(104, usdc, token);
+
+        let path =
+            ArbPath::from_hops(&[(PoolId(0), true), (PoolId(1), true), (PoolId(2), true)]).unwrap();
+        let pools = vec![
+            usdc_weth,
+            weth_token,
+            token_usdc,
+            deep_usdc_weth.clone(),
+            shallow_usdc_token.clone(),
+        ];
+
+        let mut reserves = HashMap::new();
+        for pool in &pools {
+            reserves.insert(
+                pool.address,
+                Reserve {
+                    reserve0: U256::from(1_000),
+                    reserve1: U256::from(1_000),
+                },
+            );
+        }
+        reserves.insert(
+            deep_usdc_weth.address,
+            Reserve {
+                reserve0: U256::from(10),
+                reserve1: U256::from(50_000),
+            },
+        );
+        let registry = PoolRegistry::new(&pools);
+        let reserves = registry.dense_reserves(&reserves);
+
+        // the path's own USDC/WETH pool is deeper than the shallow pair but can't be used
+        assert_eq!(
+            find_v2_loan_pool(&path, &usdc, U256::from(500), &registry, &reserves, &[]),
+            Some(deep_usdc_weth.address)
+        );
+        assert_eq!(
+            find_v2_loan_pool(&path, &usdc, U256::from(50_000), &registry, &reserves, &[]),
+            None
+        );
+        assert_eq!(
+            find_v2_loan_pool(&path, &weth, U256::from(5), &registry, &reserves, &[]),
+            Some(deep_usdc_weth.address)
+        );
+
+        // another order in the bundle already uses the deep pair
+        assert_eq!(
+            find_v2_loan_pool(
+                &path,
+                &usdc,
+                U256::from(500),
+                &registry,
+                &reserves,
+                &[PoolId(3)]
+            ),
+            Some(shallow_usdc_token.address)
+        );
+    }
+}
@@ -0,0 +1,271 @@
+use anyhow::{anyhow, Result};
+use async_trait::async_trait;
+use ethers::{
+    providers::{Provider, Ws},
+    types::{U256, U64},
+};
+use futures::future::join_all;
+use serde::Deserialize;
+use std::{sync::Arc, time::Duration};
+
+use crate::constants::{Env, GWEI};
+use crate::streams::NewBlock;
+use crate::utils::{estimate_priority_fees, PRIORITY_FEE_PERCENTILES};
+
+pub static BLOCKNATIVE_URL: &str = "https://api.blocknative.com/gasprices/blockprices";
+
+#[async_trait]
+pub trait GasOracle: Send + Sync {
+    fn name(&self) -> &'static str;
+
+    // max_priority_fee_per_gas to bid for the block after this one
+    async fn priority_fee(&self, block: &NewBlock) -> Result<U256>;
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct BlocknativeResponse {
+    pub block_prices: Vec<BlocknativeBlockPrice>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct BlocknativeBlockPrice {
+    pub block_number: u64,
+    pub estimated_prices: Vec<BlocknativeEstimate>,
+}
+
+// Blocknative quotes prices in gwei
+#[derive(Debug, Clone, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct BlocknativeEstimate {
+    pub confidence: u64,
+    pub max_priority_fee_per_gas: f64,
+    pub max_fee_per_gas: f64,
+}
+
+pub struct BlocknativeOracle {
+    pub url: String,
+    pub token: String,
+    pub chain_id: U64,
+    pub confidence: u64,
+    pub client: reqwest::Client,
+}
+
+impl BlocknativeOracle {
+    pub fn new(url: &str, token: &str, chain_id: U64, confidence: u64) -> Self {
+        Self {
+            url: url.to_string(),
+            token: token.to_string(),
+            chain_id,
+            confidence,
+            client: reqwest::Client::new(),
+        }
+    }
+
+    pub async fn get_block_prices(&self) -> Result<BlocknativeResponse> {
+        let response = self
+            .client
+            .get(&self.url)
+            .query(&[("chainid", self.chain_id.as_u64())])
+            .header("Authorization", &self.token)
+            .send()
+            .await?
+            .error_for_status()?
+            .text()
+            .await?;
+        Ok(serde_json::from_str(&response)?)
+    }
+}
+
+#[async_trait]
+impl GasOracle for BlocknativeOracle {
+    fn name(&self) -> &'static str {
+        "blocknative"
+    }
+
+    async fn priority_fee(&self, _block: &NewBlock) -> Result<U256> {
+        let response = self.get_block_prices().await?;
+        let block_price = response
+            .block_prices
+            .first()
+            .ok_or_else(|| anyhow!("Blocknative returned no block prices"))?;
+
+        // lowest quote that still meets our confidence level
+        let estimate = block_price
+            .estimated_prices
+            .iter()
+            .filter(|estimate| estimate.confidence >= self.confidence)
+            .min_by_key(|estimate| estimate.confidence)
+            .ok_or_else(|| anyhow!("No Blocknative estimate at {}% confidence", self.confidence))?;
+
+        let wei = (estimate.max_priority_fee_per_gas * 1e9).round() as u128;
+        Ok(U256::from(wei))
+    }
+}
+
+// Fetches the fee history when a block needs pricing, fails without a provider
+pub struct FeeHistoryOracle {
+    pub percentile: f64,
+    pub provider: Option<Arc<Provider<Ws>>>,
+}
+
+#[async_trait]
+impl GasOracle for FeeHistoryOracle {
+    fn name(&self) -> &'static str {
+        "fee_history"
+    }
+
+    async fn priority_fee(&self, _block: &NewBlock) -> Result<U256> {
+        let provider = self
+            .provider
+            .as_ref()
+            .ok_or_else(|| anyhow!("No provider to fetch the fee history"))?;
+        let priority_fees =
+            estimate_priority_fees(provider.clone(), 20, &PRIORITY_FEE_PERCENTILES).await?;
+        priority_fees
+            .at(self.percentile)
+            .filter(|fee| !fee.is_zero())
+            .ok_or_else(|| anyhow!("No fee history at the {} percentile", self.percentile))
+    }
+}
+
+pub struct FixedOracle {
+    pub priority_fee: U256,
+}
+
+#[async_trait]
+impl GasOracle for FixedOracle {
+    fn name(&self) -> &'static str {
+        "fixed"
+    }
+
+    async fn priority_fee(&self, _block: &NewBlock) -> Result<U256> {
+        Ok(self.priority_fee)
+    }
+}
+
+/*
+Asks every oracle at once and returns the answer of the first one in order
+that arrives within the timeout, so a slow oracle costs the timeout once, not per oracle.
+*/
+pub struct FallbackOracle {
+    pub oracles: Vec<Box<dyn GasOracle>>,
+    pub timeout: Duration,
+}
+
+#[async_trait]
+impl GasOracle for FallbackOracle {
+    fn name(&self) -> &'static str {
+        "fallback"
+    }
+
+    async fn priority_fee(&self, block: &NewBlock) -> Result<U256> {
+        let answers = join_all(
+            self.oracles
+                .iter()
+                .map(|oracle| tokio::time::timeout(self.timeout, oracle.priority_fee(block))),
+        )
+        .await;
+        answers
+            .into_iter()
+            .find_map(|answer| answer.ok()?.ok())
+            .ok_or_else(|| anyhow!("All gas oracles failed"))
+    }
+}
+
+pub fn build_gas_oracle(env: &Env, provider: Arc<Provider<Ws>>) -> FallbackOracle {
+    let mut oracles: Vec<Box<dyn GasOracle>> = Vec::new();
+
+    if let Some(token) = &env.blocknative_token {
+        oracles.push(Box::new(BlocknativeOracle::new(
+            BLOCKNATIVE_URL,
+            token,
+            env.chain_id,
+            90,
+        )));
+    }
+    oracles.push(Box::new(FeeHistoryOracle {
+        percentile: 50.0,
+        provider: Some(provider),
+    }));
+    oracles.push(Box::new(FixedOracle {
+        priority_fee: U256::from(1) * *GWEI,
+    }));
+
+    FallbackOracle {
+        oracles,
+        timeout: Duration::from_millis(300),
+    }
+}
+
+#[cfg(test)]
+mod gas_tests {
+    use super::*;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+
+    static BLOCK_PRICES: &str = r#"{"system":"ethereum","network":"main","unit":"gwei","maxPrice":64,"currentBlockNumber":18000000,"msSinceLastBlock":3000,"blockPrices":[{"blockNumber":18000001,"estimatedTransactionCount":140,"baseFeePerGas":12.5,"estimatedPrices":[{"confidence":99,"price":14,"maxPriorityFeePerGas":1.5,"maxFeePerGas":26.5},{"confidence":95,"price":13,"maxPriorityFeePerGas":0.52,"maxFeePerGas":25.52},{"confidence":90,"price":13,"maxPriorityFeePerGas":0.25,"maxFeePerGas":25.25},{"confidence":80,"price":12.6,"maxPriorityFeePerGas":0.1,"maxFeePerGas":25.1}]}]}"#;
+
+    // local stand-in for the Blocknative gas platform, returns the request line it got
+    async fn blocknative_stand_in(respond: bool) -> (String, tokio::task::JoinHandle<String>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!(
+            "http://{}/gasprices/blockprices",
+            listener.local_addr().unwrap()
+        );
+        let handle = tokio::spawn(async move {
+            let (mut socket, _) = listener.accept().await.unwrap();
+            let mut request = [0u8; 2048];
+            let n = socket.read(&mut request).await.unwrap();
+            let request = String::from_utf8_lossy(&request[..n]).to_string();
+            if respond {
+                let response = format!(
+                    "HTTP/1.1 200 OK\r\ncontent-type: application/json\r\ncontent-length: {}\r\n\r\n{}",
+                    BLOCK_PRICES.len(),
+                    BLOCK_PRICES
+                );
+                socket.write_all(response.as_bytes()).await.unwrap();
+            } else {
+                tokio::time::sleep(Duration::from_secs(5)).await;
+            }
+            request
+        });
+        (url, handle)
+    }
+
+    #[tokio::test]
+    async fn blocknative_oracle_test() {
+        let (url, handle) = blocknative_stand_in(true).await;
+        let oracle = BlocknativeOracle::new(&url, "bn-token", U64::from(1), 90);
+
+        let priority_fee = oracle.priority_fee(&NewBlock::default()).await.unwrap();
+        assert_eq!(priority_fee, U256::from(250_000_000u64));
+
+        let request = handle.await.unwrap();
+        assert!(request.starts_with("GET /gasprices/blockprices?chainid=1 "));
+        assert!(request.to_lowercase().contains("authorization: bn-token"));
+    }
+
+    #[tokio::test]
+    async fn fallback_oracle_test() {
+        let (url, _handle) = blocknative_stand_in(false).await;
+        let block = NewBlock::default();
+
+        // blocknative times out and the fee history has no provider, so the fixed fee wins
+        let oracle = FallbackOracle {
+            oracles: vec![
+                Box::new(BlocknativeOracle::new(&url, "bn-token", U64::from(1), 90)),
+                Box::new(FeeHistoryOracle {
+                    percentile: 50.0,
+                    provider: None,
+                }),
+                Box::new(FixedOracle {
+                    priority_fee: U256::from(7),
+                }),
+            ],
+            timeout: Duration::from_millis(100),
+        };
+        assert_eq!(oracle.priority_fee(&block).await.unwrap(), U256::from(7));
+    }
+}
@@ -1,10 +1,29 @@
 pub mod abi;
+pub mod amounts;
+pub mod balancer;
+pub mod batch;
 pub mod bundler;
 pub mod constants;
+pub mod cost;
+pub mod curve;
+pub mod cycles;
+pub mod execution;
+pub mod fixed_point;
+pub mod flashloan;
+pub mod gas;
+pub mod mev_share;
 pub mod multi;
 pub mod paths;
+pub mod policy;
 pub mod pools;
+pub mod pricing;
+pub mod runtime;
+pub mod scoring;
+pub mod selection;
 pub mod simulator;
 pub mod strategy;
 pub mod streams;
+#[cfg(test)]
+mod test_utils;
+pub mod tokens;
 pub mod utils;
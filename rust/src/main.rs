@@ -33,6 +33,11 @@ async fn main() -> Result<()> {
     //     provider.clone(),
     //     event_sender.clone(),
     // ));
+    // mev-share hints are only available on Ethereum mainnet
+    // set.spawn(rust::streams::stream_mev_share_hints(
+    //     rust::mev_share::MEV_SHARE_SSE_URL.to_string(),
+    //     event_sender.clone(),
+    // ));
     set.spawn(event_handler(provider.clone(), event_sender.clone()));
 
     while let Some(res) = set.join_next().await {
@@ -0,0 +1,245 @@
+use ethers::types::{Address, Bytes, TxHash, H256, U256, U64};
+use serde::{Deserialize, Serialize};
+
+pub static MEV_SHARE_SSE_URL: &str = "https://mev-share.flashbots.net";
+pub static MEV_SHARE_RELAY_URL: &str = "https://relay.flashbots.net";
+
+/*
+MEV-Share hints as they come in from the SSE stream.
+Every field other than the hash is optional and depends on what the user chose to share.
+*/
+#[derive(Default, Debug, Clone, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct MevShareHint {
+    pub hash: TxHash,
+    #[serde(default)]
+    pub logs: Vec<MevShareLog>,
+    #[serde(default)]
+    pub txs: Vec<MevShareTx>,
+    pub mev_gas_price: Option<U256>,
+    pub gas_used: Option<U256>,
+}
+
+#[derive(Default, Debug, Clone, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct MevShareLog {
+    pub address: Address,
+    #[serde(default)]
+    pub topics: Vec<H256>,
+    pub data: Option<Bytes>,
+}
+
+#[derive(Default, Debug, Clone, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct MevShareTx {
+    pub to: Option<Address>,
+    pub function_selector: Option<Bytes>,
+    pub call_data: Option<Bytes>,
+}
+
+/*
+Request body for mev_sendBundle (v0.1).
+Hint transactions are referenced by hash, our own transactions are sent signed.
+*/
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct MevShareBundle {
+    pub version: String,
+    pub inclusion: MevShareInclusion,
+    pub body: Vec<MevShareBundleItem>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub validity: Option<MevShareValidity>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub privacy: Option<MevSharePrivacy>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct MevShareInclusion {
+    pub block: U64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_block: Option<U64>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(untagged)]
+pub enum MevShareBundleItem {
+    Hash {
+        hash: TxHash,
+    },
+    Tx {
+        tx: Bytes,
+        #[serde(rename = "canRevert")]
+        can_revert: bool,
+    },
+}
+
+#[derive(Default, Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct MevShareValidity {
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub refund: Vec<MevShareRefund>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub refund_config: Vec<MevShareRefundConfig>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct MevShareRefund {
+    pub body_idx: u64,
+    pub percent: u64,
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct MevShareRefundConfig {
+    pub address: Address,
+    pub percent: u64,
+}
+
+#[derive(Default, Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct MevSharePrivacy {
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub hints: Vec<String>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub builders: Vec<String>,
+}
+
+/*
+Bytes are buffered until a whole line is in, so a character or a "\r\n" split between
+network chunks is only decoded once it's complete.
+*/
+#[derive(Default, Debug, Clone)]
+pub struct SseParser {
+    buffer: Vec<u8>,
+    // data lines of the event being read
+    data: Vec<String>,
+}
+
+impl SseParser {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /*
+    Feed raw bytes from the stream and get back the data payloads of every completed event.
+    Comment lines (": ping") and fields other than "data" are ignored.
+    */
+    pub fn feed(&mut self, chunk: &[u8]) -> Vec<String> {
+        self.buffer.extend_from_slice(chunk);
+
+        let mut payloads = Vec::new();
+        while let Some(end) = self.buffer.iter().position(|byte| *byte == b'\n') {
+            let mut line: Vec<u8> = self.buffer.drain(..end + 1).collect();
+            line.pop();
+            if line.last() == Some(&b'\r') {
+                line.pop();
+            }
+            let line = String::from_utf8_lossy(&line);
+
+            if line.is_empty() {
+                if !self.data.is_empty() {
+                    payloads.push(self.data.join("\n"));
+                    self.data.clear();
+                }
+            } else if let Some(data) = line.strip_prefix("data:") {
+                self.data.push(data.trim_start().to_string());
+            }
+        }
+        payloads
+    }
+}
+
+#[cfg(test)]
+mod mev_share_tests {
+    use super::*;
+    use crate::streams::{stream_mev_share_hints, Event};
+    use std::str::FromStr;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+    use tokio::sync::broadcast::{self, Sender};
+
+    static HINT: &str = r#"{"hash":"0x5f2d5b1a4c7d3e8f9a0b1c2d3e4f5a6b7c8d9e0f1a2b3c4d5e6f7a8b9c0d1e2f","logs":[{"address":"0x397ff1542f962076d0bfe58ea045ffa2d347aca0","topics":["0x1c411e9a96e071241c2f21f7726b17ae89e3cab4c78be50e062b03a9fffbbad1"],"data":"0x"}],"txs":[{"to":"0xd9e1ce17f2641f24ae83637ab66a2cca9c378b9f","functionSelector":"0x38ed1739"}],"mevGasPrice":"0x2540be400","gasUsed":"0x30d40"}"#;
+
+    #[test]
+    fn sse_parser_test() {
+        let mut parser = SseParser::new();
+        assert!(parser.feed(b": ping\n\ndata: {\"a\"").is_empty());
+        let payloads = parser.feed(b":1}\r\n\r\ndata: 2\n\n");
+        assert_eq!(payloads, vec!["{\"a\":1}".to_string(), "2".to_string()]);
+
+        // "é" and a "\r\n" split between chunks
+        assert!(parser.feed(b"data: caf\xc3").is_empty());
+        assert!(parser.feed(b"\xa9\r").is_empty());
+        assert!(parser.feed(b"\n\r").is_empty());
+        assert_eq!(parser.feed(b"\n"), vec!["caf\u{e9}".to_string()]);
+    }
+
+    #[tokio::test]
+    async fn stream_mev_share_hints_test() {
+        // local stand-in for the MEV-Share SSE endpoint
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let sse_url = format!("http://{}", listener.local_addr().unwrap());
+        tokio::spawn(async move {
+            let (mut socket, _) = listener.accept().await.unwrap();
+            let mut request = [0u8; 1024];
+            let _ = socket.read(&mut request).await.unwrap();
+            let response = format!(
+                "HTTP/1.1 200 OK\r\ncontent-type: text/event-stream\r\n\r\n: ping\n\ndata: {}\n\n",
+                HINT
+            );
+            socket.write_all(response.as_bytes()).await.unwrap();
+        });
+
+        let (event_sender, _): (Sender<Event>, _) = broadcast::channel(16);
+        let mut event_receiver = event_sender.subscribe();
+        tokio::spawn(stream_mev_share_hints(sse_url, event_sender.clone()));
+
+        let hint = match event_receiver.recv().await.unwrap() {
+            Event::MevShare(hint) => hint,
+            _ => panic!("Expected a MEV-Share hint"),
+        };
+        assert_eq!(
+            hint.hash,
+            TxHash::from_str("0x5f2d5b1a4c7d3e8f9a0b1c2d3e4f5a6b7c8d9e0f1a2b3c4d5e6f7a8b9c0d1e2f")
+                .unwrap()
+        );
+        assert_eq!(hint.logs.len(), 1);
+        assert_eq!(hint.txs[0].call_data, None);
+        assert_eq!(hint.gas_used, Some(U256::from(200000)));
+    }
+
+    #[test]
+    fn mev_share_bundle_test() {
+        let bundle = MevShareBundle {
+            version: "v0.1".to_string(),
+            inclusion: MevShareInclusion {
+                block: U64::from(100),
+                max_block: None,
+            },
+            body: vec![
+                MevShareBundleItem::Hash {
+                    hash: TxHash::zero(),
+                },
+                MevShareBundleItem::Tx {
+                    tx: Bytes::from(vec![1u8]),
+                    can_revert: false,
+                },
+            ],
+            validity: Some(MevShareValidity {
+                refund: vec![MevShareRefund {
+                    body_idx: 0,
+                    percent: 90,
+                }],
+                refund_config: Vec::new(),
+            }),
+            privacy: None,
+        };
+        let json = serde_json::to_value(&bundle).unwrap();
+        assert_eq!(json["inclusion"]["block"], "0x64");
+        assert_eq!(json["body"][1]["canRevert"], false);
+        assert_eq!(json["validity"]["refund"][0]["bodyIdx"], 0);
+        assert!(json.get("privacy").is_none());
+    }
+}
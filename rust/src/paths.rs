@@ -1,137 +1,297 @@
 use ethers::types::{H160, U256};
 use indicatif::{ProgressBar, ProgressStyle};
-use itertools::Itertools;
-use std::{collections::HashMap, time::Instant};
+use std::time::Instant;
+use thiserror::Error;
 
+use crate::amounts::{Profit, TokenAmount};
 use crate::bundler::PathParam;
 use crate::multi::Reserve;
-use crate::pools::Pool;
-use crate::simulator::UniswapV2Simulator;
+use crate::policy::{Policy, PolicyViolation};
+use crate::pools::{DexVariant, PoolId, PoolRegistry, RouterRegistry};
+use crate::simulator::{StableSwapSimulator, UniswapV2Simulator, WeightedSimulator};
+
+#[derive(Debug, Clone, PartialEq, Eq, Error)]
+pub enum PathError {
+    #[error("path has {0} hops, expected 1 to {}", MAX_HOPS)]
+    HopCount(u8),
+    #[error("hop {hop} pool does not hold {token:?}")]
+    Continuity { hop: u8, token: H160 },
+    #[error("hop {hop} swaps in the wrong direction")]
+    Direction { hop: u8 },
+    #[error("pool id {0:?} is not in the registry")]
+    UnknownPool(PoolId),
+    #[error("pool {0:?} is used more than once")]
+    DuplicatePool(H160),
+    #[error("path ends in {end:?} instead of {start:?}")]
+    NotClosed { start: H160, end: H160 },
+    #[error("hop {hop} is denied by policy: {violation}")]
+    Denied { hop: u8, violation: PolicyViolation },
+}
+
+// The most hops a path can have, paths store that many pool ids
+pub const MAX_HOPS: usize = 6;
 
-#[derive(Debug, Clone)]
+/*
+A path is a few pool ids into the PoolRegistry, so it is Copy and never allocates.
+Only the first nhop entries of pools and zero_for_one are used.
+*/
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub struct ArbPath {
     pub nhop: u8,
-    pub pool_1: Pool,
-    pub pool_2: Pool,
-    pub pool_3: Pool,
-    pub zero_for_one_1: bool,
-    pub zero_for_one_2: bool,
-    pub zero_for_one_3: bool,
+    pub pools: [PoolId; MAX_HOPS],
+    pub zero_for_one: [bool; MAX_HOPS],
 }
 
 impl ArbPath {
-    pub fn has_pool(&self, pool: &H160) -> bool {
-        let is_pool_1 = self.pool_1.address == *pool;
-        let is_pool_2 = self.pool_2.address == *pool;
-        let is_pool_3 = self.pool_3.address == *pool;
-        return is_pool_1 || is_pool_2 || is_pool_3;
+    // hops are (pool, zero_for_one) in swap order
+    pub fn from_hops(hops: &[(PoolId, bool)]) -> Result<Self, PathError> {
+        if hops.is_empty() || hops.len() > MAX_HOPS {
+            return Err(PathError::HopCount(hops.len().min(u8::MAX as usize) as u8));
+        }
+        let mut path = Self {
+            nhop: hops.len() as u8,
+            pools: [PoolId::default(); MAX_HOPS],
+            zero_for_one: [false; MAX_HOPS],
+        };
+        for (i, (pool, zero_for_one)) in hops.iter().enumerate() {
+            path.pools[i] = *pool;
+            path.zero_for_one[i] = *zero_for_one;
+        }
+        Ok(path)
     }
 
-    pub fn _get_pool(&self, i: u8) -> &Pool {
-        match i {
-            0 => Some(&self.pool_1),
-            1 => Some(&self.pool_2),
-            2 => Some(&self.pool_3),
-            _ => None,
-        }
-        .unwrap()
+    pub fn has_pool(&self, pool: PoolId) -> bool {
+        self.pools
+            .iter()
+            .take(self.nhop as usize)
+            .any(|id| *id == pool)
+    }
+
+    pub fn _get_pool(&self, i: u8) -> PoolId {
+        self.pools[i as usize]
     }
 
     pub fn _get_zero_for_one(&self, i: u8) -> bool {
-        match i {
-            0 => Some(self.zero_for_one_1),
-            1 => Some(self.zero_for_one_2),
-            2 => Some(self.zero_for_one_3),
-            _ => None,
-        }
-        .unwrap()
+        self.zero_for_one[i as usize]
     }
 
-    pub fn should_blacklist(&self, blacklist_tokens: &Vec<H160>) -> bool {
+    /*
+    Checks that the path is a closed cycle the contract can execute:
+    every hop takes in what the previous hop gave out, the direction flags agree with that,
+    no pool is used twice, and the policy allows every pool, its factory and its tokens.
+    */
+    pub fn validate(&self, pools: &PoolRegistry, policy: &Policy) -> Result<(), PathError> {
+        if self.nhop == 0 || self.nhop as usize > MAX_HOPS {
+            return Err(PathError::HopCount(self.nhop));
+        }
+
+        if pools.get(self._get_pool(0)).is_none() {
+            return Err(PathError::UnknownPool(self._get_pool(0)));
+        }
+        let (start, _) = self.token_in(pools);
+        let mut token = start;
+
         for i in 0..self.nhop {
-            let pool = self._get_pool(i);
-            return blacklist_tokens.contains(&pool.token0)
-                || blacklist_tokens.contains(&pool.token1);
+            let id = self._get_pool(i);
+            let pool = pools.get(id).ok_or(PathError::UnknownPool(id))?;
+
+            if pool.token0 != token && pool.token1 != token {
+                return Err(PathError::Continuity { hop: i, token });
+            }
+            if self._get_zero_for_one(i) != (pool.token0 == token) {
+                return Err(PathError::Direction { hop: i });
+            }
+            // pair views of one Curve or Balancer pool have their own ids but share the address
+            if (0..i).any(|j| pools[self._get_pool(j)].address == pool.address) {
+                return Err(PathError::DuplicatePool(pool.address));
+            }
+
+            if let Err(violation) = policy.check_pool(pool) {
+                return Err(PathError::Denied { hop: i, violation });
+            }
+
+            token = if pool.token0 == token {
+                pool.token1
+            } else {
+                pool.token0
+            };
+        }
+
+        if token != start {
+            return Err(PathError::NotClosed { start, end: token });
         }
-        false
+        Ok(())
     }
 
+    pub fn token_in(&self, pools: &PoolRegistry) -> (H160, u8) {
+        let pool_1 = &pools[self._get_pool(0)];
+        if self._get_zero_for_one(0) {
+            (pool_1.token0, pool_1.decimals0)
+        } else {
+            (pool_1.token1, pool_1.decimals1)
+        }
+    }
+
+    /*
+    amount_in is in the start token's base units, and so is the output.
+    reserves is indexed by PoolId (see PoolRegistry::dense_reserves), so there are no hash lookups.
+    Curve and Balancer hops read the token balances from the pair views' reserves.
+    */
     pub fn simulate_v2_path(
         &self,
         amount_in: U256,
-        reserves: &HashMap<H160, Reserve>,
+        pools: &PoolRegistry,
+        reserves: &[Reserve],
     ) -> Option<U256> {
-        let token_in_decimals = if self.zero_for_one_1 {
-            self.pool_1.decimals0
-        } else {
-            self.pool_1.decimals1
-        };
-        let unit = U256::from(10).pow(U256::from(token_in_decimals));
-        let mut amount_out = amount_in * unit;
+        let mut amount_out = amount_in;
 
         for i in 0..self.nhop {
-            let pool = self._get_pool(i);
+            amount_out = swap(
+                self._get_pool(i),
+                self._get_zero_for_one(i),
+                amount_out,
+                pools,
+                reserves,
+            )?;
+        }
+
+        Some(amount_out)
+    }
+
+    pub fn simulate_amount(
+        &self,
+        amount_in: &TokenAmount,
+        pools: &PoolRegistry,
+        reserves: &[Reserve],
+    ) -> Option<TokenAmount> {
+        let amount_out = self.simulate_v2_path(amount_in.raw, pools, reserves)?;
+        Some(amount_in.with_raw(amount_out))
+    }
+
+    /*
+    Like simulate_v2_path, but leaves reserves as they are after the swaps.
+    The part of a Curve or Balancer fee that goes to the admin or protocol isn't taken out,
+    the next sync corrects it.
+    */
+    pub fn apply_v2_path(
+        &self,
+        amount_in: U256,
+        pools: &PoolRegistry,
+        reserves: &mut [Reserve],
+    ) -> Option<U256> {
+        let mut amount_out = amount_in;
+
+        for i in 0..self.nhop {
+            let id = self._get_pool(i);
             let zero_for_one = self._get_zero_for_one(i);
+            let pool = pools.get(id)?;
+            let amount_in = amount_out;
+
+            if matches!(
+                pool.version,
+                DexVariant::CurveStableSwap | DexVariant::BalancerWeighted
+            ) {
+                let (index0, index1) = pools.view_indices(id)?;
+                let (index_in, index_out) = if zero_for_one {
+                    (index0, index1)
+                } else {
+                    (index1, index0)
+                };
+                let mut balances = pools.pool_balances(&pool.address, reserves)?;
+                amount_out = swap(id, zero_for_one, amount_in, pools, reserves)?;
+                balances[index_in] = balances[index_in].checked_add(amount_in)?;
+                balances[index_out] -= amount_out;
+                pools.set_pool_balances(&pool.address, &balances, reserves);
+                continue;
+            }
 
-            let reserve = reserves.get(&pool.address)?;
-            let reserve0 = reserve.reserve0;
-            let reserve1 = reserve.reserve1;
             let fee = U256::from(pool.fee);
-
-            let reserve_in;
-            let reserve_out;
+            let reserve = reserves.get_mut(id.index())?;
             if zero_for_one {
-                reserve_in = reserve0;
-                reserve_out = reserve1;
+                amount_out = UniswapV2Simulator::get_amount_out(
+                    amount_in,
+                    reserve.reserve0,
+                    reserve.reserve1,
+                    fee,
+                )?;
+                reserve.reserve0 = reserve.reserve0.checked_add(amount_in)?;
+                reserve.reserve1 -= amount_out;
             } else {
-                reserve_in = reserve1;
-                reserve_out = reserve0;
+                amount_out = UniswapV2Simulator::get_amount_out(
+                    amount_in,
+                    reserve.reserve1,
+                    reserve.reserve0,
+                    fee,
+                )?;
+                reserve.reserve1 = reserve.reserve1.checked_add(amount_in)?;
+                reserve.reserve0 -= amount_out;
             }
-
-            amount_out =
-                UniswapV2Simulator::get_amount_out(amount_out, reserve_in, reserve_out, fee)?;
         }
 
         Some(amount_out)
     }
 
+    // Steps through amounts in base units up to max_amount_in, stopping once profit declines
     pub fn optimize_amount_in(
         &self,
         max_amount_in: U256,
-        step_size: usize,
-        reserves: &HashMap<H160, Reserve>,
-    ) -> (U256, U256) {
-        let token_in_decimals = if self.zero_for_one_1 {
-            self.pool_1.decimals0
-        } else {
-            self.pool_1.decimals1
-        };
+        step_size: U256,
+        pools: &PoolRegistry,
+        reserves: &[Reserve],
+    ) -> (TokenAmount, Profit) {
+        let (token_in, token_in_decimals) = self.token_in(pools);
 
-        let mut optimized_in = U256::zero();
-        let mut profit = 0;
+        let mut optimized_in = TokenAmount::zero(token_in, token_in_decimals);
+        let mut profit = Profit::zero(token_in, token_in_decimals);
 
-        for amount_in in (0..max_amount_in.as_u64()).step_by(step_size) {
-            let amount_in = U256::from(amount_in);
-            let unit = U256::from(10).pow(U256::from(token_in_decimals));
-            if let Some(amount_out) = self.simulate_v2_path(amount_in, &reserves) {
-                let this_profit =
-                    (amount_out.as_u128() as i128) - ((amount_in * unit).as_u128() as i128);
-                if this_profit >= profit {
+        if step_size.is_zero() {
+            return (optimized_in, profit);
+        }
+
+        let mut amount_in = optimized_in;
+        while amount_in.raw < max_amount_in {
+            if let Some(amount_out) = self.simulate_amount(&amount_in, pools, reserves) {
+                let this_profit = Profit::from_amounts(&amount_out, &amount_in).unwrap();
+                if this_profit.value >= profit.value {
                     optimized_in = amount_in;
                     profit = this_profit;
                 } else {
                     break;
                 }
             }
+            amount_in = match amount_in.raw.checked_add(step_size) {
+                Some(raw) => amount_in.with_raw(raw),
+                None => break,
+            };
         }
 
-        (optimized_in, U256::from(profit))
+        (optimized_in, profit)
     }
 
-    pub fn to_path_params(&self, routers: &Vec<H160>) -> Vec<PathParam> {
+    /*
+    Whether the bot can send the path. It only swaps through V2 routers,
+    so every pool has to be a V2 pair from a factory with a registered router.
+    Curve and Balancer paths can be simulated but not executed.
+    */
+    pub fn is_executable(&self, pools: &PoolRegistry, registry: &RouterRegistry) -> bool {
+        (0..self.nhop).all(|i| match pools.get(self._get_pool(i)) {
+            Some(pool) => {
+                matches!(pool.version, DexVariant::UniswapV2)
+                    && registry.router(&pool.factory).is_some()
+            }
+            None => false,
+        })
+    }
+
+    // None if a pool's factory has no router registered
+    pub fn to_path_params(
+        &self,
+        pools: &PoolRegistry,
+        registry: &RouterRegistry,
+    ) -> Option<Vec<PathParam>> {
         let mut path_params = Vec::new();
         for i in 0..self.nhop {
-            let pool = self._get_pool(i);
+            let pool = pools.get(self._get_pool(i))?;
             let zero_for_one = self._get_zero_for_one(i);
 
             let token_in;
@@ -145,20 +305,155 @@ impl ArbPath {
             }
 
             let param = PathParam {
-                router: routers[i as usize],
+                router: registry.router(&pool.factory)?,
+                pair: pool.address,
                 token_in: token_in,
                 token_out: token_out,
             };
             path_params.push(param);
         }
-        path_params
+        Some(path_params)
+    }
+}
+
+// One hop through a pool, dispatched on its kind
+fn swap(
+    id: PoolId,
+    zero_for_one: bool,
+    amount_in: U256,
+    pools: &PoolRegistry,
+    reserves: &[Reserve],
+) -> Option<U256> {
+    let pool = pools.get(id)?;
+    match pool.version {
+        DexVariant::CurveStableSwap => {
+            let (curve, coin0, coin1) = pools.curve_pool(id)?;
+            let balances = pools.pool_balances(&curve.address, reserves)?;
+            let (coin_in, coin_out) = if zero_for_one {
+                (coin0, coin1)
+            } else {
+                (coin1, coin0)
+            };
+            StableSwapSimulator::get_dy(
+                coin_in,
+                coin_out,
+                amount_in,
+                &balances,
+                &curve.decimals,
+                curve.amp,
+                curve.fee,
+            )
+        }
+        DexVariant::BalancerWeighted => {
+            let (weighted, token0, token1) = pools.weighted_pool(id)?;
+            let reserve = reserves.get(id.index())?;
+            let (token_in, token_out, balances) = if zero_for_one {
+                (token0, token1, (reserve.reserve0, reserve.reserve1))
+            } else {
+                (token1, token0, (reserve.reserve1, reserve.reserve0))
+            };
+            WeightedSimulator::get_amount_out(
+                amount_in,
+                balances,
+                (weighted.decimals[token_in], weighted.decimals[token_out]),
+                (weighted.weights[token_in], weighted.weights[token_out]),
+                weighted.swap_fee,
+            )
+        }
+        _ => {
+            let reserve = reserves.get(id.index())?;
+            let (reserve_in, reserve_out) = if zero_for_one {
+                (reserve.reserve0, reserve.reserve1)
+            } else {
+                (reserve.reserve1, reserve.reserve0)
+            };
+            UniswapV2Simulator::get_amount_out(
+                amount_in,
+                reserve_in,
+                reserve_out,
+                U256::from(pool.fee),
+            )
+        }
     }
 }
 
-pub fn generate_triangular_paths(pools: &Vec<Pool>, token_in: H160) -> Vec<ArbPath> {
+/*
+Maps each pool to the ids (indexes into the paths Vec) of the paths trading through it,
+so a block only costs as much as the paths its touched pools are part of.
+Indexed by PoolId like the reserves.
+*/
+#[derive(Debug, Clone, Default)]
+pub struct PathIndex {
+    pub paths_by_pool: Vec<Vec<usize>>,
+}
+
+impl PathIndex {
+    pub fn new(paths: &[ArbPath]) -> Self {
+        let mut index = Self::default();
+        for (path_id, path) in paths.iter().enumerate() {
+            index.insert(path_id, path);
+        }
+        index
+    }
+
+    pub fn insert(&mut self, path_id: usize, path: &ArbPath) {
+        for i in 0..path.nhop {
+            let pool = path._get_pool(i).index();
+            if pool >= self.paths_by_pool.len() {
+                self.paths_by_pool.resize(pool + 1, Vec::new());
+            }
+            let ids = &mut self.paths_by_pool[pool];
+            // path ids only grow, so checking the last one is enough
+            if ids.last() != Some(&path_id) {
+                ids.push(path_id);
+            }
+        }
+    }
+
+    pub fn paths_for_pool(&self, pool: PoolId) -> &[usize] {
+        match self.paths_by_pool.get(pool.index()) {
+            Some(ids) => ids,
+            None => &[],
+        }
+    }
+
+    // Ids of the paths trading through any of the pools, sorted and without duplicates
+    pub fn touched_paths(&self, pools: &[PoolId]) -> Vec<usize> {
+        let mut path_ids: Vec<usize> = pools
+            .iter()
+            .flat_map(|pool| self.paths_for_pool(*pool).iter().copied())
+            .collect();
+        path_ids.sort_unstable();
+        path_ids.dedup();
+        path_ids
+    }
+
+    // Pools that at least one path trades through
+    pub fn pools(&self) -> Vec<PoolId> {
+        self.paths_by_pool
+            .iter()
+            .enumerate()
+            .filter(|(_, ids)| !ids.is_empty())
+            .map(|(i, _)| PoolId(i as u32))
+            .collect()
+    }
+
+    pub fn pool_count(&self) -> usize {
+        self.paths_by_pool
+            .iter()
+            .filter(|ids| !ids.is_empty())
+            .count()
+    }
+}
+
+pub fn generate_triangular_paths(
+    pools: &PoolRegistry,
+    token_in: H160,
+    policy: &Policy,
+) -> Vec<ArbPath> {
     let start_time = Instant::now();
 
-    let token_out = token_in.clone();
+    let token_out = token_in;
     let mut paths = Vec::new();
 
     let pb = ProgressBar::new(pools.len() as u64);
@@ -170,8 +465,7 @@ pub fn generate_triangular_paths(pools: &Vec<Pool>, token_in: H160) -> Vec<ArbPa
         .progress_chars("##-"),
     );
 
-    for i in 0..pools.len() {
-        let pool_1 = &pools[i];
+    for (id_1, pool_1) in pools.iter() {
         let can_trade_1 = (pool_1.token0 == token_in) || (pool_1.token1 == token_in);
 
         if can_trade_1 {
@@ -185,8 +479,7 @@ pub fn generate_triangular_paths(pools: &Vec<Pool>, token_in: H160) -> Vec<ArbPa
                 continue;
             }
 
-            for j in 0..pools.len() {
-                let pool_2 = &pools[j];
+            for (id_2, pool_2) in pools.iter() {
                 let can_trade_2 = (pool_2.token0 == token_out_1) || (pool_2.token1 == token_out_1);
 
                 if can_trade_2 {
@@ -200,14 +493,12 @@ pub fn generate_triangular_paths(pools: &Vec<Pool>, token_in: H160) -> Vec<ArbPa
                         continue;
                     }
 
-                    for k in 0..pools.len() {
-                        let pool_3 = &pools[k];
+                    for (id_3, pool_3) in pools.iter() {
                         let can_trade_3 =
                             (pool_3.token0 == token_out_2) || (pool_3.token1 == token_out_2);
 
                         if can_trade_3 {
-                            let zero_for_one_3 =
-                                (pool_3.token0 == token_out_2) || (pool_3.token1 == token_out_2);
+                            let zero_for_one_3 = pool_3.token0 == token_out_2;
                             let (token_in_3, token_out_3) = if zero_for_one_3 {
                                 (pool_3.token0, pool_3.token1)
                             } else {
@@ -218,28 +509,20 @@ pub fn generate_triangular_paths(pools: &Vec<Pool>, token_in: H160) -> Vec<ArbPa
                             }
 
                             if token_out_3 == token_out {
-                                let unique_pool_cnt =
-                                    vec![pool_1.address, pool_2.address, pool_3.address]
-                                        .into_iter()
-                                        .unique()
-                                        .collect::<Vec<H160>>()
-                                        .len();
-
-                                if unique_pool_cnt < 3 {
+                                if id_1 == id_2 || id_2 == id_3 || id_1 == id_3 {
                                     continue;
                                 }
 
-                                let arb_path = ArbPath {
-                                    nhop: 3,
-                                    pool_1: pool_1.clone(),
-                                    pool_2: pool_2.clone(),
-                                    pool_3: pool_3.clone(),
-                                    zero_for_one_1: zero_for_one_1,
-                                    zero_for_one_2: zero_for_one_2,
-                                    zero_for_one_3: zero_for_one_3,
-                                };
-
-                                paths.push(arb_path);
+                                let arb_path = ArbPath::from_hops(&[
+                                    (id_1, zero_for_one_1),
+                                    (id_2, zero_for_one_2),
+                                    (id_3, zero_for_one_3),
+                                ])
+                                .unwrap();
+
+                                if arb_path.validate(pools, policy).is_ok() {
+                                    paths.push(arb_path);
+                                }
                             }
                         }
                     }
@@ -257,3 +540,421 @@ pub fn generate_triangular_paths(pools: &Vec<Pool>, token_in: H160) -> Vec<ArbPa
     ));
     paths
 }
+
+#[cfg(test)]
+mod paths_tests {
+    use super::*;
+    use crate::policy::PolicyRules;
+    use crate::pools::{CurvePool, Pool, WeightedPool};
+    use crate::test_utils::{pool, token};
+    use ethers::types::H256;
+    use proptest::prelude::*;
+
+    fn deny_tokens(tokens: Vec<H160>) -> Policy {
+        Policy::new(PolicyRules {
+            deny_tokens: tokens,
+            ..Default::default()
+        })
+    }
+
+    // random pools between up to 6 tokens, token 0 is where paths start
+    fn pool_graph() -> impl Strategy<Value = Vec<Pool>> {
+        prop::collection::vec((0..6u64, 0..6u64), 3..24).prop_map(|pairs| {
+            pairs
+                .into_iter()
+                .filter(|(a, b)| a != b)
+                .enumerate()
+                .map(|(i, (a, b))| pool(i as u64, token(a), token(b)))
+                .collect()
+        })
+    }
+
+    // every start -> a -> b -> start cycle over three distinct pools staying clear of denied
+    fn brute_force_triangles(
+        pools: &[Pool],
+        start: H160,
+        denied: Option<H160>,
+    ) -> Vec<Vec<(usize, bool)>> {
+        let mut triangles = Vec::new();
+        for i in 0..pools.len() {
+            for j in 0..pools.len() {
+                for k in 0..pools.len() {
+                    if i == j || j == k || i == k {
+                        continue;
+                    }
+                    let mut token = start;
+                    let mut hops = Vec::new();
+                    for index in [i, j, k] {
+                        let pool = &pools[index];
+                        if denied
+                            .is_some_and(|denied| pool.token0 == denied || pool.token1 == denied)
+                        {
+                            break;
+                        }
+                        if pool.token0 == token {
+                            hops.push((index, true));
+                            token = pool.token1;
+                        } else if pool.token1 == token {
+                            hops.push((index, false));
+                            token = pool.token0;
+                        } else {
+                            break;
+                        }
+                    }
+                    if hops.len() == 3 && token == start {
+                        triangles.push(hops);
+                    }
+                }
+            }
+        }
+        triangles.sort();
+        triangles
+    }
+
+    fn hops(paths: &[ArbPath]) -> Vec<Vec<(usize, bool)>> {
+        let mut hops: Vec<Vec<(usize, bool)>> = paths
+            .iter()
+            .map(|path| {
+                (0..path.nhop)
+                    .map(|i| (path._get_pool(i).index(), path._get_zero_for_one(i)))
+                    .collect()
+            })
+            .collect();
+        hops.sort();
+        hops
+    }
+
+    #[test]
+    fn generate_triangular_paths_direction_test() {
+        let (usdc, weth, token_3) = (token(0), token(1), token(2));
+        // the last hop sells token_3 which is token1 of its pool
+        let pools = vec![
+            pool(0, usdc, weth),
+            pool(1, weth, token_3),
+            pool(2, usdc, token_3),
+        ];
+
+        let registry = PoolRegistry::new(&pools);
+
+        let paths = generate_triangular_paths(&registry, usdc, &Policy::default());
+        assert_eq!(paths.len(), 2);
+        let forward = paths
+            .iter()
+            .find(|path| path._get_pool(0) == PoolId(0))
+            .unwrap();
+        assert_eq!(forward.zero_for_one[..3], [true, true, false]);
+
+        assert!(generate_triangular_paths(&registry, usdc, &deny_tokens(vec![token_3])).is_empty());
+    }
+
+    #[test]
+    fn curve_path_test() {
+        let (usdc, token_3, dai, usdt) = (token(0), token(1), token(2), token(3));
+        let curve = CurvePool {
+            address: H160::from_low_u64_be(50),
+            coins: vec![dai, usdc, usdt],
+            decimals: vec![18, 6, 6],
+            amp: U256::from(200_000u64),
+            fee: U256::from(1_000_000u64),
+            registry: H160::zero(),
+        };
+        let mut registry = PoolRegistry::new(&vec![pool(0, usdc, token_3), pool(1, token_3, dai)]);
+        registry.intern_curve(&curve);
+
+        let e18 = U256::exp10(18);
+        let mut reserves = vec![
+            Reserve {
+                reserve0: U256::from(1_000_000u64) * U256::exp10(6),
+                reserve1: U256::from(1_000_000u64) * e18,
+            },
+            Reserve {
+                reserve0: U256::from(1_000_000u64) * e18,
+                reserve1: U256::from(1_050_000u64) * e18,
+            },
+        ];
+        reserves.resize(registry.len(), Reserve::default());
+        let balances = vec![
+            U256::from(50_000_000u64) * e18,
+            U256::from(50_000_000u64) * U256::exp10(6),
+            U256::from(50_000_000u64) * U256::exp10(6),
+        ];
+        registry.set_pool_balances(&curve.address, &balances, &mut reserves);
+
+        // both directions through the dai/usdc view, paths using two views of one pool are dropped
+        let paths = generate_triangular_paths(&registry, usdc, &Policy::default());
+        assert_eq!(paths.len(), 2);
+        let forward = paths
+            .iter()
+            .find(|path| path._get_pool(0) == PoolId(0))
+            .unwrap();
+        assert_eq!(forward._get_pool(2), PoolId(2));
+
+        let amount_in = U256::from(1000u64) * U256::exp10(6);
+        let fee = U256::from(300);
+        let dai_in = UniswapV2Simulator::get_amount_out(
+            UniswapV2Simulator::get_amount_out(
+                amount_in,
+                reserves[0].reserve0,
+                reserves[0].reserve1,
+                fee,
+            )
+            .unwrap(),
+            reserves[1].reserve0,
+            reserves[1].reserve1,
+            fee,
+        )
+        .unwrap();
+        let expected = StableSwapSimulator::get_dy(
+            0,
+            1,
+            dai_in,
+            &balances,
+            &curve.decimals,
+            curve.amp,
+            curve.fee,
+        );
+        let amount_out = forward.simulate_v2_path(amount_in, &registry, &reserves);
+        assert_eq!(amount_out, expected);
+        assert!(amount_out.unwrap() > amount_in);
+
+        // the registry has the same address as the V2 factory, but the bot can't swap on Curve
+        let mut routers = RouterRegistry::new();
+        routers.register("uniswap_v2", H160::zero(), H160::from_low_u64_be(70));
+        assert!(!forward.is_executable(&registry, &routers));
+        let v2_only = ArbPath::from_hops(&[(PoolId(0), true), (PoolId(1), true)]).unwrap();
+        assert!(v2_only.is_executable(&registry, &routers));
+        assert!(!v2_only.is_executable(&registry, &RouterRegistry::new()));
+
+        // every view of the pool sees the swap
+        let applied = forward.apply_v2_path(amount_in, &registry, &mut reserves);
+        assert_eq!(applied, amount_out);
+        let after = registry.pool_balances(&curve.address, &reserves).unwrap();
+        assert_eq!(after[0], balances[0] + dai_in);
+        assert_eq!(after[1], balances[1] - amount_out.unwrap());
+        assert_eq!(reserves[4].reserve0, after[1]);
+    }
+
+    #[test]
+    fn weighted_path_test() {
+        let (usdc, token_3, weth, wbtc) = (token(0), token(1), token(2), token(3));
+        let e18 = U256::exp10(18);
+        let weighted = WeightedPool {
+            address: H160::from_low_u64_be(60),
+            pool_id: H256::from_low_u64_be(60),
+            tokens: vec![weth, usdc, wbtc],
+            decimals: vec![18, 6, 8],
+            weights: vec![e18 * 4 / 10, e18 * 4 / 10, e18 * 2 / 10],
+            swap_fee: U256::exp10(15),
+            vault: H160::zero(),
+        };
+        let mut registry = PoolRegistry::new(&vec![pool(0, usdc, token_3), pool(1, token_3, weth)]);
+        registry.intern_weighted(&weighted);
+
+        let mut reserves = vec![
+            Reserve {
+                reserve0: U256::from(1_000_000u64) * U256::exp10(6),
+                reserve1: U256::from(1_000_000u64) * e18,
+            },
+            Reserve {
+                reserve0: U256::from(2_000_000u64) * e18,
+                reserve1: U256::from(1_100u64) * e18,
+            },
+        ];
+        reserves.resize(registry.len(), Reserve::default());
+        let balances = vec![
+            U256::from(10_000u64) * e18,
+            U256::from(20_000_000u64) * U256::exp10(6),
+            U256::from(300u64) * U256::exp10(8),
+        ];
+        registry.set_pool_balances(&weighted.address, &balances, &mut reserves);
+
+        let paths = generate_triangular_paths(&registry, usdc, &Policy::default());
+        let forward = paths
+            .iter()
+            .find(|path| path._get_pool(0) == PoolId(0))
+            .unwrap();
+        assert_eq!(forward._get_pool(2), PoolId(2));
+
+        let amount_in = U256::from(1000u64) * U256::exp10(6);
+        let weth_in = ArbPath::from_hops(&[(PoolId(0), true), (PoolId(1), true)])
+            .unwrap()
+            .simulate_v2_path(amount_in, &registry, &reserves)
+            .unwrap();
+        let expected = WeightedSimulator::get_amount_out(
+            weth_in,
+            (balances[0], balances[1]),
+            (18, 6),
+            (weighted.weights[0], weighted.weights[1]),
+            weighted.swap_fee,
+        );
+        let amount_out = forward.simulate_v2_path(amount_in, &registry, &reserves);
+        assert_eq!(amount_out, expected);
+        assert!(amount_out.unwrap() > amount_in);
+
+        // the bot can't swap through the vault either
+        let mut routers = RouterRegistry::new();
+        routers.register("uniswap_v2", H160::zero(), H160::from_low_u64_be(70));
+        assert!(!forward.is_executable(&registry, &routers));
+
+        // the weth/wbtc view sees the weth that came in
+        let applied = forward.apply_v2_path(amount_in, &registry, &mut reserves);
+        assert_eq!(applied, amount_out);
+        let after = registry
+            .pool_balances(&weighted.address, &reserves)
+            .unwrap();
+        assert_eq!(after[0], balances[0] + weth_in);
+        assert_eq!(after[1], balances[1] - amount_out.unwrap());
+        assert_eq!(reserves[3].reserve0, after[0]);
+    }
+
+    #[test]
+    fn validate_test() {
+        let (usdc, weth, token_3) = (token(0), token(1), token(2));
+        let registry = PoolRegistry::new(&vec![
+            pool(0, usdc, weth),
+            pool(1, weth, token_3),
+            pool(2, usdc, token_3),
+            pool(3, token(3), token_3),
+        ]);
+        let path = ArbPath::from_hops(&[(PoolId(0), true), (PoolId(1), true), (PoolId(2), false)])
+            .unwrap();
+        assert_eq!(path.validate(&registry, &Policy::default()), Ok(()));
+
+        // token_3 comes out of hop 1 and goes back in at hop 2, the first hop touching it is reported
+        assert_eq!(
+            path.validate(&registry, &deny_tokens(vec![token_3])),
+            Err(PathError::Denied {
+                hop: 1,
+                violation: PolicyViolation::TokenDenied(token_3)
+            })
+        );
+
+        let mut wrong_direction = path;
+        wrong_direction.zero_for_one[2] = true;
+        assert_eq!(
+            wrong_direction.validate(&registry, &Policy::default()),
+            Err(PathError::Direction { hop: 2 })
+        );
+
+        // usdc -> weth -> usdc through the same pool is continuous and closed
+        let duplicate = ArbPath::from_hops(&[(PoolId(0), true), (PoolId(0), false)]).unwrap();
+        assert_eq!(
+            duplicate.validate(&registry, &Policy::default()),
+            Err(PathError::DuplicatePool(registry[PoolId(0)].address))
+        );
+
+        let mut broken = path;
+        broken.pools[2] = broken.pools[0];
+        assert_eq!(
+            broken.validate(&registry, &Policy::default()),
+            Err(PathError::Continuity {
+                hop: 2,
+                token: token_3
+            })
+        );
+
+        let mut not_closed = path;
+        not_closed.pools[2] = PoolId(3);
+        assert_eq!(
+            not_closed.validate(&registry, &Policy::default()),
+            Err(PathError::NotClosed {
+                start: usdc,
+                end: token(3)
+            })
+        );
+
+        let mut too_long = path;
+        too_long.nhop = MAX_HOPS as u8 + 1;
+        assert_eq!(
+            too_long.validate(&registry, &Policy::default()),
+            Err(PathError::HopCount(MAX_HOPS as u8 + 1))
+        );
+        assert!(ArbPath::from_hops(&[]).is_err());
+
+        let mut unknown = path;
+        unknown.pools[1] = PoolId(4);
+        assert_eq!(
+            unknown.validate(&registry, &Policy::default()),
+            Err(PathError::UnknownPool(PoolId(4)))
+        );
+    }
+
+    #[test]
+    fn path_index_test() {
+        let (usdc, weth, token_3, token_4) = (token(0), token(1), token(2), token(3));
+        let pools = vec![
+            pool(0, usdc, weth),
+            pool(1, weth, token_3),
+            pool(2, usdc, token_3),
+            pool(3, weth, token_4),
+            pool(4, usdc, token_4),
+        ];
+        let registry = PoolRegistry::new(&pools);
+        let paths = generate_triangular_paths(&registry, usdc, &Policy::default());
+        let index = PathIndex::new(&paths);
+
+        // both directions of both triangles go through usdc/weth
+        assert_eq!(index.paths_for_pool(PoolId(0)).len(), 4);
+        assert_eq!(index.paths_for_pool(PoolId(1)).len(), 2);
+        assert!(index.paths_for_pool(PoolId(5)).is_empty());
+        assert_eq!(index.pool_count(), 5);
+
+        let touched = index.touched_paths(&[PoolId(1), PoolId(2)]);
+        assert_eq!(touched.len(), 2);
+        assert_eq!(
+            index.touched_paths(&[PoolId(0), PoolId(3)]),
+            vec![0, 1, 2, 3]
+        );
+    }
+
+    proptest! {
+        #[test]
+        fn path_index_matches_scan(pools in pool_graph(), touched in prop::collection::vec(0..24u64, 0..6)) {
+            let registry = PoolRegistry::new(&pools);
+            let paths = generate_triangular_paths(&registry, token(0), &Policy::default());
+            let index = PathIndex::new(&paths);
+
+            let touched: Vec<PoolId> = touched.into_iter().map(|i| PoolId(i as u32)).collect();
+            let scanned: Vec<usize> = paths
+                .iter()
+                .enumerate()
+                .filter(|(_, path)| touched.iter().any(|pool| path.has_pool(*pool)))
+                .map(|(path_id, _)| path_id)
+                .collect();
+            prop_assert_eq!(index.touched_paths(&touched), scanned);
+        }
+
+        #[test]
+        fn generated_paths_are_valid(pools in pool_graph(), blacklisted in 1..6u64) {
+            let start = token(0);
+            let policy = deny_tokens(vec![token(blacklisted)]);
+
+            let registry = PoolRegistry::new(&pools);
+
+            // pool i of the graph is PoolId(i), every pool has its own address
+            let paths = generate_triangular_paths(&registry, start, &Policy::default());
+            prop_assert_eq!(hops(&paths), brute_force_triangles(&pools, start, None));
+            for path in &paths {
+                prop_assert_eq!(path.validate(&registry, &Policy::default()), Ok(()));
+                prop_assert_eq!(path.token_in(&registry).0, start);
+            }
+
+            let filtered = generate_triangular_paths(&registry, start, &policy);
+            prop_assert_eq!(
+                hops(&filtered),
+                brute_force_triangles(&pools, start, Some(token(blacklisted)))
+            );
+        }
+
+        #[test]
+        fn flipped_direction_is_invalid(pools in pool_graph(), hop in 0..3u8) {
+            let registry = PoolRegistry::new(&pools);
+            let paths = generate_triangular_paths(&registry, token(0), &Policy::default());
+            for path in paths {
+                let mut flipped = path;
+                flipped.zero_for_one[hop as usize] = !flipped.zero_for_one[hop as usize];
+                prop_assert!(flipped.validate(&registry, &Policy::default()).is_err());
+            }
+        }
+    }
+}
@@ -0,0 +1,359 @@
+use anyhow::Result;
+use ethers::types::{H160, U256};
+use log::info;
+use serde::{Deserialize, Deserializer};
+use std::{
+    collections::{HashMap, HashSet},
+    fs,
+    path::{Path, PathBuf},
+    time::SystemTime,
+};
+use thiserror::Error;
+
+use crate::constants::{Env, BLACKLIST_TOKENS};
+use crate::multi::Reserve;
+use crate::paths::ArbPath;
+use crate::pools::{Pool, PoolRegistry};
+
+/*
+Rules read from the policy file (policy.json by default).
+An empty allow list allows everything, and a deny always wins over an allow.
+min_reserves maps a token to the smallest reserve of it a pool must hold, in base units.
+*/
+#[derive(Default, Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct PolicyRules {
+    pub allow_tokens: Vec<H160>,
+    pub deny_tokens: Vec<H160>,
+    pub allow_pools: Vec<H160>,
+    pub deny_pools: Vec<H160>,
+    pub allow_factories: Vec<H160>,
+    pub deny_factories: Vec<H160>,
+    #[serde(deserialize_with = "deserialize_amounts")]
+    pub min_reserves: HashMap<H160, U256>,
+}
+
+// accepts decimal ("1000000") or hex ("0xf4240") amounts
+fn deserialize_amounts<'de, D>(deserializer: D) -> Result<HashMap<H160, U256>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let raw: HashMap<H160, String> = HashMap::deserialize(deserializer)?;
+    raw.into_iter()
+        .map(|(token, amount)| {
+            let parsed = match amount.strip_prefix("0x") {
+                Some(hex) => U256::from_str_radix(hex, 16).ok(),
+                None => U256::from_dec_str(&amount).ok(),
+            };
+            parsed
+                .map(|amount| (token, amount))
+                .ok_or_else(|| serde::de::Error::custom(format!("invalid amount: {}", amount)))
+        })
+        .collect()
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Error)]
+pub enum PolicyViolation {
+    #[error("token {0:?} is denied")]
+    TokenDenied(H160),
+    #[error("token {0:?} is not on the allow list")]
+    TokenNotAllowed(H160),
+    #[error("token {0:?} failed the fork checks")]
+    TokenFlagged(H160),
+    #[error("pool {0:?} is denied")]
+    PoolDenied(H160),
+    #[error("pool {0:?} is not on the allow list")]
+    PoolNotAllowed(H160),
+    #[error("factory {0:?} is denied")]
+    FactoryDenied(H160),
+    #[error("factory {0:?} is not on the allow list")]
+    FactoryNotAllowed(H160),
+    #[error("pool {pool:?} holds {reserve} of {token:?}, below {min}")]
+    LowLiquidity {
+        pool: H160,
+        token: H160,
+        reserve: U256,
+        min: U256,
+    },
+}
+
+fn check_lists(
+    item: &H160,
+    allow: &[H160],
+    deny: &[H160],
+    denied: fn(H160) -> PolicyViolation,
+    not_allowed: fn(H160) -> PolicyViolation,
+) -> Result<(), PolicyViolation> {
+    if deny.contains(item) {
+        return Err(denied(*item));
+    }
+    if !allow.is_empty() && !allow.contains(item) {
+        return Err(not_allowed(*item));
+    }
+    Ok(())
+}
+
+#[derive(Default, Debug, Clone)]
+pub struct Policy {
+    pub path: Option<PathBuf>,
+    pub rules: PolicyRules,
+    // tokens the fork checks flagged (see tokens.rs), kept across reloads
+    pub flagged_tokens: HashSet<H160>,
+    modified: Option<SystemTime>,
+}
+
+impl Policy {
+    pub fn new(rules: PolicyRules) -> Self {
+        Self {
+            path: None,
+            rules,
+            flagged_tokens: HashSet::new(),
+            modified: None,
+        }
+    }
+
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let path = path.as_ref().to_path_buf();
+        let modified = fs::metadata(&path)?.modified().ok();
+        let rules = serde_json::from_str(&fs::read_to_string(&path)?)?;
+        Ok(Self {
+            path: Some(path),
+            rules,
+            flagged_tokens: HashSet::new(),
+            modified,
+        })
+    }
+
+    /*
+    Falls back to an empty policy if the file can't be read, it is picked up once it's fixed.
+    BLACKLIST_TOKENS are denied either way.
+    */
+    pub fn from_env(env: &Env) -> Self {
+        match Self::load(&env.policy_path) {
+            Ok(policy) => policy,
+            Err(e) => {
+                info!("Error loading policy from {}: {:?}", env.policy_path, e);
+                Self {
+                    path: Some(PathBuf::from(&env.policy_path)),
+                    ..Default::default()
+                }
+            }
+        }
+    }
+
+    /*
+    Reloads the rules if the file changed since the last load.
+    Paths are generated once, so new allow rules can't add paths back, only narrow them.
+    On a parse error the previous rules stay in place.
+    */
+    pub fn reload_if_changed(&mut self) -> Result<bool> {
+        let path = match &self.path {
+            Some(path) => path.clone(),
+            None => return Ok(false),
+        };
+        // a missing file keeps the current rules
+        let metadata = match fs::metadata(&path) {
+            Ok(metadata) => metadata,
+            Err(_) => return Ok(false),
+        };
+        let modified = metadata.modified().ok();
+        if modified.is_some() && modified == self.modified {
+            return Ok(false);
+        }
+        // recorded first so a broken file is reported once, not at every block
+        self.modified = modified;
+        self.rules = serde_json::from_str(&fs::read_to_string(&path)?)?;
+        Ok(true)
+    }
+
+    pub fn set_flagged_tokens(&mut self, tokens: Vec<H160>) {
+        self.flagged_tokens = tokens.into_iter().collect();
+    }
+
+    pub fn check_token(&self, token: &H160) -> Result<(), PolicyViolation> {
+        if BLACKLIST_TOKENS.contains(token) {
+            return Err(PolicyViolation::TokenDenied(*token));
+        }
+        if self.flagged_tokens.contains(token) {
+            return Err(PolicyViolation::TokenFlagged(*token));
+        }
+        check_lists(
+            token,
+            &self.rules.allow_tokens,
+            &self.rules.deny_tokens,
+            PolicyViolation::TokenDenied,
+            PolicyViolation::TokenNotAllowed,
+        )
+    }
+
+    // Rules that don't depend on reserves
+    pub fn check_pool(&self, pool: &Pool) -> Result<(), PolicyViolation> {
+        check_lists(
+            &pool.address,
+            &self.rules.allow_pools,
+            &self.rules.deny_pools,
+            PolicyViolation::PoolDenied,
+            PolicyViolation::PoolNotAllowed,
+        )?;
+        check_lists(
+            &pool.factory,
+            &self.rules.allow_factories,
+            &self.rules.deny_factories,
+            PolicyViolation::FactoryDenied,
+            PolicyViolation::FactoryNotAllowed,
+        )?;
+        self.check_token(&pool.token0)?;
+        self.check_token(&pool.token1)
+    }
+
+    pub fn check_liquidity(&self, pool: &Pool, reserve: &Reserve) -> Result<(), PolicyViolation> {
+        for (token, token_reserve) in [
+            (pool.token0, reserve.reserve0),
+            (pool.token1, reserve.reserve1),
+        ] {
+            if let Some(min) = self.rules.min_reserves.get(&token) {
+                if token_reserve < *min {
+                    return Err(PolicyViolation::LowLiquidity {
+                        pool: pool.address,
+                        token,
+                        reserve: token_reserve,
+                        min: *min,
+                    });
+                }
+            }
+        }
+        Ok(())
+    }
+
+    // reserves is indexed by PoolId, liquidity is only checked for pools it covers
+    pub fn check_path(
+        &self,
+        path: &ArbPath,
+        pools: &PoolRegistry,
+        reserves: &[Reserve],
+    ) -> Result<(), PolicyViolation> {
+        for i in 0..path.nhop {
+            let id = path._get_pool(i);
+            let pool = &pools[id];
+            self.check_pool(pool)?;
+            if let Some(reserve) = reserves.get(id.index()) {
+                self.check_liquidity(pool, reserve)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod policy_tests {
+    use super::*;
+    use crate::test_utils;
+
+    // addresses as they are written in RULES
+    fn pool(address: u64, token0: u64, token1: u64, factory: u64) -> Pool {
+        Pool {
+            address: H160::from_low_u64_be(address),
+            factory: H160::from_low_u64_be(factory),
+            ..test_utils::pool(
+                0,
+                H160::from_low_u64_be(token0),
+                H160::from_low_u64_be(token1),
+            )
+        }
+    }
+
+    static RULES: &str = r#"{
+        "deny_tokens": ["0x0000000000000000000000000000000000000003"],
+        "deny_pools": ["0x0000000000000000000000000000000000000102"],
+        "allow_factories": ["0x0000000000000000000000000000000000000050"],
+        "min_reserves": {"0x0000000000000000000000000000000000000001": "1000"}
+    }"#;
+
+    #[test]
+    fn policy_rules_test() {
+        let policy = Policy::new(serde_json::from_str(RULES).unwrap());
+
+        assert_eq!(policy.check_pool(&pool(100, 1, 2, 80)), Ok(()));
+        assert_eq!(
+            policy.check_pool(&pool(101, 2, 3, 80)),
+            Err(PolicyViolation::TokenDenied(H160::from_low_u64_be(3)))
+        );
+        assert_eq!(
+            policy.check_pool(&pool(0x102, 1, 2, 80)),
+            Err(PolicyViolation::PoolDenied(H160::from_low_u64_be(0x102)))
+        );
+        assert_eq!(
+            policy.check_pool(&pool(103, 1, 2, 81)),
+            Err(PolicyViolation::FactoryNotAllowed(H160::from_low_u64_be(
+                81
+            )))
+        );
+
+        let shallow = Reserve {
+            reserve0: U256::from(999),
+            reserve1: U256::from(1),
+        };
+        assert!(matches!(
+            policy.check_liquidity(&pool(100, 1, 2, 80), &shallow),
+            Err(PolicyViolation::LowLiquidity { .. })
+        ));
+        // no minimum for token 2 on either side
+        assert_eq!(
+            policy.check_liquidity(&pool(104, 2, 4, 80), &shallow),
+            Ok(())
+        );
+
+        // denied without any rules
+        let blacklisted = BLACKLIST_TOKENS[0];
+        let mut listed = pool(105, 1, 2, 80);
+        listed.token1 = blacklisted;
+        assert_eq!(
+            Policy::default().check_pool(&listed),
+            Err(PolicyViolation::TokenDenied(blacklisted))
+        );
+
+        let mut policy = policy;
+        policy.set_flagged_tokens(vec![H160::from_low_u64_be(4)]);
+        assert_eq!(
+            policy.check_pool(&pool(104, 2, 4, 80)),
+            Err(PolicyViolation::TokenFlagged(H160::from_low_u64_be(4)))
+        );
+    }
+
+    #[test]
+    fn policy_reload_test() {
+        let path = std::env::temp_dir().join(format!("policy-{}.json", std::process::id()));
+        fs::write(&path, "{}").unwrap();
+
+        let mut policy = Policy::load(&path).unwrap();
+        assert!(policy.rules.deny_tokens.is_empty());
+        assert!(!policy.reload_if_changed().unwrap());
+
+        // make sure the modified time moves even on coarse filesystems
+        std::thread::sleep(std::time::Duration::from_millis(20));
+        fs::write(&path, RULES).unwrap();
+        let file = fs::File::options().write(true).open(&path).unwrap();
+        file.set_modified(SystemTime::now() + std::time::Duration::from_secs(1))
+            .unwrap();
+
+        assert!(policy.reload_if_changed().unwrap());
+        assert_eq!(policy.rules.deny_tokens.len(), 1);
+        assert_eq!(
+            policy.rules.min_reserves[&H160::from_low_u64_be(1)],
+            U256::from(1000)
+        );
+
+        // a broken file keeps the previous rules
+        fs::write(&path, "{").unwrap();
+        let file = fs::File::options().write(true).open(&path).unwrap();
+        file.set_modified(SystemTime::now() + std::time::Duration::from_secs(2))
+            .unwrap();
+        assert!(policy.reload_if_changed().is_err());
+        assert_eq!(policy.rules.deny_tokens.len(), 1);
+        // and is only reported until it changes again
+        assert!(!policy.reload_if_changed().unwrap());
+        assert_eq!(policy.rules.deny_tokens.len(), 1);
+
+        fs::remove_file(&path).unwrap();
+    }
+}
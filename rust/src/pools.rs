@@ -7,15 +7,19 @@ use cfmms::{
 use csv::StringRecord;
 use ethers::{
     providers::{Provider, Ws},
-    types::H160,
+    types::{H160, H256, U256},
 };
 use log::info;
-use std::{path::Path, str::FromStr, sync::Arc};
+use std::{collections::HashMap, ops::Index, path::Path, str::FromStr, sync::Arc};
+
+use crate::multi::Reserve;
 
 #[derive(Debug, Clone)]
 pub enum DexVariant {
     UniswapV2,
     UniswapV3,
+    CurveStableSwap,
+    BalancerWeighted,
 }
 
 #[derive(Debug, Clone)]
@@ -27,6 +31,330 @@ pub struct Pool {
     pub decimals0: u8,
     pub decimals1: u8,
     pub fee: u32,
+    pub factory: H160,
+    pub dex: String,
+}
+
+// name, factory, router on Ethereum
+pub static KNOWN_DEXES: &[(&str, &str, &str)] = &[
+    (
+        "uniswap_v2",
+        "0x5C69bEe701ef814a2B6a3EDD4B1652CB9cc5aA6f",
+        "0x7a250d5630B4cF539739dF2C5dAcb4c659F2488D",
+    ),
+    (
+        "sushiswap",
+        "0xC0AEe478e3658e2610c5F7A4A2E1777cE9e4f2Ac",
+        "0xd9e1cE17f2641f24aE83637ab66a2cca9C378B9F",
+    ),
+    (
+        "shibaswap",
+        "0x115934131916C8b277DD010Ee02de363c09d037c",
+        "0x03f7724180AA6b939894B5Ca4314783B0b36b329",
+    ),
+];
+
+/*
+A Curve StableSwap pool with n coins. PoolRegistry::intern_curve adds it as one pair view
+per pair of coins, and its balances are kept in those views' reserves.
+*/
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CurvePool {
+    pub address: H160,
+    pub coins: Vec<H160>,
+    pub decimals: Vec<u8>,
+    // A * A_PRECISION and the fee over CURVE_FEE_DENOMINATOR, see StableSwapSimulator
+    pub amp: U256,
+    pub fee: U256,
+    // the Curve registry it was found in
+    pub registry: H160,
+}
+
+/*
+A Balancer weighted pool, traded through the vault with its poolId. Like a Curve pool it's
+added as pair views by PoolRegistry::intern_weighted, and its balances live in their reserves.
+*/
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct WeightedPool {
+    pub address: H160,
+    pub pool_id: H256,
+    pub tokens: Vec<H160>,
+    pub decimals: Vec<u8>,
+    // normalized weights and the swap fee, both 18 decimal fixed point, see WeightedSimulator
+    pub weights: Vec<U256>,
+    pub swap_fee: U256,
+    pub vault: H160,
+}
+
+#[derive(Debug, Clone)]
+pub struct DexInfo {
+    pub name: String,
+    pub factory: H160,
+    pub router: H160,
+}
+
+// Maps each factory to the router that swaps through its pairs
+#[derive(Debug, Clone, Default)]
+pub struct RouterRegistry {
+    pub dexes: HashMap<H160, DexInfo>,
+}
+
+impl RouterRegistry {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_known_dexes() -> Self {
+        let mut registry = Self::new();
+        for (name, factory, router) in KNOWN_DEXES {
+            registry.register(
+                name,
+                H160::from_str(factory).unwrap(),
+                H160::from_str(router).unwrap(),
+            );
+        }
+        registry
+    }
+
+    pub fn register(&mut self, name: &str, factory: H160, router: H160) {
+        self.dexes.insert(
+            factory,
+            DexInfo {
+                name: name.to_string(),
+                factory,
+                router,
+            },
+        );
+    }
+
+    pub fn router(&self, factory: &H160) -> Option<H160> {
+        self.dexes.get(factory).map(|dex| dex.router)
+    }
+
+    pub fn dex_name(&self, factory: &H160) -> String {
+        match self.dexes.get(factory) {
+            Some(dex) => dex.name.clone(),
+            None => "unknown".to_string(),
+        }
+    }
+}
+
+// A pool's position in PoolRegistry.pools, and in any reserve Vec built from the registry
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct PoolId(pub u32);
+
+impl PoolId {
+    pub fn index(&self) -> usize {
+        self.0 as usize
+    }
+}
+
+/*
+Holds every pool once so paths can refer to them by PoolId instead of cloning them.
+Reserves are kept in a Vec<Reserve> indexed by PoolId, see dense_reserves.
+*/
+#[derive(Debug, Clone, Default)]
+pub struct PoolRegistry {
+    pub pools: Vec<Pool>,
+    // a Curve or Balancer pool's address maps to its first pair view
+    pub ids: HashMap<H160, PoolId>,
+    pub curve_pools: HashMap<H160, CurvePool>,
+    pub weighted_pools: HashMap<H160, WeightedPool>,
+    pub pair_views: HashMap<H160, Vec<PoolId>>,
+}
+
+impl PoolRegistry {
+    pub fn new(pools: &Vec<Pool>) -> Self {
+        let mut registry = Self::default();
+        for pool in pools {
+            registry.intern(pool);
+        }
+        registry
+    }
+
+    // A pool that was already interned keeps its id
+    pub fn intern(&mut self, pool: &Pool) -> PoolId {
+        if let Some(views) = self.pair_views.get(&pool.address) {
+            let view = views.iter().find(|id| {
+                let view = &self.pools[id.index()];
+                view.token0 == pool.token0 && view.token1 == pool.token1
+            });
+            if let Some(id) = view {
+                return *id;
+            }
+        } else if let Some(id) = self.ids.get(&pool.address) {
+            return *id;
+        }
+        let id = PoolId(self.pools.len() as u32);
+        self.pools.push(pool.clone());
+        self.ids.entry(pool.address).or_insert(id);
+        id
+    }
+
+    /*
+    Adds a pair view for every pair of tokens, token i as token0 and token j > i as token1.
+    A pool that already has views keeps them and their ids, only their fee is updated.
+    */
+    fn intern_views(&mut self, template: Pool, tokens: &[H160], decimals: &[u8]) -> Vec<PoolId> {
+        if let Some(views) = self.pair_views.get(&template.address) {
+            for id in views {
+                self.pools[id.index()].fee = template.fee;
+            }
+            return views.clone();
+        }
+
+        let mut views = Vec::new();
+        for i in 0..tokens.len() {
+            for j in (i + 1)..tokens.len() {
+                let view = Pool {
+                    token0: tokens[i],
+                    token1: tokens[j],
+                    decimals0: decimals[i],
+                    decimals1: decimals[j],
+                    ..template.clone()
+                };
+                let id = PoolId(self.pools.len() as u32);
+                self.pools.push(view);
+                self.ids.entry(template.address).or_insert(id);
+                views.push(id);
+            }
+        }
+        self.pair_views.insert(template.address, views.clone());
+        views
+    }
+
+    // Interning a pool again only updates its parameters
+    pub fn intern_curve(&mut self, curve: &CurvePool) -> Vec<PoolId> {
+        self.curve_pools.insert(curve.address, curve.clone());
+        let template = Pool {
+            address: curve.address,
+            version: DexVariant::CurveStableSwap,
+            token0: H160::zero(),
+            token1: H160::zero(),
+            decimals0: 0,
+            decimals1: 0,
+            // in the V2 fee units, the simulator uses CurvePool.fee
+            fee: (curve.fee / U256::from(100_000u64)).low_u32(),
+            factory: curve.registry,
+            dex: "curve".to_string(),
+        };
+        self.intern_views(template, &curve.coins, &curve.decimals)
+    }
+
+    pub fn intern_weighted(&mut self, weighted: &WeightedPool) -> Vec<PoolId> {
+        self.weighted_pools
+            .insert(weighted.address, weighted.clone());
+        let template = Pool {
+            address: weighted.address,
+            version: DexVariant::BalancerWeighted,
+            token0: H160::zero(),
+            token1: H160::zero(),
+            decimals0: 0,
+            decimals1: 0,
+            // in the V2 fee units, the simulator uses WeightedPool.swap_fee
+            fee: (weighted.swap_fee / U256::exp10(13)).low_u32(),
+            factory: weighted.vault,
+            dex: "balancer".to_string(),
+        };
+        self.intern_views(template, &weighted.tokens, &weighted.decimals)
+    }
+
+    // Tokens of a pool with pair views, in the pool's own order
+    fn view_tokens(&self, address: &H160) -> Option<&[H160]> {
+        match self.curve_pools.get(address) {
+            Some(curve) => Some(&curve.coins),
+            None => Some(&self.weighted_pools.get(address)?.tokens),
+        }
+    }
+
+    // Indices of a pair view's token0 and token1 in its pool's tokens
+    pub fn view_indices(&self, id: PoolId) -> Option<(usize, usize)> {
+        let view = self.get(id)?;
+        let tokens = self.view_tokens(&view.address)?;
+        let i = tokens.iter().position(|token| *token == view.token0)?;
+        let j = tokens.iter().position(|token| *token == view.token1)?;
+        Some((i, j))
+    }
+
+    // The Curve pool behind a pair view, with the coin indices of its token0 and token1
+    pub fn curve_pool(&self, id: PoolId) -> Option<(&CurvePool, usize, usize)> {
+        let curve = self.curve_pools.get(&self.get(id)?.address)?;
+        let (i, j) = self.view_indices(id)?;
+        Some((curve, i, j))
+    }
+
+    // The Balancer pool behind a pair view, with the token indices of its token0 and token1
+    pub fn weighted_pool(&self, id: PoolId) -> Option<(&WeightedPool, usize, usize)> {
+        let weighted = self.weighted_pools.get(&self.get(id)?.address)?;
+        let (i, j) = self.view_indices(id)?;
+        Some((weighted, i, j))
+    }
+
+    // Token balances of a pool with pair views, read back from the views' reserves
+    pub fn pool_balances(&self, address: &H160, reserves: &[Reserve]) -> Option<Vec<U256>> {
+        let mut balances = vec![U256::zero(); self.view_tokens(address)?.len()];
+        for id in self.pair_views.get(address)? {
+            let (i, j) = self.view_indices(*id)?;
+            let reserve = reserves.get(id.index())?;
+            balances[i] = reserve.reserve0;
+            balances[j] = reserve.reserve1;
+        }
+        Some(balances)
+    }
+
+    pub fn set_pool_balances(&self, address: &H160, balances: &[U256], reserves: &mut [Reserve]) {
+        let views = match self.pair_views.get(address) {
+            Some(views) => views,
+            None => return,
+        };
+        for id in views {
+            if let (Some((i, j)), Some(reserve)) =
+                (self.view_indices(*id), reserves.get_mut(id.index()))
+            {
+                reserve.reserve0 = balances[i];
+                reserve.reserve1 = balances[j];
+            }
+        }
+    }
+
+    pub fn id(&self, address: &H160) -> Option<PoolId> {
+        self.ids.get(address).copied()
+    }
+
+    pub fn get(&self, id: PoolId) -> Option<&Pool> {
+        self.pools.get(id.index())
+    }
+
+    pub fn len(&self) -> usize {
+        self.pools.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.pools.is_empty()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (PoolId, &Pool)> {
+        self.pools
+            .iter()
+            .enumerate()
+            .map(|(i, pool)| (PoolId(i as u32), pool))
+    }
+
+    // Reserves in PoolId order, pools missing from the map get zero reserves
+    pub fn dense_reserves(&self, reserves: &HashMap<H160, Reserve>) -> Vec<Reserve> {
+        self.pools
+            .iter()
+            .map(|pool| reserves.get(&pool.address).cloned().unwrap_or_default())
+            .collect()
+    }
+}
+
+impl Index<PoolId> for PoolRegistry {
+    type Output = Pool;
+
+    fn index(&self, id: PoolId) -> &Pool {
+        &self.pools[id.index()]
+    }
 }
 
 impl From<StringRecord> for Pool {
@@ -44,23 +372,35 @@ impl From<StringRecord> for Pool {
             decimals0: record.get(4).unwrap().parse().unwrap(),
             decimals1: record.get(5).unwrap().parse().unwrap(),
             fee: record.get(6).unwrap().parse().unwrap(),
+            // caches written before factories were recorded don't have these columns,
+            // load_all_pools_from_v2 syncs those again
+            factory: record
+                .get(7)
+                .and_then(|factory| H160::from_str(factory).ok())
+                .unwrap_or_default(),
+            dex: record.get(8).unwrap_or("unknown").to_string(),
         }
     }
 }
 
 impl Pool {
-    pub fn cache_row(&self) -> (String, i32, String, String, u8, u8, u32) {
+    pub fn cache_row(&self) -> (String, i32, String, String, u8, u8, u32, String, String) {
         (
             format!("{:?}", self.address),
             match self.version {
                 DexVariant::UniswapV2 => 2,
                 DexVariant::UniswapV3 => 3,
+                // never cached, Curve and Balancer pools are found again on every start
+                DexVariant::CurveStableSwap => 0,
+                DexVariant::BalancerWeighted => 0,
             },
             format!("{:?}", self.token0),
             format!("{:?}", self.token1),
             self.decimals0,
             self.decimals1,
             self.fee,
+            format!("{:?}", self.factory),
+            self.dex.clone(),
         )
     }
 }
@@ -81,38 +421,32 @@ pub async fn load_all_pools_from_v2(
             let pool = Pool::from(row);
             pools_vec.push(pool);
         }
-        return Ok(pools_vec);
+        // without a factory a pool has no router, so an old cache is synced again and rewritten
+        if pools_vec.iter().all(|pool| !pool.factory.is_zero()) {
+            return Ok(pools_vec);
+        }
+        info!("Cached pools have no factory, syncing them again");
     }
 
     let ws = Ws::connect(wss_url).await?;
     let provider = Arc::new(Provider::new(ws));
 
-    let mut dexes_data = Vec::new();
+    let registry = RouterRegistry::with_known_dexes();
+    let mut pools_vec: Vec<Pool> = Vec::new();
 
+    // synced one factory at a time so every pool knows which DEX it came from
     for i in 0..factory_addresses.len() {
-        dexes_data.push((
-            factory_addresses[i].clone(),
+        let factory = H160::from_str(factory_addresses[i]).unwrap();
+        let dex_name = registry.dex_name(&factory);
+        let dex = Dex::new(
+            factory,
             CfmmsDexVariant::UniswapV2,
             from_blocks[i],
-        ))
-    }
-
-    let dexes: Vec<_> = dexes_data
-        .into_iter()
-        .map(|(address, variant, number)| {
-            Dex::new(
-                H160::from_str(&address).unwrap(),
-                variant,
-                number,
-                Some(3000),
-            )
-        })
-        .collect();
-
-    let pools_vec: Vec<CfmmsPool> = sync_pairs(dexes.clone(), provider.clone(), None).await?;
-    let pools_vec: Vec<Pool> = pools_vec
-        .into_iter()
-        .map(|pool| match pool {
+            Some(3000),
+        );
+
+        let synced: Vec<CfmmsPool> = sync_pairs(vec![dex], provider.clone(), None).await?;
+        pools_vec.extend(synced.into_iter().map(|pool| match pool {
             CfmmsPool::UniswapV2(pool) => Pool {
                 address: pool.address,
                 version: DexVariant::UniswapV2,
@@ -121,6 +455,8 @@ pub async fn load_all_pools_from_v2(
                 decimals0: pool.token_a_decimals,
                 decimals1: pool.token_b_decimals,
                 fee: pool.fee,
+                factory,
+                dex: dex_name.clone(),
             },
             CfmmsPool::UniswapV3(pool) => Pool {
                 address: pool.address,
@@ -130,9 +466,11 @@ pub async fn load_all_pools_from_v2(
                 decimals0: pool.token_a_decimals,
                 decimals1: pool.token_b_decimals,
                 fee: pool.fee,
+                factory,
+                dex: dex_name.clone(),
             },
-        })
-        .collect();
+        }));
+    }
     info!("Synced to {} pools", pools_vec.len());
 
     let mut writer = csv::Writer::from_path(file_path)?;
@@ -144,6 +482,8 @@ pub async fn load_all_pools_from_v2(
         "decimals0",
         "decimals1",
         "fee",
+        "factory",
+        "dex",
     ])?;
 
     for pool in &pools_vec {
@@ -153,3 +493,159 @@ pub async fn load_all_pools_from_v2(
 
     Ok(pools_vec)
 }
+
+#[cfg(test)]
+mod pools_tests {
+    use super::*;
+
+    #[test]
+    fn pool_cache_row_test() {
+        let registry = RouterRegistry::with_known_dexes();
+        let factory = H160::from_str("0xC0AEe478e3658e2610c5F7A4A2E1777cE9e4f2Ac").unwrap();
+        let pool = Pool {
+            address: H160::from_low_u64_be(1),
+            version: DexVariant::UniswapV2,
+            token0: H160::from_low_u64_be(2),
+            token1: H160::from_low_u64_be(3),
+            decimals0: 6,
+            decimals1: 18,
+            fee: 300,
+            factory,
+            dex: registry.dex_name(&factory),
+        };
+        assert_eq!(pool.dex, "sushiswap");
+
+        let row = pool.cache_row();
+        let record = StringRecord::from(vec![
+            row.0,
+            row.1.to_string(),
+            row.2,
+            row.3,
+            row.4.to_string(),
+            row.5.to_string(),
+            row.6.to_string(),
+            row.7,
+            row.8,
+        ]);
+        let cached = Pool::from(record);
+        assert_eq!(cached.factory, factory);
+        assert_eq!(
+            registry.router(&cached.factory),
+            Some(H160::from_str("0xd9e1cE17f2641f24aE83637ab66a2cca9C378B9F").unwrap())
+        );
+
+        // a cache from before factories were recorded
+        let old = StringRecord::from(vec![
+            "0x0000000000000000000000000000000000000001",
+            "2",
+            "0x0000000000000000000000000000000000000002",
+            "0x0000000000000000000000000000000000000003",
+            "6",
+            "18",
+            "300",
+        ]);
+        let cached = Pool::from(old);
+        assert_eq!(cached.factory, H160::zero());
+        assert_eq!(registry.router(&cached.factory), None);
+    }
+
+    #[test]
+    fn pool_registry_test() {
+        let pool = |address: u64| Pool {
+            address: H160::from_low_u64_be(address),
+            version: DexVariant::UniswapV2,
+            token0: H160::from_low_u64_be(2),
+            token1: H160::from_low_u64_be(3),
+            decimals0: 6,
+            decimals1: 18,
+            fee: 300,
+            factory: H160::zero(),
+            dex: "uniswap_v2".to_string(),
+        };
+        let mut registry = PoolRegistry::new(&vec![pool(10), pool(11)]);
+        assert_eq!(registry.intern(&pool(11)), PoolId(1));
+        assert_eq!(registry.intern(&pool(12)), PoolId(2));
+        assert_eq!(registry.len(), 3);
+        assert_eq!(registry[PoolId(2)].address, H160::from_low_u64_be(12));
+        assert_eq!(registry.id(&H160::from_low_u64_be(13)), None);
+
+        let mut reserves = HashMap::new();
+        reserves.insert(
+            H160::from_low_u64_be(11),
+            Reserve {
+                reserve0: 5u64.into(),
+                reserve1: 7u64.into(),
+            },
+        );
+        let dense = registry.dense_reserves(&reserves);
+        assert_eq!(dense.len(), 3);
+        assert!(dense[0].reserve0.is_zero());
+        assert_eq!(dense[1].reserve1, 7u64.into());
+    }
+
+    #[test]
+    fn pair_views_test() {
+        let coin = |i: u64| H160::from_low_u64_be(100 + i);
+        let curve = CurvePool {
+            address: H160::from_low_u64_be(50),
+            coins: vec![coin(0), coin(1), coin(2)],
+            decimals: vec![18, 6, 6],
+            amp: U256::from(200_000u64),
+            fee: U256::from(1_000_000u64),
+            registry: H160::zero(),
+        };
+        let mut registry = PoolRegistry::new(&vec![]);
+        let views = registry.intern_curve(&curve);
+        assert_eq!(views, vec![PoolId(0), PoolId(1), PoolId(2)]);
+        assert_eq!(registry.id(&curve.address), Some(PoolId(0)));
+        assert_eq!(registry[PoolId(2)].token0, coin(1));
+        assert_eq!(registry[PoolId(2)].token1, coin(2));
+        assert_eq!(registry[PoolId(2)].fee, 10);
+        assert_eq!(registry.intern(&registry[PoolId(1)].clone()), PoolId(1));
+
+        let (_, i, j) = registry.curve_pool(PoolId(1)).unwrap();
+        assert_eq!((i, j), (0, 2));
+
+        let mut reserves = vec![Reserve::default(); 3];
+        let balances = vec![U256::from(7u64), U256::from(8u64), U256::from(9u64)];
+        registry.set_pool_balances(&curve.address, &balances, &mut reserves);
+        assert_eq!(reserves[1].reserve1, U256::from(9u64));
+        assert_eq!(
+            registry.pool_balances(&curve.address, &reserves),
+            Some(balances)
+        );
+
+        // interning again only updates the parameters
+        let ramped = CurvePool {
+            amp: U256::from(300_000u64),
+            fee: U256::from(4_000_000u64),
+            ..curve.clone()
+        };
+        assert_eq!(registry.intern_curve(&ramped), views);
+        assert_eq!(registry.len(), 3);
+        assert_eq!(registry.curve_pools[&curve.address].amp, ramped.amp);
+        assert!(views.iter().all(|id| registry[*id].fee == 40));
+
+        let weighted = WeightedPool {
+            address: H160::from_low_u64_be(60),
+            pool_id: H256::from_low_u64_be(60),
+            tokens: vec![coin(0), coin(3)],
+            decimals: vec![18, 8],
+            weights: vec![U256::exp10(17) * 8, U256::exp10(17) * 2],
+            swap_fee: U256::exp10(15) * 3,
+            vault: H160::zero(),
+        };
+        assert_eq!(registry.intern_weighted(&weighted), vec![PoolId(3)]);
+        assert_eq!(registry[PoolId(3)].fee, 300);
+        let cheaper = WeightedPool {
+            swap_fee: U256::exp10(15),
+            ..weighted.clone()
+        };
+        assert_eq!(registry.intern_weighted(&cheaper), vec![PoolId(3)]);
+        assert_eq!(registry[PoolId(3)].fee, 100);
+        assert_eq!(registry[PoolId(3)].decimals1, 8);
+        assert!(registry.curve_pool(PoolId(3)).is_none());
+        let (pool, i, j) = registry.weighted_pool(PoolId(3)).unwrap();
+        assert_eq!((pool.pool_id, i, j), (weighted.pool_id, 0, 1));
+    }
+}
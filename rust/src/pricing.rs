@@ -0,0 +1,335 @@
+use ethers::{
+    prelude::Lazy,
+    types::{H160, U256, U512},
+};
+use std::collections::HashMap;
+
+use crate::multi::Reserve;
+use crate::pools::{DexVariant, Pool};
+
+// Prices are native base units per token base unit, scaled by 1e18
+pub static PRICE_SCALE: Lazy<U256> = Lazy::new(|| U256::from(10).pow(U256::from(18)));
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PriceHop {
+    pub pool: H160,
+    // whether the side closer to the native token is token0
+    pub near_is_token0: bool,
+}
+
+#[derive(Debug, Clone)]
+pub struct PriceOracle {
+    pub native: H160,
+    pub max_hops: usize,
+    pub routes: HashMap<H160, Vec<PriceHop>>,
+    pub prices: HashMap<H160, U256>,
+    // native value of each route's shallowest pool when the route was picked
+    pub depths: HashMap<H160, U256>,
+}
+
+fn hop_price(price_near: U256, reserve_near: U256, reserve_far: U256) -> Option<U256> {
+    mul_div(price_near, reserve_near, reserve_far)
+}
+
+fn sides(pool: &Pool, reserve: &Reserve, near_is_token0: bool) -> (H160, H160, U256, U256) {
+    if near_is_token0 {
+        (pool.token0, pool.token1, reserve.reserve0, reserve.reserve1)
+    } else {
+        (pool.token1, pool.token0, reserve.reserve1, reserve.reserve0)
+    }
+}
+
+impl PriceOracle {
+    pub fn new(
+        native: H160,
+        pools: &Vec<Pool>,
+        reserves: &HashMap<H160, Reserve>,
+        max_hops: usize,
+    ) -> Self {
+        let mut oracle = Self {
+            native,
+            max_hops,
+            routes: HashMap::new(),
+            prices: HashMap::new(),
+            depths: HashMap::new(),
+        };
+        oracle.build_routes(pools, reserves);
+        oracle
+    }
+
+    /*
+    Picks the route to the native token for every reachable token.
+    A route is as deep as its shallowest pool (measured in native value),
+    and we keep the deepest route with at most max_hops pools.
+    A route picked through a token whose own route changed later is re-derived from the new one,
+    so a token is always priced along its near token's current route.
+    */
+    pub fn build_routes(&mut self, pools: &Vec<Pool>, reserves: &HashMap<H160, Reserve>) {
+        let mut depths: HashMap<H160, U256> = HashMap::new();
+        let mut routes: HashMap<H160, Vec<PriceHop>> = HashMap::new();
+        let mut prices: HashMap<H160, U256> = HashMap::new();
+
+        depths.insert(self.native, U256::MAX);
+        routes.insert(self.native, Vec::new());
+        prices.insert(self.native, *PRICE_SCALE);
+
+        // re-derived routes ripple further down, bounded in case depths keep trading places
+        for _ in 0..self.max_hops * pools.len().max(1) {
+            let mut updated = false;
+
+            for pool in pools {
+                if !matches!(pool.version, DexVariant::UniswapV2) {
+                    continue;
+                }
+                let reserve = match reserves.get(&pool.address) {
+                    Some(reserve) => reserve,
+                    None => continue,
+                };
+
+                for near_is_token0 in [true, false] {
+                    let (near, far, reserve_near, reserve_far) =
+                        sides(pool, reserve, near_is_token0);
+                    if far == self.native {
+                        continue;
+                    }
+                    let price_near = match prices.get(&near) {
+                        Some(price) => *price,
+                        None => continue,
+                    };
+                    let route_near = &routes[&near];
+                    if route_near.len() >= self.max_hops
+                        || route_near.iter().any(|hop| hop.pool == pool.address)
+                    {
+                        continue;
+                    }
+
+                    let hop = PriceHop {
+                        pool: pool.address,
+                        near_is_token0,
+                    };
+                    let stale = match routes.get(&far) {
+                        Some(route_far) => {
+                            route_far.last() == Some(&hop)
+                                && route_far[..route_far.len() - 1] != route_near[..]
+                        }
+                        None => false,
+                    };
+
+                    let liquidity =
+                        mul_div(reserve_near, price_near, *PRICE_SCALE).unwrap_or(U256::MAX);
+                    let depth = std::cmp::min(depths[&near], liquidity);
+                    if !stale && depth <= *depths.get(&far).unwrap_or(&U256::zero()) {
+                        continue;
+                    }
+
+                    if let Some(price_far) = hop_price(price_near, reserve_near, reserve_far) {
+                        let mut route = route_near.clone();
+                        route.push(hop);
+                        depths.insert(far, depth);
+                        routes.insert(far, route);
+                        prices.insert(far, price_far);
+                        updated = true;
+                    }
+                }
+            }
+
+            if !updated {
+                break;
+            }
+        }
+
+        self.routes = routes;
+        self.prices = prices;
+        self.depths = depths;
+    }
+
+    /*
+    Re-prices every token along its route with the latest reserves.
+    Once a route pool is drained to less than half the depth the route was picked with,
+    some other route is likely deeper by now, so the routes are rebuilt instead.
+    */
+    pub fn refresh(&mut self, pools: &Vec<Pool>, reserves: &HashMap<H160, Reserve>) {
+        let mut prices = HashMap::new();
+        for (token, route) in &self.routes {
+            let mut price = *PRICE_SCALE;
+            let mut depth = U256::MAX;
+            let mut priced = true;
+            for hop in route {
+                let reserve = match reserves.get(&hop.pool) {
+                    Some(reserve) => reserve,
+                    None => {
+                        priced = false;
+                        break;
+                    }
+                };
+                let (reserve_near, reserve_far) = if hop.near_is_token0 {
+                    (reserve.reserve0, reserve.reserve1)
+                } else {
+                    (reserve.reserve1, reserve.reserve0)
+                };
+                let liquidity = mul_div(reserve_near, price, *PRICE_SCALE).unwrap_or(U256::MAX);
+                depth = std::cmp::min(depth, liquidity);
+                match hop_price(price, reserve_near, reserve_far) {
+                    Some(next_price) => price = next_price,
+                    None => {
+                        priced = false;
+                        break;
+                    }
+                }
+            }
+            let picked_depth = self.depths.get(token).copied().unwrap_or_default();
+            if !priced || depth < picked_depth / 2 {
+                self.build_routes(pools, reserves);
+                return;
+            }
+            prices.insert(*token, price);
+        }
+        self.prices = prices;
+    }
+
+    pub fn price_in_native(&self, token: &H160) -> Option<U256> {
+        self.prices.get(token).copied()
+    }
+
+    // Quote base units per token base unit, scaled by 1e18
+    pub fn price_in(&self, token: &H160, quote: &H160) -> Option<U256> {
+        let price = self.price_in_native(token)?;
+        let quote_price = self.price_in_native(quote)?;
+        mul_div(price, *PRICE_SCALE, quote_price)
+    }
+
+    pub fn to_native(&self, token: &H160, amount: U256) -> Option<U256> {
+        mul_div(amount, self.price_in_native(token)?, *PRICE_SCALE)
+    }
+
+    pub fn from_native(&self, token: &H160, native_amount: U256) -> Option<U256> {
+        mul_div(native_amount, *PRICE_SCALE, self.price_in_native(token)?)
+    }
+
+    pub fn convert(&self, from: &H160, to: &H160, amount: U256) -> Option<U256> {
+        let native_amount = self.to_native(from, amount)?;
+        self.from_native(to, native_amount)
+    }
+}
+
+pub fn mul_div(a: U256, b: U256, denominator: U256) -> Option<U256> {
+    if denominator.is_zero() {
+        return None;
+    }
+    let result = a.full_mul(b) / U512::from(denominator);
+    U256::try_from(result).ok()
+}
+
+#[cfg(test)]
+mod pricing_tests {
+    use super::*;
+    use crate::test_utils::{pool, token};
+
+    fn reserve(reserve0: u128, reserve1: u128) -> Reserve {
+        Reserve {
+            reserve0: U256::from(reserve0),
+            reserve1: U256::from(reserve1),
+        }
+    }
+
+    #[test]
+    fn price_oracle_test() {
+        let weth = token(0);
+        let usdc = token(1);
+        let token = token(2);
+
+        // USDC (6 decimals) / WETH (18 decimals) at 2000 USDC per WETH
+        let deep = pool(100, usdc, weth);
+        let shallow = pool(101, usdc, weth);
+        let token_usdc = pool(102, token, usdc);
+        let pools = vec![shallow.clone(), deep.clone(), token_usdc.clone()];
+
+        let mut reserves = HashMap::new();
+        reserves.insert(
+            deep.address,
+            reserve(20_000_000 * 1_000_000, 10_000 * 10u128.pow(18)),
+        );
+        reserves.insert(shallow.address, reserve(3_000 * 1_000_000, 10u128.pow(18)));
+        // 1 TOKEN = 0.5 USDC
+        reserves.insert(
+            token_usdc.address,
+            reserve(1_000 * 10u128.pow(18), 500 * 1_000_000),
+        );
+
+        let mut oracle = PriceOracle::new(weth, &pools, &reserves, 3);
+
+        assert_eq!(oracle.routes[&usdc][0].pool, deep.address);
+        assert_eq!(oracle.routes[&token].len(), 2);
+
+        let one_weth = U256::from(10).pow(U256::from(18));
+        assert_eq!(
+            oracle.convert(&weth, &usdc, one_weth),
+            Some(U256::from(2_000_000_000u64))
+        );
+        assert_eq!(
+            oracle.convert(&token, &usdc, one_weth),
+            Some(U256::from(500_000u64))
+        );
+        assert_eq!(
+            oracle.convert(&H160::from_low_u64_be(4), &usdc, one_weth),
+            None
+        );
+
+        // WETH doubles in USDC terms
+        reserves.insert(
+            deep.address,
+            reserve(40_000_000 * 1_000_000, 10_000 * 10u128.pow(18)),
+        );
+        oracle.refresh(&pools, &reserves);
+        assert_eq!(
+            oracle.convert(&weth, &usdc, one_weth),
+            Some(U256::from(4_000_000_000u64))
+        );
+        assert_eq!(oracle.routes[&usdc][0].pool, deep.address);
+
+        // the deep pool is drained below the shallow one, USDC is routed through the other pool
+        reserves.insert(deep.address, reserve(1_000 * 1_000_000, 10u128.pow(17)));
+        oracle.refresh(&pools, &reserves);
+        assert_eq!(oracle.routes[&usdc][0].pool, shallow.address);
+        assert_eq!(
+            oracle.convert(&weth, &usdc, one_weth),
+            Some(U256::from(3_000_000_000u64))
+        );
+    }
+
+    #[test]
+    fn upstream_route_change_test() {
+        let weth = token(0);
+        let usdc = token(1);
+        let token = token(2);
+
+        // TOKEN is routed through the shallow pool before the deep one replaces USDC's route
+        let shallow = pool(100, usdc, weth);
+        let token_usdc = pool(101, token, usdc);
+        let deep = pool(102, usdc, weth);
+        let pools = vec![shallow.clone(), token_usdc.clone(), deep.clone()];
+
+        let mut reserves = HashMap::new();
+        reserves.insert(shallow.address, reserve(3_000 * 1_000_000, 10u128.pow(18)));
+        // 1 TOKEN = 0.5 USDC, worth less in WETH through the deep pool's cheaper USDC
+        reserves.insert(
+            token_usdc.address,
+            reserve(1_000 * 10u128.pow(18), 500 * 1_000_000),
+        );
+        reserves.insert(
+            deep.address,
+            reserve(40_000_000 * 1_000_000, 10_000 * 10u128.pow(18)),
+        );
+
+        let oracle = PriceOracle::new(weth, &pools, &reserves, 3);
+
+        assert_eq!(oracle.routes[&usdc][0].pool, deep.address);
+        assert_eq!(oracle.routes[&token][0].pool, deep.address);
+        assert_eq!(oracle.routes[&token][1].pool, token_usdc.address);
+        let one_token = U256::from(10).pow(U256::from(18));
+        assert_eq!(
+            oracle.convert(&token, &usdc, one_token),
+            Some(U256::from(500_000u64))
+        );
+    }
+}
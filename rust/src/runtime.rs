@@ -0,0 +1,166 @@
+use ethers::types::U64;
+use log::info;
+use std::{
+    fmt,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+};
+use tokio::{
+    sync::broadcast::{error::RecvError, Sender},
+    task::JoinHandle,
+};
+
+use crate::streams::Event;
+
+/*
+Counters for how the strategy keeps up with the chain.
+A block is skipped if a newer head was already seen when it was received,
+aborted if a newer head arrived before it was scored, and processed otherwise.
+Each block counts in exactly one of them.
+*/
+#[derive(Debug, Default)]
+pub struct RuntimeMetrics {
+    pub processed_blocks: AtomicU64,
+    pub skipped_blocks: AtomicU64,
+    pub aborted_blocks: AtomicU64,
+    // events the broadcast channel dropped because a receiver fell behind
+    pub lagged_events: AtomicU64,
+    pub lag_incidents: AtomicU64,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct MetricsSnapshot {
+    pub processed_blocks: u64,
+    pub skipped_blocks: u64,
+    pub aborted_blocks: u64,
+    pub lagged_events: u64,
+    pub lag_incidents: u64,
+}
+
+impl RuntimeMetrics {
+    pub fn block_processed(&self) {
+        self.processed_blocks.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn block_skipped(&self) {
+        self.skipped_blocks.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn block_aborted(&self) {
+        self.aborted_blocks.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn record_lag(&self, missed: u64) {
+        self.lagged_events.fetch_add(missed, Ordering::Relaxed);
+        self.lag_incidents.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn snapshot(&self) -> MetricsSnapshot {
+        MetricsSnapshot {
+            processed_blocks: self.processed_blocks.load(Ordering::Relaxed),
+            skipped_blocks: self.skipped_blocks.load(Ordering::Relaxed),
+            aborted_blocks: self.aborted_blocks.load(Ordering::Relaxed),
+            lagged_events: self.lagged_events.load(Ordering::Relaxed),
+            lag_incidents: self.lag_incidents.load(Ordering::Relaxed),
+        }
+    }
+}
+
+impl fmt::Display for MetricsSnapshot {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "processed={} skipped={} aborted={} lagged_events={} lag_incidents={}",
+            self.processed_blocks,
+            self.skipped_blocks,
+            self.aborted_blocks,
+            self.lagged_events,
+            self.lag_incidents
+        )
+    }
+}
+
+/*
+Follows new blocks on its own receiver, so latest_block moves even while the
+strategy is busy with an older block. Lag on this receiver is counted too.
+*/
+pub fn spawn_head_tracker(
+    event_sender: &Sender<Event>,
+    latest_block: Arc<AtomicU64>,
+    metrics: Arc<RuntimeMetrics>,
+) -> JoinHandle<()> {
+    let mut event_receiver = event_sender.subscribe();
+    tokio::spawn(async move {
+        loop {
+            match event_receiver.recv().await {
+                Ok(Event::Block(block)) => {
+                    latest_block.fetch_max(block.block_number.as_u64(), Ordering::SeqCst);
+                }
+                Ok(_) => {}
+                Err(RecvError::Lagged(missed)) => {
+                    info!("Head tracker lagged by {} events", missed);
+                    metrics.record_lag(missed);
+                }
+                Err(RecvError::Closed) => break,
+            }
+        }
+    })
+}
+
+pub fn is_superseded(latest_block: &AtomicU64, block_number: U64) -> bool {
+    latest_block.load(Ordering::SeqCst) > block_number.as_u64()
+}
+
+#[cfg(test)]
+mod runtime_tests {
+    use super::*;
+    use crate::streams::NewBlock;
+    use tokio::sync::broadcast;
+
+    fn block(block_number: u64) -> Event {
+        Event::Block(NewBlock {
+            block_number: U64::from(block_number),
+            ..Default::default()
+        })
+    }
+
+    #[test]
+    fn runtime_metrics_test() {
+        let metrics = RuntimeMetrics::default();
+        metrics.block_processed();
+        metrics.block_skipped();
+        metrics.record_lag(3);
+        metrics.record_lag(2);
+
+        let snapshot = metrics.snapshot();
+        assert_eq!(snapshot.lagged_events, 5);
+        assert_eq!(snapshot.lag_incidents, 2);
+        assert_eq!(
+            snapshot.to_string(),
+            "processed=1 skipped=1 aborted=0 lagged_events=5 lag_incidents=2"
+        );
+    }
+
+    #[tokio::test]
+    async fn head_tracker_test() {
+        let (event_sender, _) = broadcast::channel(2);
+        let latest_block = Arc::new(AtomicU64::new(0));
+        let metrics = Arc::new(RuntimeMetrics::default());
+        let tracker = spawn_head_tracker(&event_sender, latest_block.clone(), metrics.clone());
+
+        // the channel holds two events, so the tracker misses some before it runs
+        for block_number in [10, 12, 11, 13, 9] {
+            event_sender.send(block(block_number)).unwrap();
+        }
+        drop(event_sender);
+        tracker.await.unwrap();
+
+        // an older block arriving late doesn't move the head back
+        assert_eq!(latest_block.load(Ordering::SeqCst), 13);
+        assert!(is_superseded(&latest_block, U64::from(12)));
+        assert!(!is_superseded(&latest_block, U64::from(13)));
+        assert_eq!(metrics.snapshot().lagged_events, 3);
+    }
+}
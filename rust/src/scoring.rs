@@ -0,0 +1,251 @@
+use anyhow::Result;
+use ethers::types::U256;
+use rayon::prelude::*;
+use std::{
+    cmp::Ordering as CmpOrdering,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+};
+use tokio::sync::oneshot;
+
+use crate::amounts::{Profit, TokenAmount};
+use crate::multi::Reserve;
+use crate::paths::ArbPath;
+use crate::policy::Policy;
+use crate::pools::PoolRegistry;
+
+#[derive(Debug, Clone, Copy)]
+pub struct ScoringParams {
+    // probe amount for the spread check, paths that lose on it aren't sized
+    pub spread_amount: U256,
+    pub max_amount_in: U256,
+    pub step_size: U256,
+}
+
+/*
+Everything a block's evaluation reads, frozen when the block arrives.
+Workers only see this snapshot, so reserve updates for the next block can't race with them.
+*/
+#[derive(Debug, Clone)]
+pub struct ReserveSnapshot {
+    pub block_number: u64,
+    pub paths: Arc<Vec<ArbPath>>,
+    pub pools: Arc<PoolRegistry>,
+    pub reserves: Arc<Vec<Reserve>>,
+    pub policy: Arc<Policy>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PathScore {
+    pub path_id: usize,
+    pub path: ArbPath,
+    pub spread: Profit,
+    pub amount_in: TokenAmount,
+    pub profit: Profit,
+}
+
+// Most profitable first, ties broken by path id so the order doesn't depend on scheduling
+pub fn sort_scores(scores: &mut [PathScore]) {
+    scores.sort_by(|a, b| match b.profit.value.cmp(&a.profit.value) {
+        CmpOrdering::Equal => a.path_id.cmp(&b.path_id),
+        ordering => ordering,
+    });
+}
+
+pub fn score_path(
+    snapshot: &ReserveSnapshot,
+    path_id: usize,
+    params: &ScoringParams,
+) -> Option<PathScore> {
+    let path = snapshot.paths.get(path_id)?;
+    let pools = &snapshot.pools;
+    let reserves = &snapshot.reserves;
+
+    // the same rules as generation, plus liquidity now that reserves are known
+    if snapshot.policy.check_path(path, pools, reserves).is_err() {
+        return None;
+    }
+
+    let (token_in, decimals) = path.token_in(pools);
+    let probe = TokenAmount::new(token_in, decimals, params.spread_amount);
+    let quote = path.simulate_amount(&probe, pools, reserves)?;
+    let spread = Profit::from_amounts(&quote, &probe)?;
+    if !spread.is_positive() {
+        return None;
+    }
+
+    let (amount_in, profit) =
+        path.optimize_amount_in(params.max_amount_in, params.step_size, pools, reserves);
+    if !profit.is_positive() {
+        return None;
+    }
+    Some(PathScore {
+        path_id,
+        path: *path,
+        spread,
+        amount_in,
+        profit,
+    })
+}
+
+/*
+Scores the paths in parallel on the current rayon pool.
+Returns None as soon as is_cancelled says so, otherwise the profitable paths in sort_scores order.
+*/
+pub fn score_paths<F>(
+    snapshot: &ReserveSnapshot,
+    path_ids: &[usize],
+    params: &ScoringParams,
+    is_cancelled: F,
+) -> Option<Vec<PathScore>>
+where
+    F: Fn() -> bool + Sync,
+{
+    let scores: Option<Vec<Option<PathScore>>> = path_ids
+        .par_iter()
+        .map(|path_id| {
+            if is_cancelled() {
+                return None;
+            }
+            Some(score_path(snapshot, *path_id, params))
+        })
+        .collect();
+
+    let mut scores: Vec<PathScore> = scores?.into_iter().flatten().collect();
+    sort_scores(&mut scores);
+    Some(scores)
+}
+
+/*
+Runs path scoring on its own rayon pool so it doesn't hold up the tokio workers.
+latest_block is the newest head seen, an evaluation of an older block stops early.
+*/
+pub struct PathScorer {
+    pub pool: Arc<rayon::ThreadPool>,
+    pub latest_block: Arc<AtomicU64>,
+    pub params: ScoringParams,
+}
+
+impl PathScorer {
+    // num_threads 0 lets rayon use one thread per core
+    pub fn new(num_threads: usize, params: ScoringParams) -> Result<Self> {
+        let pool = rayon::ThreadPoolBuilder::new()
+            .num_threads(num_threads)
+            .thread_name(|i| format!("path-scorer-{}", i))
+            .build()?;
+        Ok(Self {
+            pool: Arc::new(pool),
+            latest_block: Arc::new(AtomicU64::new(0)),
+            params,
+        })
+    }
+
+    // Call on every new head, in-flight evaluations of older blocks are cancelled
+    pub fn advance(&self, block_number: u64) {
+        self.latest_block.fetch_max(block_number, Ordering::SeqCst);
+    }
+
+    pub fn is_stale(&self, block_number: u64) -> bool {
+        self.latest_block.load(Ordering::SeqCst) > block_number
+    }
+
+    // None if a newer block arrived before scoring finished
+    pub async fn evaluate(
+        &self,
+        snapshot: ReserveSnapshot,
+        path_ids: Vec<usize>,
+    ) -> Option<Vec<PathScore>> {
+        self.advance(snapshot.block_number);
+
+        let (sender, receiver) = oneshot::channel();
+        let latest_block = self.latest_block.clone();
+        let params = self.params;
+        self.pool.spawn(move || {
+            let block_number = snapshot.block_number;
+            let scores = score_paths(&snapshot, &path_ids, &params, || {
+                latest_block.load(Ordering::Relaxed) > block_number
+            });
+            let _ = sender.send(scores);
+        });
+        receiver.await.ok().flatten()
+    }
+}
+
+#[cfg(test)]
+mod scoring_tests {
+    use super::*;
+    use crate::paths::generate_triangular_paths;
+    use crate::test_utils::{pool, token, triangle};
+
+    // usdc/weth is mispriced against two triangles, so some paths are profitable
+    fn snapshot(block_number: u64) -> ReserveSnapshot {
+        let (usdc, weth) = (token(0), token(1));
+        let (mut pools, mut reserves) = triangle(usdc, weth, token(2));
+        // three more triangles through usdc/weth, each a bit more mispriced
+        for i in 1..4u64 {
+            let other = token(2 + i);
+            pools.push(pool(1 + 2 * i, weth, other));
+            pools.push(pool(2 + 2 * i, usdc, other));
+            reserves.push(Reserve {
+                reserve1: reserves[1].reserve1 + U256::from(i * 10_000) * U256::exp10(18),
+                ..reserves[1].clone()
+            });
+            reserves.push(reserves[2].clone());
+        }
+
+        let registry = PoolRegistry::new(&pools);
+        let paths = generate_triangular_paths(&registry, usdc, &Policy::default());
+        ReserveSnapshot {
+            block_number,
+            paths: Arc::new(paths),
+            pools: Arc::new(registry),
+            reserves: Arc::new(reserves),
+            policy: Arc::new(Policy::default()),
+        }
+    }
+
+    fn params() -> ScoringParams {
+        ScoringParams {
+            spread_amount: U256::exp10(18),
+            max_amount_in: U256::from(100_000u64) * U256::exp10(18),
+            step_size: U256::from(1_000u64) * U256::exp10(18),
+        }
+    }
+
+    #[test]
+    fn score_paths_test() {
+        let snapshot = snapshot(1);
+        let path_ids: Vec<usize> = (0..snapshot.paths.len()).collect();
+
+        let scores = score_paths(&snapshot, &path_ids, &params(), || false).unwrap();
+        assert_eq!(scores.len(), 4);
+        assert!(scores
+            .windows(2)
+            .all(|pair| pair[0].profit.value >= pair[1].profit.value));
+
+        // the same as scoring one path at a time
+        let mut serial: Vec<PathScore> = path_ids
+            .iter()
+            .filter_map(|path_id| score_path(&snapshot, *path_id, &params()))
+            .collect();
+        sort_scores(&mut serial);
+        assert_eq!(scores, serial);
+
+        assert_eq!(score_paths(&snapshot, &path_ids, &params(), || true), None);
+    }
+
+    #[tokio::test]
+    async fn path_scorer_test() {
+        let scorer = PathScorer::new(2, params()).unwrap();
+        let path_ids: Vec<usize> = (0..snapshot(5).paths.len()).collect();
+
+        let scores = scorer.evaluate(snapshot(5), path_ids.clone()).await;
+        assert_eq!(scores.map(|scores| scores.len()), Some(4));
+
+        // block 4 was superseded by block 5
+        assert!(scorer.is_stale(4));
+        assert_eq!(scorer.evaluate(snapshot(4), path_ids).await, None);
+    }
+}
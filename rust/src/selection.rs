@@ -0,0 +1,382 @@
+use ethers::types::{I256, U256};
+use std::collections::HashSet;
+
+use crate::amounts::Profit;
+use crate::multi::Reserve;
+use crate::paths::ArbPath;
+use crate::pools::{PoolId, PoolRegistry};
+use crate::scoring::{PathScore, ScoringParams};
+
+/*
+Paths that share a pool invalidate each other's quotes, so a block's scores can't all be executed.
+Past this many candidates the exact search gives way to filling greedily.
+*/
+pub const MAX_EXACT_CANDIDATES: usize = 24;
+
+// 550000 for three hops, most of it goes to the swaps
+pub fn estimate_gas_usage(path: &ArbPath) -> u64 {
+    100000 + 150000 * path.nhop as u64
+}
+
+// Unused gas isn't paid for, so an order's limit is its estimate with half of it again on top
+pub fn gas_limit(gas_usage: u64) -> u64 {
+    gas_usage + gas_usage / 2
+}
+
+/*
+Limits on what goes into one submission.
+max_paths 1 means no packing, capital is the total amount_in in the start token.
+*/
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SelectionBudget {
+    pub max_paths: usize,
+    pub max_gas: u64,
+    pub max_capital: U256,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Candidate {
+    pub score: PathScore,
+    pub gas_usage: u64,
+    // in the profit token, doesn't change when the path is re-sized
+    pub gas_cost: U256,
+}
+
+impl Candidate {
+    pub fn new(score: PathScore, gas_cost: U256) -> Self {
+        Self {
+            score,
+            gas_usage: estimate_gas_usage(&score.path),
+            gas_cost,
+        }
+    }
+
+    pub fn excess_profit(&self) -> Profit {
+        self.score.profit.sub_cost(self.gas_cost)
+    }
+}
+
+// What a set of candidates uses up, checked against a SelectionBudget as it grows
+#[derive(Debug, Clone, Default)]
+struct Usage {
+    pools: HashSet<PoolId>,
+    paths: usize,
+    gas: u64,
+    capital: U256,
+}
+
+impl Usage {
+    fn fits(&self, candidate: &Candidate, budget: &SelectionBudget) -> bool {
+        let capital = self.capital.saturating_add(candidate.score.amount_in.raw);
+        self.paths < budget.max_paths
+            && self.gas.saturating_add(candidate.gas_usage) <= budget.max_gas
+            && capital <= budget.max_capital
+    }
+
+    fn is_disjoint(&self, path: &ArbPath) -> bool {
+        (0..path.nhop).all(|i| !self.pools.contains(&path._get_pool(i)))
+    }
+
+    fn add(&mut self, candidate: &Candidate) {
+        let path = &candidate.score.path;
+        self.pools.extend((0..path.nhop).map(|i| path._get_pool(i)));
+        self.paths += 1;
+        self.gas += candidate.gas_usage;
+        self.capital = self.capital.saturating_add(candidate.score.amount_in.raw);
+    }
+}
+
+/*
+The most profitable set of pool-disjoint candidates within the budget, most profitable first.
+Branch and bound over the top MAX_EXACT_CANDIDATES by excess profit,
+then whatever still fits from the rest is added greedily.
+*/
+pub fn select_disjoint(candidates: &[Candidate], budget: &SelectionBudget) -> Vec<Candidate> {
+    let mut sorted: Vec<Candidate> = candidates
+        .iter()
+        .filter(|candidate| candidate.excess_profit().is_positive())
+        .copied()
+        .collect();
+    sort_candidates(&mut sorted);
+
+    let exact = &sorted[..sorted.len().min(MAX_EXACT_CANDIDATES)];
+    // bound[i] is the most the candidates from i on could still add
+    let mut bound = vec![I256::zero(); exact.len() + 1];
+    for i in (0..exact.len()).rev() {
+        bound[i] = bound[i + 1].saturating_add(exact[i].excess_profit().value);
+    }
+
+    let mut search = DisjointSearch {
+        candidates: exact,
+        bound: &bound,
+        budget,
+        chosen: Vec::new(),
+        best: Vec::new(),
+        best_value: I256::zero(),
+    };
+    search.visit(0, &Usage::default(), I256::zero());
+
+    let mut usage = Usage::default();
+    let mut selected: Vec<Candidate> = search.best.iter().map(|i| exact[*i]).collect();
+    for candidate in &selected {
+        usage.add(candidate);
+    }
+    for candidate in &sorted[exact.len()..] {
+        if usage.fits(candidate, budget) && usage.is_disjoint(&candidate.score.path) {
+            usage.add(candidate);
+            selected.push(*candidate);
+        }
+    }
+    sort_candidates(&mut selected);
+    selected
+}
+
+struct DisjointSearch<'a> {
+    candidates: &'a [Candidate],
+    bound: &'a [I256],
+    budget: &'a SelectionBudget,
+    chosen: Vec<usize>,
+    best: Vec<usize>,
+    best_value: I256,
+}
+
+impl<'a> DisjointSearch<'a> {
+    fn visit(&mut self, i: usize, usage: &Usage, value: I256) {
+        if value > self.best_value {
+            self.best_value = value;
+            self.best = self.chosen.clone();
+        }
+        if i == self.candidates.len() || value.saturating_add(self.bound[i]) <= self.best_value {
+            return;
+        }
+
+        let candidate = &self.candidates[i];
+        if usage.fits(candidate, self.budget) && usage.is_disjoint(&candidate.score.path) {
+            let mut taken = usage.clone();
+            taken.add(candidate);
+            self.chosen.push(i);
+            let taken_value = value.saturating_add(candidate.excess_profit().value);
+            self.visit(i + 1, &taken, taken_value);
+            self.chosen.pop();
+        }
+        self.visit(i + 1, usage, value);
+    }
+}
+
+/*
+Takes the best candidate, applies its swaps to a copy of the reserves and re-sizes the rest
+on what it leaves, until nothing within the budget is profitable after gas.
+Paths may share pools, the returned scores are the re-sized ones in execution order.
+*/
+pub fn select_sequential(
+    candidates: &[Candidate],
+    pools: &PoolRegistry,
+    reserves: &[Reserve],
+    params: &ScoringParams,
+    budget: &SelectionBudget,
+) -> Vec<Candidate> {
+    let mut reserves = reserves.to_vec();
+    let mut remaining: Vec<Candidate> = candidates
+        .iter()
+        .filter(|candidate| candidate.excess_profit().is_positive())
+        .copied()
+        .collect();
+    let mut usage = Usage::default();
+    let mut selected = Vec::new();
+
+    while !remaining.is_empty() {
+        let mut resized: Vec<(usize, Candidate)> = remaining
+            .iter()
+            .enumerate()
+            .filter_map(|(i, candidate)| {
+                let path = &candidate.score.path;
+                let (amount_in, profit) = path.optimize_amount_in(
+                    params.max_amount_in,
+                    params.step_size,
+                    pools,
+                    &reserves,
+                );
+                let candidate = Candidate {
+                    score: PathScore {
+                        amount_in,
+                        profit,
+                        ..candidate.score
+                    },
+                    ..*candidate
+                };
+                if candidate.excess_profit().is_positive() && usage.fits(&candidate, budget) {
+                    Some((i, candidate))
+                } else {
+                    None
+                }
+            })
+            .collect();
+        resized.sort_by(|a, b| compare_candidates(&a.1, &b.1));
+
+        let (i, best) = match resized.first() {
+            Some(best) => *best,
+            None => break,
+        };
+        if best
+            .score
+            .path
+            .apply_v2_path(best.score.amount_in.raw, pools, &mut reserves)
+            .is_none()
+        {
+            break;
+        }
+        usage.add(&best);
+        selected.push(best);
+        remaining.remove(i);
+    }
+    selected
+}
+
+// Most excess profit first, ties broken by path id like sort_scores
+fn compare_candidates(a: &Candidate, b: &Candidate) -> std::cmp::Ordering {
+    b.excess_profit()
+        .value
+        .cmp(&a.excess_profit().value)
+        .then(a.score.path_id.cmp(&b.score.path_id))
+}
+
+fn sort_candidates(candidates: &mut [Candidate]) {
+    candidates.sort_by(compare_candidates);
+}
+
+#[cfg(test)]
+mod selection_tests {
+    use super::*;
+    use crate::amounts::TokenAmount;
+    use crate::test_utils::{token, triangle};
+    use ethers::types::H160;
+
+    fn usdc() -> H160 {
+        token(0)
+    }
+
+    fn budget(max_paths: usize) -> SelectionBudget {
+        SelectionBudget {
+            max_paths,
+            max_gas: u64::MAX,
+            max_capital: U256::MAX,
+        }
+    }
+
+    // a 2 hop path over the given pools, scored at a fixed profit
+    fn candidate(path_id: usize, pools: [u32; 2], profit: i64) -> Candidate {
+        let path =
+            ArbPath::from_hops(&[(PoolId(pools[0]), true), (PoolId(pools[1]), false)]).unwrap();
+        let amount_in = TokenAmount::from_units(usdc(), 6, 100);
+        let mut spread = Profit::zero(usdc(), 6);
+        spread.value = I256::from(profit);
+        let score = PathScore {
+            path_id,
+            path,
+            spread,
+            amount_in,
+            profit: spread,
+        };
+        Candidate::new(score, U256::from(1))
+    }
+
+    fn path_ids(candidates: &[Candidate]) -> Vec<usize> {
+        candidates
+            .iter()
+            .map(|candidate| candidate.score.path_id)
+            .collect()
+    }
+
+    #[test]
+    fn select_disjoint_test() {
+        // taking the best path first would block both of the others
+        let candidates = vec![
+            candidate(0, [0, 1], 10),
+            candidate(1, [1, 2], 7),
+            candidate(2, [0, 3], 7),
+            candidate(3, [4, 5], -5),
+        ];
+        assert_eq!(
+            path_ids(&select_disjoint(&candidates, &budget(4))),
+            vec![1, 2]
+        );
+        assert_eq!(path_ids(&select_disjoint(&candidates, &budget(1))), vec![0]);
+
+        let mut gas_budget = budget(4);
+        gas_budget.max_gas = candidates[0].gas_usage;
+        assert_eq!(
+            path_ids(&select_disjoint(&candidates, &gas_budget)),
+            vec![0]
+        );
+
+        let mut capital_budget = budget(4);
+        capital_budget.max_capital = candidates[0].score.amount_in.raw;
+        assert_eq!(
+            path_ids(&select_disjoint(&candidates, &capital_budget)),
+            vec![0]
+        );
+
+        assert!(select_disjoint(&[], &budget(4)).is_empty());
+    }
+
+    #[test]
+    fn select_disjoint_beyond_exact_test() {
+        // pool 0 is shared by every path, only the best one can be taken
+        let candidates: Vec<Candidate> = (0..MAX_EXACT_CANDIDATES + 8)
+            .map(|i| candidate(i, [0, 1 + i as u32], 100 - i as i64))
+            .collect();
+        assert_eq!(path_ids(&select_disjoint(&candidates, &budget(8))), vec![0]);
+
+        // disjoint paths past the exact search are still added
+        let candidates: Vec<Candidate> = (0..MAX_EXACT_CANDIDATES + 8)
+            .map(|i| candidate(i, [2 * i as u32, 2 * i as u32 + 1], 100 - i as i64))
+            .collect();
+        let selected = select_disjoint(&candidates, &budget(MAX_EXACT_CANDIDATES + 8));
+        assert_eq!(selected.len(), MAX_EXACT_CANDIDATES + 8);
+    }
+
+    #[test]
+    fn select_sequential_test() {
+        let (pools, reserves) = triangle(usdc(), token(1), token(2));
+        let registry = PoolRegistry::new(&pools);
+        let e18 = U256::exp10(18);
+        let params = ScoringParams {
+            spread_amount: e18,
+            max_amount_in: U256::from(100_000u64) * e18,
+            step_size: U256::from(1_000u64) * e18,
+        };
+
+        let path = ArbPath::from_hops(&[(PoolId(0), true), (PoolId(1), true), (PoolId(2), false)])
+            .unwrap();
+        let (amount_in, profit) =
+            path.optimize_amount_in(params.max_amount_in, params.step_size, &registry, &reserves);
+        assert!(profit.is_positive());
+        let score = PathScore {
+            path_id: 0,
+            path,
+            spread: profit,
+            amount_in,
+            profit,
+        };
+        let gas_cost = U256::from(10u64) * e18;
+
+        // the same path twice, the first takes the spread the second was quoted on
+        let candidates = vec![
+            Candidate::new(score, gas_cost),
+            Candidate::new(
+                PathScore {
+                    path_id: 1,
+                    ..score
+                },
+                gas_cost,
+            ),
+        ];
+        let selected = select_sequential(&candidates, &registry, &reserves, &params, &budget(2));
+        assert_eq!(path_ids(&selected), vec![0]);
+        assert_eq!(selected[0].score.profit, profit);
+
+        // the input reserves are left alone
+        let again = select_sequential(&candidates, &registry, &reserves, &params, &budget(2));
+        assert_eq!(again, selected);
+    }
+}
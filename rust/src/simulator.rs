@@ -1,4 +1,6 @@
-use ethers::types::U256;
+use ethers::types::{U256, U512};
+
+use crate::fixed_point;
 
 pub struct UniswapV2Simulator;
 
@@ -28,16 +30,526 @@ impl UniswapV2Simulator {
         }
     }
 
+    /*
+    The router's getAmountOut. V2 reserves are uint112, so nearly every swap is done
+    by get_amount_out_u128, and only amounts that don't fit in u128 go through U256.
+    */
     pub fn get_amount_out(
         amount_in: U256,
         reserve_in: U256,
         reserve_out: U256,
         fee: U256,
     ) -> Option<U256> {
+        if amount_in.bits() <= 128
+            && reserve_in.bits() <= 128
+            && reserve_out.bits() <= 128
+            && fee.bits() <= 32
+        {
+            if let Some(amount_out) = Self::get_amount_out_u128(
+                amount_in.as_u128(),
+                reserve_in.as_u128(),
+                reserve_out.as_u128(),
+                fee.as_u32(),
+            ) {
+                return Some(U256::from(amount_out));
+            }
+        }
+        Self::get_amount_out_u256(amount_in, reserve_in, reserve_out, fee)
+    }
+
+    /*
+    Same result as get_amount_out_u256 without leaving native integers:
+    the numerator is a 256 bit product held in two u128 words.
+    None if amount_in * (1000 - fee) or the denominator overflows u128,
+    callers should then fall back to get_amount_out_u256.
+    */
+    pub fn get_amount_out_u128(
+        amount_in: u128,
+        reserve_in: u128,
+        reserve_out: u128,
+        fee: u32,
+    ) -> Option<u128> {
+        let fee = (fee / 100) as u128;
+        let amount_in_with_fee = amount_in.checked_mul(1000u128.checked_sub(fee)?)?;
+        let (numerator_hi, numerator_lo) = mul_wide(amount_in_with_fee, reserve_out);
+        let denominator = reserve_in
+            .checked_mul(1000)?
+            .checked_add(amount_in_with_fee)?;
+        div_wide(numerator_hi, numerator_lo, denominator)
+    }
+
+    pub fn get_amount_out_u256(
+        amount_in: U256,
+        reserve_in: U256,
+        reserve_out: U256,
+        fee: U256,
+    ) -> Option<U256> {
+        // products are taken in U512 so large amounts can't overflow, the result is below reserve_out
         let fee = fee / U256::from(100);
-        let amount_in_with_fee = amount_in * (U256::from(1000) - fee);
-        let numerator = amount_in_with_fee * reserve_out;
-        let denominator = (reserve_in * 1000) + amount_in_with_fee;
-        numerator.checked_div(denominator)
+        let amount_in_with_fee = amount_in.full_mul(U256::from(1000).checked_sub(fee)?);
+        let numerator = amount_in_with_fee.checked_mul(U512::from(reserve_out))?;
+        let denominator = reserve_in.full_mul(U256::from(1000)) + amount_in_with_fee;
+        if denominator.is_zero() {
+            return None;
+        }
+        U256::try_from(numerator / denominator).ok()
+    }
+}
+
+// Curve keeps A scaled by this, pools that only expose A() are multiplied up to match
+pub const A_PRECISION: u64 = 100;
+pub const CURVE_FEE_DENOMINATOR: u64 = 10_000_000_000;
+const CURVE_ITERATIONS: usize = 255;
+
+/*
+The StableSwap invariant as Curve's plain pools compute it, for n coins.
+Balances are normalized to 18 decimals (Curve's xp), amp is A * A_PRECISION
+and fee is over CURVE_FEE_DENOMINATOR. Everything returns None instead of overflowing.
+*/
+pub struct StableSwapSimulator;
+
+impl StableSwapSimulator {
+    pub fn normalize(balances: &[U256], decimals: &[u8]) -> Option<Vec<U256>> {
+        if balances.len() != decimals.len() {
+            return None;
+        }
+        balances
+            .iter()
+            .zip(decimals)
+            .map(|(balance, decimals)| balance.checked_mul(Self::precision_mul(*decimals)?))
+            .collect()
+    }
+
+    fn precision_mul(decimals: u8) -> Option<U256> {
+        Some(U256::exp10(18usize.checked_sub(decimals as usize)?))
+    }
+
+    pub fn get_d(xp: &[U256], amp: U256) -> Option<U256> {
+        let n = U256::from(xp.len());
+        let a_precision = U256::from(A_PRECISION);
+        let mut sum = U256::zero();
+        for x in xp {
+            sum = sum.checked_add(*x)?;
+        }
+        if sum.is_zero() {
+            return Some(U256::zero());
+        }
+
+        let ann = amp.checked_mul(n)?;
+        let mut d = sum;
+        for _ in 0..CURVE_ITERATIONS {
+            let mut d_p = d;
+            for x in xp {
+                d_p = d_p.checked_mul(d)?.checked_div(x.checked_mul(n)?)?;
+            }
+            let d_prev = d;
+            let numerator = (ann.checked_mul(sum)? / a_precision)
+                .checked_add(d_p.checked_mul(n)?)?
+                .checked_mul(d)?;
+            let denominator = (ann.checked_sub(a_precision)?.checked_mul(d)? / a_precision)
+                .checked_add((n + 1).checked_mul(d_p)?)?;
+            d = numerator.checked_div(denominator)?;
+            if converged(d, d_prev) {
+                return Some(d);
+            }
+        }
+        None
+    }
+
+    // The balance of coin j that keeps D when coin i's balance is x, all normalized
+    pub fn get_y(i: usize, j: usize, x: U256, xp: &[U256], amp: U256) -> Option<U256> {
+        if i == j || i >= xp.len() || j >= xp.len() {
+            return None;
+        }
+        let n = U256::from(xp.len());
+        let a_precision = U256::from(A_PRECISION);
+        let d = Self::get_d(xp, amp)?;
+        let ann = amp.checked_mul(n)?;
+
+        let mut c = d;
+        let mut sum = U256::zero();
+        for (k, balance) in xp.iter().enumerate() {
+            let x_k = if k == i {
+                x
+            } else if k != j {
+                *balance
+            } else {
+                continue;
+            };
+            sum = sum.checked_add(x_k)?;
+            c = c.checked_mul(d)?.checked_div(x_k.checked_mul(n)?)?;
+        }
+        c = c
+            .checked_mul(d)?
+            .checked_mul(a_precision)?
+            .checked_div(ann.checked_mul(n)?)?;
+        let b = sum.checked_add(d.checked_mul(a_precision)?.checked_div(ann)?)?;
+
+        let mut y = d;
+        for _ in 0..CURVE_ITERATIONS {
+            let y_prev = y;
+            let numerator = y.checked_mul(y)?.checked_add(c)?;
+            let denominator = y
+                .checked_mul(U256::from(2))?
+                .checked_add(b)?
+                .checked_sub(d)?;
+            y = numerator.checked_div(denominator)?;
+            if converged(y, y_prev) {
+                return Some(y);
+            }
+        }
+        None
+    }
+
+    // The pool's get_dy: what swapping dx of coin i gives out in coin j, after the fee
+    pub fn get_dy(
+        i: usize,
+        j: usize,
+        dx: U256,
+        balances: &[U256],
+        decimals: &[u8],
+        amp: U256,
+        fee: U256,
+    ) -> Option<U256> {
+        let xp = Self::normalize(balances, decimals)?;
+        let x = xp
+            .get(i)?
+            .checked_add(dx.checked_mul(Self::precision_mul(decimals[i])?)?)?;
+        let y = Self::get_y(i, j, x, &xp, amp)?;
+        // one unit less, rounding against the trader like the pool does
+        let dy = xp[j].checked_sub(y)?.checked_sub(U256::one())?;
+        let dy_fee = dy.checked_mul(fee)? / U256::from(CURVE_FEE_DENOMINATOR);
+        Some(dy.checked_sub(dy_fee)? / Self::precision_mul(decimals[j])?)
+    }
+}
+
+fn converged(value: U256, previous: U256) -> bool {
+    if value > previous {
+        value - previous <= U256::one()
+    } else {
+        previous - value <= U256::one()
+    }
+}
+
+/*
+A Balancer weighted pool's swap as the vault runs it: the swap fee comes off amount_in,
+balances and the amount are scaled to 18 decimals and go through WeightedMath._calcOutGivenIn,
+and the output is scaled back down. Pairs are (token in, token out), weights and the fee
+are 18 decimal fixed point.
+*/
+pub struct WeightedSimulator;
+
+impl WeightedSimulator {
+    pub fn get_amount_out(
+        amount_in: U256,
+        balances: (U256, U256),
+        decimals: (u8, u8),
+        weights: (U256, U256),
+        swap_fee: U256,
+    ) -> Option<U256> {
+        let fee_amount = fixed_point::mul_up(amount_in, swap_fee)?;
+        let amount_in = amount_in.checked_sub(fee_amount)?;
+        let (scale_in, scale_out) = (
+            StableSwapSimulator::precision_mul(decimals.0)?,
+            StableSwapSimulator::precision_mul(decimals.1)?,
+        );
+        let amount_out = Self::calc_out_given_in(
+            balances.0.checked_mul(scale_in)?,
+            weights.0,
+            balances.1.checked_mul(scale_out)?,
+            weights.1,
+            amount_in.checked_mul(scale_in)?,
+        )?;
+        Some(amount_out / scale_out)
+    }
+
+    // balance_out * (1 - (balance_in / (balance_in + amount_in)) ^ (weight_in / weight_out))
+    pub fn calc_out_given_in(
+        balance_in: U256,
+        weight_in: U256,
+        balance_out: U256,
+        weight_out: U256,
+        amount_in: U256,
+    ) -> Option<U256> {
+        // the vault won't take in more than 30% of the balance
+        let max_in = fixed_point::mul_down(balance_in, U256::from(3u64) * U256::exp10(17))?;
+        if amount_in > max_in {
+            return None;
+        }
+        let denominator = balance_in.checked_add(amount_in)?;
+        let base = fixed_point::div_up(balance_in, denominator)?;
+        let exponent = fixed_point::div_down(weight_in, weight_out)?;
+        let power = fixed_point::pow_up(base, exponent)?;
+        fixed_point::mul_down(balance_out, fixed_point::complement(power))
+    }
+}
+
+const HALF: u32 = 64;
+const HALF_MASK: u128 = (1 << HALF) - 1;
+
+// a * b as (high, low) u128 words
+fn mul_wide(a: u128, b: u128) -> (u128, u128) {
+    let (a_hi, a_lo) = (a >> HALF, a & HALF_MASK);
+    let (b_hi, b_lo) = (b >> HALF, b & HALF_MASK);
+
+    let lo_lo = a_lo * b_lo;
+    let hi_lo = a_hi * b_lo;
+    let lo_hi = a_lo * b_hi;
+    let hi_hi = a_hi * b_hi;
+
+    // the middle column can't overflow: each term is below 2^64
+    let mid = (lo_lo >> HALF) + (hi_lo & HALF_MASK) + (lo_hi & HALF_MASK);
+    let lo = (mid << HALF) | (lo_lo & HALF_MASK);
+    let hi = hi_hi + (hi_lo >> HALF) + (lo_hi >> HALF) + (mid >> HALF);
+    (hi, lo)
+}
+
+/*
+(hi, lo) / d rounded down, None if d is zero or the quotient doesn't fit in u128.
+Long division in 64 bit digits (divlu from Hacker's Delight, 9-3).
+*/
+fn div_wide(hi: u128, lo: u128, d: u128) -> Option<u128> {
+    if d == 0 || hi >= d {
+        return None;
+    }
+    if hi == 0 {
+        return Some(lo / d);
+    }
+
+    let base: u128 = 1 << HALF;
+    // normalize so the divisor's top bit is set, that keeps each digit estimate off by at most 2
+    let shift = d.leading_zeros();
+    let d = d << shift;
+    let (d_hi, d_lo) = (d >> HALF, d & HALF_MASK);
+    let top = if shift == 0 {
+        hi
+    } else {
+        (hi << shift) | (lo >> (128 - shift))
+    };
+    let lo = lo << shift;
+    let (lo_hi, lo_lo) = (lo >> HALF, lo & HALF_MASK);
+
+    let mut q_hi = top / d_hi;
+    let mut rem = top - q_hi * d_hi;
+    while q_hi >= base || q_hi * d_lo > (rem << HALF) + lo_hi {
+        q_hi -= 1;
+        rem += d_hi;
+        if rem >= base {
+            break;
+        }
+    }
+
+    let mid = (top << HALF)
+        .wrapping_add(lo_hi)
+        .wrapping_sub(q_hi.wrapping_mul(d));
+    let mut q_lo = mid / d_hi;
+    let mut rem = mid - q_lo * d_hi;
+    while q_lo >= base || q_lo * d_lo > (rem << HALF) + lo_lo {
+        q_lo -= 1;
+        rem += d_hi;
+        if rem >= base {
+            break;
+        }
+    }
+
+    Some((q_hi << HALF) + q_lo)
+}
+
+#[cfg(test)]
+mod simulator_tests {
+    use super::*;
+    use proptest::prelude::*;
+
+    #[test]
+    fn get_amount_out_test() {
+        let fee = U256::from(300);
+        let amount_out = UniswapV2Simulator::get_amount_out(
+            U256::from(1_000_000u64),
+            U256::from(100_000_000u64),
+            U256::from(50_000_000u64),
+            fee,
+        );
+        assert_eq!(amount_out, Some(U256::from(493_579u64)));
+
+        // amount_in * 997 * reserve_out overflows U256 but not the result
+        let huge = U256::MAX >> 12;
+        let amount_out = UniswapV2Simulator::get_amount_out(huge, huge, huge, fee).unwrap();
+        assert!(amount_out < huge && amount_out > huge / 3);
+
+        assert_eq!(
+            UniswapV2Simulator::get_amount_out(U256::zero(), U256::zero(), huge, fee),
+            None
+        );
+    }
+
+    #[test]
+    fn wide_math_test() {
+        assert_eq!(mul_wide(u128::MAX, u128::MAX), (u128::MAX - 1, 1));
+        assert_eq!(mul_wide(1 << 64, 1 << 64), (1, 0));
+        assert_eq!(div_wide(1, 0, 2), Some(1 << 127));
+        assert_eq!(div_wide(u128::MAX - 1, 1, u128::MAX), Some(u128::MAX));
+        assert_eq!(div_wide(1, 0, 1), None);
+        assert_eq!(div_wide(0, 7, 0), None);
+    }
+
+    // a DAI/USDC/USDT pool at A = 2000 with the 0.01% fee
+    fn three_pool() -> (Vec<U256>, Vec<u8>, U256, U256) {
+        let balances = vec![
+            U256::from(100_000_000u64) * U256::exp10(18),
+            U256::from(100_000_000u64) * U256::exp10(6),
+            U256::from(50_000_000u64) * U256::exp10(6),
+        ];
+        let amp = U256::from(2000 * A_PRECISION);
+        (balances, vec![18, 6, 6], amp, U256::from(1_000_000u64))
+    }
+
+    #[test]
+    fn stable_swap_test() {
+        let (balances, decimals, amp, fee) = three_pool();
+
+        // balanced coins sum to D
+        let xp = vec![U256::exp10(24); 3];
+        assert_eq!(
+            StableSwapSimulator::get_d(&xp, amp),
+            Some(U256::exp10(24) * 3)
+        );
+        assert_eq!(
+            StableSwapSimulator::get_d(&[U256::zero(); 3], amp),
+            Some(U256::zero())
+        );
+
+        // 1000 DAI for a little under 1000 USDC, the fee and a tiny slippage
+        let dy = StableSwapSimulator::get_dy(
+            0,
+            1,
+            U256::from(1000u64) * U256::exp10(18),
+            &balances,
+            &decimals,
+            amp,
+            fee,
+        )
+        .unwrap();
+        assert!(dy < U256::from(999_900_000u64) && dy > U256::from(999_800_000u64));
+
+        // USDT is scarce, so it's worth more than the other two
+        let dy = StableSwapSimulator::get_dy(
+            2,
+            1,
+            U256::from(1000u64) * U256::exp10(6),
+            &balances,
+            &decimals,
+            amp,
+            fee,
+        )
+        .unwrap();
+        assert!(dy > U256::from(1000u64) * U256::exp10(6));
+
+        let dy =
+            |i, j| StableSwapSimulator::get_dy(i, j, U256::one(), &balances, &decimals, amp, fee);
+        assert_eq!(dy(0, 0), None);
+        assert_eq!(dy(0, 3), None);
+        assert_eq!(
+            StableSwapSimulator::normalize(&balances, &[18, 6, 24]),
+            None
+        );
+    }
+
+    #[test]
+    fn weighted_swap_test() {
+        let e18 = U256::exp10(18);
+        let fee = U256::exp10(15) * 3;
+        let expected = |balance_in: f64, balance_out: f64, amount_in: f64, exponent: f64| {
+            balance_out * (1.0 - (balance_in / (balance_in + amount_in * 0.997)).powf(exponent))
+        };
+
+        // a 50/50 pool is a constant product pool, 1 WETH for USDC
+        let balances = (
+            U256::from(1_000u64) * e18,
+            U256::from(2_000_000u64) * U256::exp10(6),
+        );
+        let half = (e18 / 2, e18 / 2);
+        let amount_out =
+            WeightedSimulator::get_amount_out(e18, balances, (18, 6), half, fee).unwrap();
+        let want = expected(1000.0, 2e12, 1.0, 1.0);
+        assert!((amount_out.as_u128() as f64 - want).abs() < 2.0);
+
+        // 60/40 goes through LogExpMath.pow, which the vault rounds up so the pool keeps more
+        let weights = (e18 * 6 / 10, e18 * 4 / 10);
+        let amount_out =
+            WeightedSimulator::get_amount_out(e18 * 5, balances, (18, 6), weights, fee).unwrap();
+        let want = expected(1000.0, 2e12, 5.0, 1.5);
+        let got = amount_out.as_u128() as f64;
+        assert!(got <= want && (want - got) / want < 1e-9);
+
+        // more than 30% of the balance in is refused
+        let too_much = U256::from(400u64) * e18;
+        assert_eq!(
+            WeightedSimulator::get_amount_out(too_much, balances, (18, 6), weights, fee),
+            None
+        );
+        assert_eq!(
+            WeightedSimulator::get_amount_out(e18, balances, (18, 24), weights, fee),
+            None
+        );
+    }
+
+    // uint112 reserves, and amounts up to the largest a pair could be sent
+    fn uint112() -> impl Strategy<Value = u128> {
+        prop_oneof![0..1_000_000u128, 0..(1u128 << 64), 0..(1u128 << 112)]
+    }
+
+    proptest! {
+        #[test]
+        fn u128_matches_u256(
+            amount_in in uint112(),
+            reserve_in in uint112(),
+            reserve_out in uint112(),
+            fee in prop_oneof![Just(300u32), Just(25u32), 0..100_000u32],
+        ) {
+            let fast =
+                UniswapV2Simulator::get_amount_out_u128(amount_in, reserve_in, reserve_out, fee);
+            let wide = UniswapV2Simulator::get_amount_out_u256(
+                U256::from(amount_in),
+                U256::from(reserve_in),
+                U256::from(reserve_out),
+                U256::from(fee),
+            );
+            // uint112 inputs never need the fallback
+            prop_assert_eq!(fast.map(U256::from), wide);
+        }
+
+        #[test]
+        fn stable_swap_keeps_invariant(
+            dx in 1u64..10_000_000,
+            i in 0usize..3,
+            j in 0usize..3,
+        ) {
+            prop_assume!(i != j);
+            let (mut balances, decimals, amp, _) = three_pool();
+            let xp = StableSwapSimulator::normalize(&balances, &decimals).unwrap();
+            let d = StableSwapSimulator::get_d(&xp, amp).unwrap();
+
+            // without a fee D only moves by rounding, and never in the trader's favour
+            let dx = U256::from(dx) * U256::exp10(decimals[i] as usize);
+            let dy = StableSwapSimulator::get_dy(i, j, dx, &balances, &decimals, amp, U256::zero())
+                .unwrap();
+            balances[i] += dx;
+            balances[j] -= dy;
+            let xp = StableSwapSimulator::normalize(&balances, &decimals).unwrap();
+            let d_after = StableSwapSimulator::get_d(&xp, amp).unwrap();
+            prop_assert!(d_after + 2 >= d);
+            prop_assert!(d_after < d + U256::exp10(13));
+        }
+
+        #[test]
+        fn div_wide_matches_u256(a in any::<u128>(), b in any::<u128>(), d in 1..u128::MAX) {
+            let (hi, lo) = mul_wide(a, b);
+            let expected = U256::from(a) * U256::from(b);
+            prop_assert_eq!((U256::from(hi) << 128) + U256::from(lo), expected);
+
+            let quotient = expected / U256::from(d);
+            let expected = if quotient.bits() <= 128 { Some(quotient.as_u128()) } else { None };
+            prop_assert_eq!(div_wide(hi, lo, d), expected);
+        }
     }
 }
@@ -1,18 +1,33 @@
 use ethers::{
     providers::{Provider, Ws},
-    types::{Address, H160, U256},
+    types::{H160, U256},
 };
 use log::info;
 use std::{collections::HashMap, str::FromStr, sync::Arc};
-use tokio::sync::broadcast::Sender;
+use tokio::sync::broadcast::{error::RecvError, Sender};
 
-use crate::constants::{get_blacklist_tokens, Env, WEI};
+use crate::amounts::TokenAmount;
+use crate::batch::{sequence_orders, BatchOrder};
+use crate::bundler::{AccessListSource, Bundler, OrderParams};
+use crate::constants::Env;
+use crate::cost::CostModel;
+use crate::cycles::CycleDetector;
+use crate::execution::build_route;
+use crate::flashloan::FlashloanSelector;
+use crate::gas::{build_gas_oracle, GasOracle};
 use crate::multi::batch_get_uniswap_v2_reserves;
-use crate::paths::generate_triangular_paths;
-use crate::pools::{load_all_pools_from_v2, Pool};
-use crate::simulator::UniswapV2Simulator;
+use crate::paths::{generate_triangular_paths, ArbPath, PathIndex, MAX_HOPS};
+use crate::policy::Policy;
+use crate::pools::{
+    load_all_pools_from_v2, DexVariant, Pool, PoolId, PoolRegistry, RouterRegistry,
+};
+use crate::pricing::PriceOracle;
+use crate::runtime::{is_superseded, spawn_head_tracker, RuntimeMetrics};
+use crate::scoring::{sort_scores, PathScore, PathScorer, ReserveSnapshot, ScoringParams};
+use crate::selection::{estimate_gas_usage, select_sequential, Candidate, SelectionBudget};
 use crate::streams::Event;
-use crate::utils::get_touched_pool_reserves;
+use crate::tokens::{classify_tokens, flagged_tokens, load_token_checks, TOKEN_CHECKS_CACHE};
+use crate::utils::get_touched_pool_reserves_range;
 
 pub async fn event_handler(provider: Arc<Provider<Ws>>, event_sender: Sender<Event>) {
     /*
@@ -25,33 +40,127 @@ pub async fn event_handler(provider: Arc<Provider<Ws>>, event_sender: Sender<Eve
     let router_addresses = vec!["0xd9e1cE17f2641f24aE83637ab66a2cca9C378B9F"];
     let factory_blocks = vec![10794229u64];
 
-    let pools_vec = load_all_pools_from_v2(env.wss_url.clone(), factory_addresses, factory_blocks)
-        .await
-        .unwrap();
+    let pools_vec = load_all_pools_from_v2(
+        env.wss_url.clone(),
+        factory_addresses.clone(),
+        factory_blocks,
+    )
+    .await
+    .unwrap();
     info!("Initial pool count: {}", pools_vec.len());
 
     // Performing USDC triangular arbitrage
     let usdc_address = H160::from_str("0xA0b86991c6218b36c1d19D4a2e9Eb0cE3606eB48").unwrap();
-    let usdc_decimals = 6;
+    let usdc_decimals = 6u8;
 
-    let paths = generate_triangular_paths(&pools_vec, usdc_address);
+    // Gas is paid in the native token, which we price through the pool graph
+    let weth_address = H160::from_str("0xC02aaA39b223FE8D0A0e5C4F27eAD9083C756Cc2").unwrap();
 
-    let blacklist_tokens = get_blacklist_tokens();
+    // paths through denied tokens, pools or factories are dropped while generating
+    let mut policy = Policy::from_env(&env);
 
-    let mut pools = HashMap::new();
+    // tokens with transfer taxes, blocked sells or rebasing break the V2 math
+    let token_checks = match &env.fork_url {
+        Some(fork_url) => classify_tokens(fork_url, &pools_vec).await,
+        None => load_token_checks(TOKEN_CHECKS_CACHE),
+    };
+    match token_checks {
+        Ok(token_checks) => policy.set_flagged_tokens(flagged_tokens(&token_checks)),
+        Err(e) => info!("Error from token checks: {:?}", e),
+    }
+    // paths refer to pools by their id in the registry
+    let pool_registry = Arc::new(PoolRegistry::new(&pools_vec));
 
-    for path in &paths {
-        if !path.should_blacklist(&blacklist_tokens) {
-            pools.insert(path.pool_1.address.clone(), path.pool_1.clone());
-            pools.insert(path.pool_2.address.clone(), path.pool_2.clone());
-            pools.insert(path.pool_3.address.clone(), path.pool_3.clone());
-        }
+    // each pool swaps through the router of the factory that created it
+    let mut registry = RouterRegistry::with_known_dexes();
+    for (factory, router) in factory_addresses.iter().zip(router_addresses.iter()) {
+        let factory = H160::from_str(factory).unwrap();
+        let dex_name = registry.dex_name(&factory);
+        registry.register(&dex_name, factory, H160::from_str(router).unwrap());
     }
-    info!("New pool count: {:?}", pools.len());
 
-    let pools_vec: Vec<Pool> = pools.values().cloned().collect();
+    // paths the bot can't send aren't scored, like those through a factory without a router
+    let paths: Vec<ArbPath> = generate_triangular_paths(&pool_registry, usdc_address, &policy)
+        .into_iter()
+        .filter(|path| path.is_executable(&pool_registry, &registry))
+        .collect();
+    let path_index = PathIndex::new(&paths);
+    let paths = Arc::new(paths);
+
+    let pools_vec: Vec<Pool> = path_index
+        .pools()
+        .iter()
+        .map(|id| pool_registry[*id].clone())
+        .filter(|pool| matches!(pool.version, DexVariant::UniswapV2))
+        .collect();
+    info!("New pool count: {:?}", pools_vec.len());
+
     let mut reserves =
         batch_get_uniswap_v2_reserves(env.https_url.clone(), pools_vec.clone()).await;
+    // the same reserves indexed by PoolId, for simulating paths
+    let pool_reserves = pool_registry.dense_reserves(&reserves);
+
+    // negative cycles of up to MAX_HOPS pools, on top of the triangular paths
+    let mut cycle_detector = CycleDetector::new(&pool_registry, &pool_reserves, MAX_HOPS);
+    let initial_cycles = cycle_detector.update(&[], &pool_registry, &pool_reserves);
+    info!("Initial negative cycle count: {}", initial_cycles.len());
+
+    /*
+    Shared with the snapshots, a block's updates only copy the reserves while an older snapshot
+    still holds them. The policy is wrapped again only when the file changes.
+    */
+    let mut pool_reserves = Arc::new(pool_reserves);
+    let mut shared_policy = Arc::new(policy.clone());
+
+    let mut price_oracle = PriceOracle::new(weth_address, &pools_vec, &reserves, 3);
+    info!("Priced token count: {}", price_oracle.prices.len());
+
+    let balancer_vault = H160::from_str("0xBA12222222228d8Ba445958a75a0704d566BF2C8").unwrap();
+    let bot_address = H160::from_str(&env.bot_address).unwrap();
+    let mut flashloan_selector =
+        FlashloanSelector::new(provider.clone(), bot_address, balancer_vault);
+    if let Err(e) = flashloan_selector.update_balancer_fee().await {
+        info!("Error from update_balancer_fee: {:?}", e);
+    }
+
+    let bundler = Arc::new(Bundler::new());
+    let route = build_route(&env, bundler.clone()).unwrap();
+    info!("Execution route: {:?}", route.kind());
+
+    let gas_oracle = build_gas_oracle(&env, provider.clone());
+    let mut cost_model = CostModel::new(env.chain_id);
+
+    // touched paths are scored on a rayon pool, one thread per core
+    let path_scorer = PathScorer::new(
+        0,
+        ScoringParams {
+            spread_amount: TokenAmount::from_units(usdc_address, usdc_decimals, 1).raw,
+            max_amount_in: TokenAmount::from_units(usdc_address, usdc_decimals, 1000).raw,
+            step_size: TokenAmount::from_units(usdc_address, usdc_decimals, 10).raw,
+        },
+    )
+    .unwrap();
+
+    // up to three orders in one submission, borrowing at most 3000 USDC between them
+    let selection_budget = SelectionBudget {
+        max_paths: 3,
+        max_gas: 2_000_000,
+        max_capital: TokenAmount::from_units(usdc_address, usdc_decimals, 3000).raw,
+    };
+
+    /*
+    The head tracker moves latest_block as soon as a block is announced.
+    A block older than that is skipped, and work on it stops at the next checkpoint,
+    the scorer stops on its own since it shares latest_block.
+    */
+    let metrics = Arc::new(RuntimeMetrics::default());
+    let latest_block = path_scorer.latest_block.clone();
+    spawn_head_tracker(&event_sender, latest_block.clone(), metrics.clone());
+
+    // first block whose Sync logs haven't been applied, skipped blocks are caught up in one range
+    let mut next_sync_block = None;
+    // pools touched by aborted blocks, their paths are scored with the next block
+    let mut unscored_pools: Vec<PoolId> = Vec::new();
 
     let mut event_receiver = event_sender.subscribe();
 
@@ -60,86 +169,292 @@ pub async fn event_handler(provider: Arc<Provider<Ws>>, event_sender: Sender<Eve
             Ok(event) => match event {
                 Event::Block(block) => {
                     info!("{:?}", block);
-                    let touched_reserves =
-                        match get_touched_pool_reserves(provider.clone(), block.block_number).await
-                        {
-                            Ok(response) => response,
-                            Err(e) => {
-                                info!("Error from get_touched_pool_reserves: {:?}", e);
-                                HashMap::new()
-                            }
-                        };
-                    let mut touched_pools = Vec::new();
+                    if is_superseded(&latest_block, block.block_number) {
+                        info!("Skipping block {}, a newer one arrived", block.block_number);
+                        metrics.block_skipped();
+                        continue;
+                    }
+
+                    let from_block = next_sync_block.unwrap_or(block.block_number);
+                    let touched_reserves = match get_touched_pool_reserves_range(
+                        provider.clone(),
+                        from_block,
+                        block.block_number,
+                    )
+                    .await
+                    {
+                        Ok(response) => {
+                            next_sync_block = Some(block.block_number + 1);
+                            response
+                        }
+                        Err(e) => {
+                            info!("Error from get_touched_pool_reserves: {:?}", e);
+                            HashMap::new()
+                        }
+                    };
+                    let mut touched_pools = std::mem::take(&mut unscored_pools);
                     for (address, reserve) in touched_reserves.into_iter() {
                         if reserves.contains_key(&address) {
+                            if let Some(id) = pool_registry.id(&address) {
+                                Arc::make_mut(&mut pool_reserves)[id.index()] = reserve.clone();
+                                touched_pools.push(id);
+                            }
                             reserves.insert(address, reserve);
-                            touched_pools.push(address);
                         }
                     }
+                    touched_pools.sort();
+                    touched_pools.dedup();
                     info!("{:?}", touched_pools);
 
-                    let mut spreads = HashMap::new();
-                    for (idx, path) in (&paths).iter().enumerate() {
-                        let touched_path = touched_pools
-                            .iter()
-                            .map(|pool| path.has_pool(&pool) as i32)
-                            .sum::<i32>()
-                            >= 1;
-
-                        if touched_path {
-                            let one_token_in = U256::from(1);
-                            let simulated = path.simulate_v2_path(one_token_in, &reserves);
-
-                            match simulated {
-                                Some(price_quote) => {
-                                    let one_usdc_in = one_token_in * U256::from(usdc_decimals);
-                                    let _out = price_quote.as_u128() as i128;
-                                    let _in = one_usdc_in.as_u128() as i128;
-                                    let spread = _out - _in;
-
-                                    if spread > 0 {
-                                        spreads.insert(idx, spread);
-                                    }
-                                }
-                                None => {}
-                            }
+                    // the reserves are applied either way, the next block starts from them
+                    if is_superseded(&latest_block, block.block_number) {
+                        metrics.block_aborted();
+                        unscored_pools = touched_pools;
+                        continue;
+                    }
+
+                    match policy.reload_if_changed() {
+                        Ok(true) => {
+                            info!("Reloaded policy: {:?}", policy.rules);
+                            shared_policy = Arc::new(policy.clone());
                         }
+                        Ok(false) => {}
+                        Err(e) => info!("Error reloading policy: {:?}", e),
                     }
 
-                    let usdc_weth_address =
-                        Address::from_str("0x397FF1542f962076d0BFE58eA045FfA2d347ACa0").unwrap();
-                    let pool = pools.get(&usdc_weth_address).unwrap();
-                    let reserve = reserves.get(&usdc_weth_address).unwrap();
-                    let weth_price = UniswapV2Simulator::reserves_to_price(
-                        reserve.reserve0,
-                        reserve.reserve1,
-                        pool.decimals0,
-                        pool.decimals1,
-                        false,
+                    let touched_paths = path_index.touched_paths(&touched_pools);
+                    info!("Touched path count: {}", touched_paths.len());
+
+                    // cycles the triangular paths already cover are scored with them
+                    let cycles =
+                        cycle_detector.update(&touched_pools, &pool_registry, &pool_reserves);
+                    let cycle_paths: Vec<ArbPath> = cycles
+                        .iter()
+                        .filter_map(|cycle| cycle_detector.to_arb_path(cycle, &usdc_address))
+                        .filter(|path| path.is_executable(&pool_registry, &registry))
+                        .filter(|path| {
+                            !path_index
+                                .paths_for_pool(path._get_pool(0))
+                                .iter()
+                                .any(|path_idx| paths[*path_idx] == *path)
+                        })
+                        .collect();
+                    info!(
+                        "Negative cycle count: {}, new paths: {}",
+                        cycles.len(),
+                        cycle_paths.len()
                     );
 
+                    let snapshot = ReserveSnapshot {
+                        block_number: block.block_number.as_u64(),
+                        paths: paths.clone(),
+                        pools: pool_registry.clone(),
+                        reserves: pool_reserves.clone(),
+                        policy: shared_policy.clone(),
+                    };
+                    let cycle_snapshot = ReserveSnapshot {
+                        paths: Arc::new(cycle_paths),
+                        ..snapshot.clone()
+                    };
+                    let cycle_ids = (0..cycle_snapshot.paths.len()).collect();
+                    let mut scores = match path_scorer.evaluate(snapshot, touched_paths).await {
+                        Some(scores) => scores,
+                        None => {
+                            info!("Block {} superseded while scoring", block.block_number);
+                            metrics.block_aborted();
+                            unscored_pools = touched_pools;
+                            continue;
+                        }
+                    };
+                    match path_scorer.evaluate(cycle_snapshot, cycle_ids).await {
+                        // cycle ids come after the triangular path ids, so they never collide
+                        Some(cycle_scores) => {
+                            scores.extend(cycle_scores.into_iter().map(|score| PathScore {
+                                path_id: paths.len() + score.path_id,
+                                ..score
+                            }))
+                        }
+                        None => {
+                            info!("Block {} superseded while scoring", block.block_number);
+                            metrics.block_aborted();
+                            unscored_pools = touched_pools;
+                            continue;
+                        }
+                    }
+                    sort_scores(&mut scores);
+                    metrics.block_processed();
+                    info!("Runtime metrics: {}", metrics.snapshot());
+
+                    price_oracle.refresh(&pools_vec, &reserves);
+                    if scores.is_empty() {
+                        continue;
+                    }
+
+                    // fees are only looked up for blocks with something to price, all at once
+                    let (priority_fee, l1_fee_update) =
+                        tokio::join!(gas_oracle.priority_fee(&block), async {
+                            if cost_model.has_l1_fee() {
+                                cost_model.update(provider.clone()).await
+                            } else {
+                                Ok(())
+                            }
+                        });
+                    if let Err(e) = l1_fee_update {
+                        info!("Error from cost_model.update: {:?}", e);
+                    }
+
                     let base_fee = block.next_base_fee;
-                    let estimated_gas_usage = U256::from(550000);
-                    let gas_cost_in_wei = base_fee * estimated_gas_usage;
-                    let gas_cost_in_wmatic =
-                        (gas_cost_in_wei.as_u64() as f64) / ((*WEI).as_u64() as f64);
-                    let gas_cost_in_usdc = weth_price * gas_cost_in_wmatic;
-                    let gas_cost_in_usdc =
-                        U256::from((gas_cost_in_usdc * ((10 as f64).powi(usdc_decimals))) as u64);
-
-                    let mut sorted_spreads: Vec<_> = spreads.iter().collect();
-                    sorted_spreads.sort_by_key(|x| x.1);
-                    sorted_spreads.reverse();
-
-                    for spread in sorted_spreads {
-                        let path_idx = spread.0;
-                        let path = &paths[*path_idx];
-                        let opt = path.optimize_amount_in(U256::from(1000), 10, &reserves);
-                        let excess_profit =
-                            (opt.1.as_u128() as i128) - (gas_cost_in_usdc.as_u128() as i128);
-
-                        // TODO
-                        if excess_profit > 0 {}
+                    let max_priority_fee_per_gas = match priority_fee {
+                        Ok(priority_fee) => priority_fee,
+                        Err(e) => {
+                            info!("Error from gas oracle: {:?}", e);
+                            continue;
+                        }
+                    };
+
+                    let gas_price = base_fee + max_priority_fee_per_gas;
+                    let mut candidates = Vec::new();
+                    for score in scores {
+                        let gas_cost_in_wei = gas_price * estimate_gas_usage(&score.path);
+                        match price_oracle.convert(&weth_address, &usdc_address, gas_cost_in_wei) {
+                            Some(cost) => candidates.push(Candidate::new(score, cost)),
+                            None => {
+                                info!("No price route from WETH to USDC");
+                                break;
+                            }
+                        }
+                    }
+
+                    // paths sharing pools are re-sized on the reserves the ones before them leave
+                    let selected = select_sequential(
+                        &candidates,
+                        &pool_registry,
+                        &pool_reserves,
+                        &path_scorer.params,
+                        &selection_budget,
+                    );
+                    info!("Selected {} of {} paths", selected.len(), candidates.len());
+
+                    // loans are picked on the reserves the orders before leave, away from their pools
+                    let mut orders = Vec::new();
+                    let mut bundle_reserves = pool_reserves.to_vec();
+                    let mut bundle_pools = Vec::new();
+                    for candidate in selected {
+                        let score = candidate.score;
+                        let path_idx = score.path_id;
+                        let path = &score.path;
+
+                        let loan = match flashloan_selector
+                            .select(
+                                path,
+                                &score.amount_in,
+                                &pool_registry,
+                                &bundle_reserves,
+                                &bundle_pools,
+                            )
+                            .await
+                        {
+                            Ok(Some(loan)) => loan,
+                            Ok(None) => {
+                                info!("No loan source for path {}", path_idx);
+                                continue;
+                            }
+                            Err(e) => {
+                                info!("Error from flashloan_selector: {:?}", e);
+                                continue;
+                            }
+                        };
+                        let path_params = match path.to_path_params(&pool_registry, &registry) {
+                            Some(path_params) => path_params,
+                            None => {
+                                info!("No router for a pool in path {}", path_idx);
+                                continue;
+                            }
+                        };
+                        // the loan fee comes out of the same token as the profit
+                        let order = BatchOrder::new(candidate, loan, path_params);
+                        if !order.excess_profit().is_positive() {
+                            continue;
+                        }
+                        if order.apply(&pool_registry, &mut bundle_reserves).is_none() {
+                            continue;
+                        }
+                        bundle_pools.extend(order.pools(&pool_registry));
+                        info!(
+                            "Path {} excess profit: {} USDC",
+                            path_idx,
+                            order.excess_profit()
+                        );
+                        orders.push(order);
+                    }
+
+                    /*
+                    Orders take consecutive nonces and each runs on the reserves the ones before it leave.
+                    If the L1 fee makes one unprofitable, the rest are sequenced and signed again.
+                    */
+                    let max_fee_per_gas = base_fee + max_priority_fee_per_gas;
+                    let mut orders = sequence_orders(orders, &pool_registry, &pool_reserves);
+                    let signed_txs = loop {
+                        let order_params: Vec<OrderParams> =
+                            orders.iter().map(|order| order.to_order_params()).collect();
+                        let signed_txs = match bundler
+                            .sign_orders(
+                                &order_params,
+                                AccessListSource::NotUsed,
+                                max_priority_fee_per_gas,
+                                max_fee_per_gas,
+                            )
+                            .await
+                        {
+                            Ok(signed_txs) => signed_txs,
+                            Err(e) => {
+                                info!("Error from sign_orders: {:?}", e);
+                                break Vec::new();
+                            }
+                        };
+                        if !cost_model.has_l1_fee() {
+                            break signed_txs;
+                        }
+
+                        // on rollups, posting the signed orders to L1 costs on top of execution
+                        let kept: Vec<BatchOrder> = orders
+                            .iter()
+                            .zip(signed_txs.iter())
+                            .filter(|(order, signed_tx)| {
+                                let total_cost_in_wei = cost_model.gas_cost(
+                                    U256::from(order.candidate.gas_usage),
+                                    max_fee_per_gas,
+                                    signed_tx,
+                                );
+                                let total_cost_in_usdc = price_oracle.convert(
+                                    &weth_address,
+                                    &usdc_address,
+                                    total_cost_in_wei,
+                                );
+                                matches!(total_cost_in_usdc,
+                                    Some(cost) if order.profit.sub_cost(cost).is_positive())
+                            })
+                            .map(|(order, _)| order.clone())
+                            .collect();
+                        if kept.len() == orders.len() {
+                            break signed_txs;
+                        }
+                        orders = sequence_orders(kept, &pool_registry, &pool_reserves);
+                    };
+
+                    if signed_txs.is_empty() {
+                        continue;
+                    }
+                    // an order for a superseded block would land on different reserves,
+                    // the block was scored already so it stays counted as processed
+                    if is_superseded(&latest_block, block.block_number) {
+                        info!("Block {} superseded before submission", block.block_number);
+                        continue;
+                    }
+
+                    match route.submit(signed_txs, block.block_number).await {
+                        Ok(hashes) => info!("{:?} submission: {:?}", route.kind(), hashes),
+                        Err(e) => info!("{:?} submission error: {:?}", route.kind(), e),
                     }
                 }
                 Event::PendingTx(_) => {
@@ -148,8 +463,18 @@ pub async fn event_handler(provider: Arc<Provider<Ws>>, event_sender: Sender<Eve
                 Event::Log(_) => {
                     // not using logs
                 }
+                Event::MevShare(_) => {
+                    // not using mev-share hints
+                }
             },
-            Err(_) => {}
+            Err(RecvError::Lagged(missed)) => {
+                info!("Strategy lagged behind by {} events", missed);
+                metrics.record_lag(missed);
+            }
+            Err(RecvError::Closed) => {
+                info!("Event channel closed, metrics: {}", metrics.snapshot());
+                break;
+            }
         }
     }
 }
@@ -7,13 +7,15 @@ use std::sync::Arc;
 use tokio::sync::broadcast::Sender;
 use tokio_stream::StreamExt;
 
-use crate::utils::calculate_next_block_base_fee;
+use crate::mev_share::{MevShareHint, SseParser};
+use crate::utils::project_base_fees;
 
 #[derive(Default, Debug, Clone)]
 pub struct NewBlock {
     pub block_number: U64,
     pub base_fee: U256,
     pub next_base_fee: U256,
+    pub projected_base_fees: Vec<U256>,
 }
 
 #[derive(Debug, Clone)]
@@ -21,20 +23,24 @@ pub enum Event {
     Block(NewBlock),
     PendingTx(Transaction),
     Log(Log),
+    MevShare(MevShareHint),
 }
 
 pub async fn stream_new_blocks(provider: Arc<Provider<Ws>>, event_sender: Sender<Event>) {
     let stream = provider.subscribe_blocks().await.unwrap();
     let mut stream = stream.filter_map(|block| match block.number {
-        Some(number) => Some(NewBlock {
-            block_number: number,
-            base_fee: block.base_fee_per_gas.unwrap_or_default(),
-            next_base_fee: U256::from(calculate_next_block_base_fee(
-                block.gas_used,
-                block.gas_limit,
-                block.base_fee_per_gas.unwrap_or_default(),
-            )),
-        }),
+        Some(number) => {
+            let base_fee = block.base_fee_per_gas.unwrap_or_default();
+            // project under the assumption that the parent's usage keeps up
+            let projected_base_fees =
+                project_base_fees(block.gas_used, block.gas_limit, base_fee, block.gas_used, 3);
+            Some(NewBlock {
+                block_number: number,
+                base_fee,
+                next_base_fee: projected_base_fees[0],
+                projected_base_fees,
+            })
+        }
         None => None,
     });
 
@@ -72,3 +78,26 @@ pub async fn stream_uniswap_v2_events(provider: Arc<Provider<Ws>>, event_sender:
         };
     }
 }
+
+pub async fn stream_mev_share_hints(sse_url: String, event_sender: Sender<Event>) {
+    let client = reqwest::Client::new();
+    let mut response = client
+        .get(sse_url)
+        .header("Accept", "text/event-stream")
+        .send()
+        .await
+        .unwrap();
+    let mut parser = SseParser::new();
+
+    while let Ok(Some(chunk)) = response.chunk().await {
+        for data in parser.feed(&chunk) {
+            match serde_json::from_str::<MevShareHint>(&data) {
+                Ok(hint) => match event_sender.send(Event::MevShare(hint)) {
+                    Ok(_) => {}
+                    Err(_) => {}
+                },
+                Err(_) => {}
+            };
+        }
+    }
+}
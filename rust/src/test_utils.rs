@@ -0,0 +1,54 @@
+use ethers::types::{H160, U256};
+
+use crate::multi::Reserve;
+use crate::pools::{DexVariant, Pool};
+
+// Fixtures shared by the unit tests
+
+// Token i, never the zero address
+pub fn token(i: u64) -> H160 {
+    H160::from_low_u64_be(i + 1)
+}
+
+// An 18 decimal Uniswap V2 pair at 0.3%, addresses start at 1000 so they don't collide with tokens
+pub fn pool(address: u64, token0: H160, token1: H160) -> Pool {
+    Pool {
+        address: H160::from_low_u64_be(1000 + address),
+        version: DexVariant::UniswapV2,
+        token0,
+        token1,
+        decimals0: 18,
+        decimals1: 18,
+        fee: 300,
+        factory: H160::zero(),
+        dex: "uniswap_v2".to_string(),
+    }
+}
+
+/*
+Pools 0, 1 and 2 are usdc/weth, weth/other and usdc/other.
+weth is cheap in the first pool, so usdc -> weth -> other -> usdc is profitable.
+*/
+pub fn triangle(usdc: H160, weth: H160, other: H160) -> (Vec<Pool>, Vec<Reserve>) {
+    let e18 = U256::exp10(18);
+    let pools = vec![
+        pool(0, usdc, weth),
+        pool(1, weth, other),
+        pool(2, usdc, other),
+    ];
+    let reserves = vec![
+        Reserve {
+            reserve0: U256::from(2_000_000u64) * e18,
+            reserve1: U256::from(1_000u64) * e18,
+        },
+        Reserve {
+            reserve0: U256::from(1_000u64) * e18,
+            reserve1: U256::from(2_100_000u64) * e18,
+        },
+        Reserve {
+            reserve0: U256::from(2_000_000u64) * e18,
+            reserve1: U256::from(2_000_000u64) * e18,
+        },
+    ];
+    (pools, reserves)
+}
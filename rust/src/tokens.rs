@@ -0,0 +1,501 @@
+use anyhow::{anyhow, Result};
+use csv::StringRecord;
+use ethers::{
+    prelude::abigen,
+    providers::{Http, Provider},
+    types::{Address, Bytes, H160, U256},
+};
+use log::info;
+use std::{collections::HashMap, path::Path, str::FromStr, sync::Arc, time::Instant};
+
+use crate::pools::{DexVariant, Pool};
+use crate::simulator::UniswapV2Simulator;
+
+abigen!(
+    CheckedToken,
+    r#"[
+        function balanceOf(address account) external view returns (uint256)
+        function transfer(address to, uint256 amount) external returns (bool)
+    ]"#,
+);
+
+abigen!(
+    CheckedPair,
+    r#"[
+        function getReserves() external view returns (uint112, uint112, uint32)
+        function swap(uint256 amount0Out, uint256 amount1Out, address to, bytes data) external
+        function sync() external
+    ]"#,
+);
+
+pub static TOKEN_CHECKS_CACHE: &str = "src/.cached-token-checks.csv";
+
+// throwaway accounts on the fork, they only exist there
+pub static PROBE_ADDRESS: &str = "0x00000000000000000000000000000000000b0b01";
+pub static RECEIVER_ADDRESS: &str = "0x00000000000000000000000000000000000b0b02";
+
+/*
+What a token did when moved around on a local fork.
+Taxes are in basis points of the amount sent:
+buy is the pair paying out, transfer is wallet to wallet, sell is a wallet paying the pair.
+transfer_blocked was added to the cache later, rows without it read as false.
+*/
+#[derive(Default, Debug, Clone, PartialEq, Eq)]
+pub struct TokenCheck {
+    pub token: H160,
+    pub buy_tax_bps: u32,
+    pub transfer_tax_bps: u32,
+    pub sell_tax_bps: u32,
+    pub buy_blocked: bool,
+    pub sell_blocked: bool,
+    pub rebasing: bool,
+    pub transfer_blocked: bool,
+}
+
+impl TokenCheck {
+    // Anything that breaks the V2 math the simulator assumes
+    pub fn is_flagged(&self) -> bool {
+        self.buy_tax_bps > 0
+            || self.transfer_tax_bps > 0
+            || self.sell_tax_bps > 0
+            || self.buy_blocked
+            || self.sell_blocked
+            || self.rebasing
+            || self.transfer_blocked
+    }
+
+    pub fn cache_row(&self) -> (String, u32, u32, u32, bool, bool, bool, bool) {
+        (
+            format!("{:?}", self.token),
+            self.buy_tax_bps,
+            self.transfer_tax_bps,
+            self.sell_tax_bps,
+            self.buy_blocked,
+            self.sell_blocked,
+            self.rebasing,
+            self.transfer_blocked,
+        )
+    }
+}
+
+impl From<StringRecord> for TokenCheck {
+    fn from(record: StringRecord) -> Self {
+        Self {
+            token: H160::from_str(record.get(0).unwrap()).unwrap(),
+            buy_tax_bps: record.get(1).unwrap().parse().unwrap(),
+            transfer_tax_bps: record.get(2).unwrap().parse().unwrap(),
+            sell_tax_bps: record.get(3).unwrap().parse().unwrap(),
+            buy_blocked: record.get(4).unwrap().parse().unwrap(),
+            sell_blocked: record.get(5).unwrap().parse().unwrap(),
+            rebasing: record.get(6).unwrap().parse().unwrap(),
+            transfer_blocked: record
+                .get(7)
+                .map(|value| value.parse().unwrap())
+                .unwrap_or_default(),
+        }
+    }
+}
+
+// The share of sent that didn't arrive, in basis points
+pub fn tax_bps(sent: U256, received: U256) -> u32 {
+    if sent.is_zero() || received >= sent {
+        return 0;
+    }
+    ((sent - received) * U256::from(10000) / sent).as_u32()
+}
+
+pub fn load_token_checks<P: AsRef<Path>>(file_path: P) -> Result<HashMap<H160, TokenCheck>> {
+    let mut checks = HashMap::new();
+    if !file_path.as_ref().exists() {
+        return Ok(checks);
+    }
+    let mut reader = csv::Reader::from_path(file_path)?;
+    for row in reader.records() {
+        let check = TokenCheck::from(row?);
+        checks.insert(check.token, check);
+    }
+    Ok(checks)
+}
+
+pub fn save_token_checks<P: AsRef<Path>>(
+    file_path: P,
+    checks: &HashMap<H160, TokenCheck>,
+) -> Result<()> {
+    let mut writer = csv::Writer::from_path(file_path)?;
+    writer.write_record(&[
+        "token",
+        "buy_tax_bps",
+        "transfer_tax_bps",
+        "sell_tax_bps",
+        "buy_blocked",
+        "sell_blocked",
+        "rebasing",
+        "transfer_blocked",
+    ])?;
+    for check in checks.values() {
+        writer.serialize(check.cache_row())?;
+    }
+    writer.flush()?;
+    Ok(())
+}
+
+pub fn flagged_tokens(checks: &HashMap<H160, TokenCheck>) -> Vec<H160> {
+    checks
+        .values()
+        .filter(|check| check.is_flagged())
+        .map(|check| check.token)
+        .collect()
+}
+
+/*
+Runs each token through a local fork (e.g. anvil --fork-url $HTTPS_URL).
+Every check is wrapped in an evm_snapshot/evm_revert so tokens can't affect each other,
+and the pair and our accounts are impersonated so no keys are needed.
+*/
+pub struct TokenClassifier {
+    pub provider: Arc<Provider<Http>>,
+    pub probe: Address,
+    pub receiver: Address,
+}
+
+impl TokenClassifier {
+    pub fn new(fork_url: &str) -> Result<Self> {
+        let provider = Provider::<Http>::try_from(fork_url)?;
+        Ok(Self {
+            provider: Arc::new(provider),
+            probe: Address::from_str(PROBE_ADDRESS).unwrap(),
+            receiver: Address::from_str(RECEIVER_ADDRESS).unwrap(),
+        })
+    }
+
+    async fn impersonate(&self, account: Address) -> Result<()> {
+        let ten_eth = U256::from(10).pow(U256::from(19));
+        self.provider
+            .request::<_, ()>("anvil_setBalance", (account, ten_eth))
+            .await?;
+        self.provider
+            .request::<_, ()>("anvil_impersonateAccount", [account])
+            .await?;
+        Ok(())
+    }
+
+    // (token reserve, other reserve)
+    async fn get_reserves(
+        &self,
+        pair: &CheckedPair<Provider<Http>>,
+        token_is_0: bool,
+    ) -> Result<(U256, U256)> {
+        let (reserve0, reserve1, _) = pair.get_reserves().call().await?;
+        if token_is_0 {
+            Ok((U256::from(reserve0), U256::from(reserve1)))
+        } else {
+            Ok((U256::from(reserve1), U256::from(reserve0)))
+        }
+    }
+
+    async fn balance_of(&self, token: &CheckedToken<Provider<Http>>, account: Address) -> U256 {
+        token.balance_of(account).call().await.unwrap_or_default()
+    }
+
+    // true if the transfer went through, a revert or failed receipt means it's blocked
+    async fn transfer(
+        &self,
+        token: &CheckedToken<Provider<Http>>,
+        from: Address,
+        to: Address,
+        amount: U256,
+    ) -> bool {
+        let call = token.transfer(to, amount).from(from).gas(1_000_000u64);
+        let pending = match call.send().await {
+            Ok(pending) => pending,
+            Err(_) => return false,
+        };
+        matches!(pending.await, Ok(Some(receipt)) if receipt.status == Some(1u64.into()))
+    }
+
+    // true if the swap to the probe went through
+    async fn swap(
+        &self,
+        pair: &CheckedPair<Provider<Http>>,
+        amount0_out: U256,
+        amount1_out: U256,
+    ) -> bool {
+        let call = pair
+            .swap(amount0_out, amount1_out, self.probe, Bytes::new())
+            .from(self.probe)
+            .gas(1_000_000u64);
+        let pending = match call.send().await {
+            Ok(pending) => pending,
+            Err(_) => return false,
+        };
+        matches!(pending.await, Ok(Some(receipt)) if receipt.status == Some(1u64.into()))
+    }
+
+    pub async fn classify(&self, token_address: H160, pool: &Pool) -> Result<TokenCheck> {
+        let snapshot: U256 = self.provider.request("evm_snapshot", ()).await?;
+        let check = self.run_checks(token_address, pool).await;
+        self.provider
+            .request::<_, bool>("evm_revert", [snapshot])
+            .await?;
+        check
+    }
+
+    async fn run_checks(&self, token_address: H160, pool: &Pool) -> Result<TokenCheck> {
+        let token = CheckedToken::new(token_address, self.provider.clone());
+        let pair = CheckedPair::new(pool.address, self.provider.clone());
+        let mut check = TokenCheck {
+            token: token_address,
+            ..Default::default()
+        };
+
+        let token_is_0 = pool.token0 == token_address;
+        let other_address = if token_is_0 { pool.token1 } else { pool.token0 };
+        let other = CheckedToken::new(other_address, self.provider.clone());
+        let (reserve_token, reserve_other) = self.get_reserves(&pair, token_is_0).await?;
+        // 1% of the pool is enough to see a tax without moving the price too far
+        let amount_in = reserve_other / U256::from(100);
+        if amount_in.is_zero() || reserve_token.is_zero() {
+            return Err(anyhow!(
+                "{:?} has no liquidity in {:?}",
+                token_address,
+                pool.address
+            ));
+        }
+
+        for account in [pool.address, self.probe, self.receiver] {
+            self.impersonate(account).await?;
+        }
+
+        /*
+        buy: a real swap, so taxes that only hit swaps show up too.
+        The probe is funded out of the pair's own balance of the other token,
+        the pair is synced, and the probe pays it back as the swap's input.
+        */
+        if !self
+            .transfer(&other, pool.address, self.probe, amount_in)
+            .await
+        {
+            return Err(anyhow!("Could not fund the probe with {:?}", other_address));
+        }
+        pair.sync().from(pool.address).send().await?.await?;
+        if !self
+            .transfer(&other, self.probe, pool.address, amount_in)
+            .await
+        {
+            return Err(anyhow!("Could not pay {:?} to the pair", other_address));
+        }
+        let (reserve_token, reserve_other) = self.get_reserves(&pair, token_is_0).await?;
+        let paid = self
+            .balance_of(&other, pool.address)
+            .await
+            .checked_sub(reserve_other)
+            .unwrap_or_default();
+        let amount_out = UniswapV2Simulator::get_amount_out(
+            paid,
+            reserve_other,
+            reserve_token,
+            U256::from(pool.fee),
+        )
+        .unwrap_or_default();
+        let (amount0_out, amount1_out) = if token_is_0 {
+            (amount_out, U256::zero())
+        } else {
+            (U256::zero(), amount_out)
+        };
+        if amount_out.is_zero() || !self.swap(&pair, amount0_out, amount1_out).await {
+            check.buy_blocked = true;
+            return Ok(check);
+        }
+        let bought = self.balance_of(&token, self.probe).await;
+        check.buy_tax_bps = tax_bps(amount_out, bought);
+
+        // wallet to wallet
+        let sent = bought / U256::from(2);
+        let receiver_before = self.balance_of(&token, self.receiver).await;
+        if self.transfer(&token, self.probe, self.receiver, sent).await {
+            // a balance that shrinks on receiving is rebasing too
+            match self
+                .balance_of(&token, self.receiver)
+                .await
+                .checked_sub(receiver_before)
+            {
+                Some(received) => {
+                    check.transfer_tax_bps = tax_bps(sent, received);
+                    if received > sent {
+                        check.rebasing = true;
+                    }
+                }
+                None => {
+                    check.transfer_tax_bps = tax_bps(sent, U256::zero());
+                    check.rebasing = true;
+                }
+            }
+        } else {
+            check.transfer_blocked = true;
+        }
+
+        // balances that move a day later without any transfer
+        let held = self.balance_of(&token, self.receiver).await;
+        self.provider
+            .request::<_, U256>("evm_increaseTime", [86400u64])
+            .await?;
+        self.provider.request::<_, String>("evm_mine", ()).await?;
+        if self.balance_of(&token, self.receiver).await != held {
+            check.rebasing = true;
+        }
+
+        // sell: pay the pair, then swap out what actually arrived
+        let selling = self.balance_of(&token, self.probe).await;
+        let pair_before = self.balance_of(&token, pool.address).await;
+        if !self
+            .transfer(&token, self.probe, pool.address, selling)
+            .await
+        {
+            check.sell_blocked = true;
+            return Ok(check);
+        }
+        let arrived = match self
+            .balance_of(&token, pool.address)
+            .await
+            .checked_sub(pair_before)
+        {
+            Some(arrived) => arrived,
+            None => {
+                check.sell_tax_bps = tax_bps(selling, U256::zero());
+                check.rebasing = true;
+                return Ok(check);
+            }
+        };
+        check.sell_tax_bps = tax_bps(selling, arrived);
+
+        let (reserve_token, reserve_other) = self.get_reserves(&pair, token_is_0).await?;
+        let amount_out = UniswapV2Simulator::get_amount_out(
+            arrived,
+            reserve_token,
+            reserve_other,
+            U256::from(pool.fee),
+        )
+        .unwrap_or_default();
+        let (amount0_out, amount1_out) = if token_is_0 {
+            (U256::zero(), amount_out)
+        } else {
+            (amount_out, U256::zero())
+        };
+        if amount_out.is_zero() || !self.swap(&pair, amount0_out, amount1_out).await {
+            check.sell_blocked = true;
+        }
+
+        Ok(check)
+    }
+}
+
+/*
+Checks every token in the pool set that isn't in the cache yet, then saves the cache.
+Tokens that couldn't be tested (RPC errors, empty pools) are left out and tried again next run.
+*/
+pub async fn classify_tokens(
+    fork_url: &str,
+    pools: &Vec<Pool>,
+) -> Result<HashMap<H160, TokenCheck>> {
+    let start_time = Instant::now();
+    let mut checks = load_token_checks(TOKEN_CHECKS_CACHE)?;
+    let classifier = TokenClassifier::new(fork_url)?;
+
+    // one V2 pool per token to trade against
+    let mut token_pools: HashMap<H160, &Pool> = HashMap::new();
+    for pool in pools {
+        if !matches!(pool.version, DexVariant::UniswapV2) {
+            continue;
+        }
+        for token in [pool.token0, pool.token1] {
+            if !checks.contains_key(&token) {
+                token_pools.entry(token).or_insert(pool);
+            }
+        }
+    }
+    info!("Checking {} new tokens on the fork", token_pools.len());
+
+    for (token, pool) in token_pools {
+        match classifier.classify(token, pool).await {
+            Ok(check) => {
+                checks.insert(token, check);
+            }
+            Err(e) => info!("Could not check {:?}: {:?}", token, e),
+        }
+    }
+
+    save_token_checks(TOKEN_CHECKS_CACHE, &checks)?;
+    info!(
+        "Token checks took {} seconds, {} tokens flagged",
+        start_time.elapsed().as_secs(),
+        flagged_tokens(&checks).len()
+    );
+    Ok(checks)
+}
+
+#[cfg(test)]
+mod tokens_tests {
+    use super::*;
+
+    #[test]
+    fn tax_bps_test() {
+        assert_eq!(tax_bps(U256::from(1000), U256::from(1000)), 0);
+        assert_eq!(tax_bps(U256::from(1000), U256::from(950)), 500);
+        assert_eq!(tax_bps(U256::from(1000), U256::from(1200)), 0);
+        assert_eq!(tax_bps(U256::zero(), U256::zero()), 0);
+        assert_eq!(tax_bps(U256::from(1000), U256::zero()), 10000);
+    }
+
+    #[test]
+    fn token_checks_cache_test() {
+        let clean = TokenCheck {
+            token: H160::from_low_u64_be(1),
+            ..Default::default()
+        };
+        let taxed = TokenCheck {
+            token: H160::from_low_u64_be(2),
+            sell_tax_bps: 1000,
+            ..Default::default()
+        };
+        let honeypot = TokenCheck {
+            token: H160::from_low_u64_be(3),
+            sell_blocked: true,
+            ..Default::default()
+        };
+        let stuck = TokenCheck {
+            token: H160::from_low_u64_be(4),
+            transfer_blocked: true,
+            ..Default::default()
+        };
+        assert!(!clean.is_flagged());
+
+        let mut checks = HashMap::new();
+        for check in [clean, taxed.clone(), honeypot.clone(), stuck.clone()] {
+            checks.insert(check.token, check);
+        }
+
+        let file_path =
+            std::env::temp_dir().join(format!("token-checks-{}.csv", std::process::id()));
+        save_token_checks(&file_path, &checks).unwrap();
+        let loaded = load_token_checks(&file_path).unwrap();
+        std::fs::remove_file(&file_path).unwrap();
+
+        assert_eq!(loaded, checks);
+        let mut flagged = flagged_tokens(&loaded);
+        flagged.sort();
+        assert_eq!(flagged, vec![taxed.token, honeypot.token, stuck.token]);
+
+        // caches written before transfer_blocked existed still load
+        let file_path =
+            std::env::temp_dir().join(format!("token-checks-old-{}.csv", std::process::id()));
+        std::fs::write(
+            &file_path,
+            "token,buy_tax_bps,transfer_tax_bps,sell_tax_bps,buy_blocked,sell_blocked,rebasing\n\
+             0x0000000000000000000000000000000000000002,0,0,1000,false,false,false\n",
+        )
+        .unwrap();
+        let loaded = load_token_checks(&file_path).unwrap();
+        std::fs::remove_file(&file_path).unwrap();
+        assert_eq!(loaded[&taxed.token], taxed);
+    }
+}
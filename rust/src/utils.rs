@@ -3,11 +3,10 @@ use ethers::{
     self,
     abi::{decode, ParamType, Token},
     providers::{Middleware, Provider, Ws},
-    types::{Filter, H160, U256, U64},
+    types::{BlockNumber, FeeHistory, Filter, H160, U256, U64},
 };
 use fern::colors::{Color, ColoredLevelConfig};
 use log::LevelFilter;
-use rand::Rng;
 use std::{collections::HashMap, sync::Arc};
 
 use crate::multi::Reserve;
@@ -39,48 +38,120 @@ pub fn setup_logger() -> Result<()> {
     Ok(())
 }
 
+// EIP-1559 constants
+static ELASTICITY_MULTIPLIER: u64 = 2;
+static BASE_FEE_MAX_CHANGE_DENOMINATOR: u64 = 8;
+
+pub static PRIORITY_FEE_PERCENTILES: [f64; 4] = [25.0, 50.0, 75.0, 90.0];
+
 pub fn calculate_next_block_base_fee(
     gas_used: U256,
     gas_limit: U256,
     base_fee_per_gas: U256,
 ) -> U256 {
-    let gas_used = gas_used;
+    let gas_target = gas_limit / ELASTICITY_MULTIPLIER;
+    if gas_target.is_zero() || gas_used == gas_target {
+        return base_fee_per_gas;
+    }
 
-    let mut target_gas_used = gas_limit / 2;
-    target_gas_used = if target_gas_used == U256::zero() {
-        U256::one()
+    if gas_used > gas_target {
+        let delta = base_fee_per_gas * (gas_used - gas_target)
+            / gas_target
+            / BASE_FEE_MAX_CHANGE_DENOMINATOR;
+        base_fee_per_gas + std::cmp::max(delta, U256::one())
     } else {
-        target_gas_used
-    };
+        let delta = base_fee_per_gas * (gas_target - gas_used)
+            / gas_target
+            / BASE_FEE_MAX_CHANGE_DENOMINATOR;
+        base_fee_per_gas.saturating_sub(delta)
+    }
+}
 
-    let new_base_fee = {
-        if gas_used > target_gas_used {
-            base_fee_per_gas
-                + ((base_fee_per_gas * (gas_used - target_gas_used)) / target_gas_used)
-                    / U256::from(8u64)
-        } else {
-            base_fee_per_gas
-                - ((base_fee_per_gas * (target_gas_used - gas_used)) / target_gas_used)
-                    / U256::from(8u64)
+pub fn project_base_fees(
+    gas_used: U256,
+    gas_limit: U256,
+    base_fee_per_gas: U256,
+    assumed_gas_used: U256,
+    blocks: usize,
+) -> Vec<U256> {
+    // the first block follows from the parent's actual usage, the rest from assumed_gas_used
+    let mut projected = Vec::with_capacity(blocks);
+    let mut base_fee = base_fee_per_gas;
+    for i in 0..blocks {
+        let used = if i == 0 { gas_used } else { assumed_gas_used };
+        base_fee = calculate_next_block_base_fee(used, gas_limit, base_fee);
+        projected.push(base_fee);
+    }
+    projected
+}
+
+#[derive(Default, Debug, Clone)]
+pub struct PriorityFeeEstimate {
+    pub percentiles: Vec<f64>,
+    pub fees: Vec<U256>,
+}
+
+impl PriorityFeeEstimate {
+    pub fn at(&self, percentile: f64) -> Option<U256> {
+        self.percentiles
+            .iter()
+            .position(|p| *p == percentile)
+            .map(|idx| self.fees[idx])
+    }
+
+    pub fn from_fee_history(history: &FeeHistory, percentiles: &[f64]) -> Self {
+        // median across blocks for every requested percentile, skipping empty blocks
+        let mut fees = Vec::new();
+        for idx in 0..percentiles.len() {
+            let mut rewards: Vec<U256> = history
+                .reward
+                .iter()
+                .filter(|block_rewards| block_rewards.iter().any(|reward| !reward.is_zero()))
+                .filter_map(|block_rewards| block_rewards.get(idx).copied())
+                .collect();
+            rewards.sort();
+            fees.push(rewards.get(rewards.len() / 2).copied().unwrap_or_default());
         }
-    };
+        Self {
+            percentiles: percentiles.to_vec(),
+            fees,
+        }
+    }
+}
 
-    let seed = rand::thread_rng().gen_range(0..9);
-    new_base_fee + seed
+pub async fn estimate_priority_fees(
+    provider: Arc<Provider<Ws>>,
+    block_count: u64,
+    percentiles: &[f64],
+) -> Result<PriorityFeeEstimate> {
+    let history = provider
+        .fee_history(block_count, BlockNumber::Latest, percentiles)
+        .await?;
+    Ok(PriorityFeeEstimate::from_fee_history(&history, percentiles))
 }
 
 pub async fn get_touched_pool_reserves(
     provider: Arc<Provider<Ws>>,
     block_number: U64,
+) -> Result<HashMap<H160, Reserve>> {
+    get_touched_pool_reserves_range(provider, block_number, block_number).await
+}
+
+// The last reserves of every pool synced between from_block and to_block, both included
+pub async fn get_touched_pool_reserves_range(
+    provider: Arc<Provider<Ws>>,
+    from_block: U64,
+    to_block: U64,
 ) -> Result<HashMap<H160, Reserve>> {
     let sync_event = "Sync(uint112,uint112)";
     let event_filter = Filter::new()
-        .from_block(block_number)
-        .to_block(block_number)
+        .from_block(from_block)
+        .to_block(to_block)
         .event(sync_event);
 
     let logs = provider.get_logs(&event_filter).await?;
 
+    // (block, tx index) of the log each reserve came from, a later one replaces it
     let mut tx_idx = HashMap::new();
     let mut reserves = HashMap::new();
 
@@ -88,9 +159,12 @@ pub async fn get_touched_pool_reserves(
         let decoded = decode(&[ParamType::Uint(256), ParamType::Uint(256)], &log.data);
         match decoded {
             Ok(data) => {
-                let idx = log.transaction_index.unwrap_or_default();
+                let idx = (
+                    log.block_number.unwrap_or_default(),
+                    log.transaction_index.unwrap_or_default(),
+                );
                 let prev_tx_idx = tx_idx.get(&log.address);
-                let update = (*prev_tx_idx.unwrap_or(&U64::zero())) <= idx;
+                let update = *prev_tx_idx.unwrap_or(&(U64::zero(), U64::zero())) <= idx;
 
                 if update {
                     let reserve0 = match data[0] {
@@ -113,3 +187,67 @@ pub async fn get_touched_pool_reserves(
 
     Ok(reserves)
 }
+
+#[cfg(test)]
+mod utils_tests {
+    use super::*;
+
+    #[test]
+    fn calculate_next_block_base_fee_test() {
+        let gas_limit = U256::from(30_000_000);
+        let base_fee = U256::from(100) * U256::exp10(9);
+
+        // full block: +12.5%, empty block: -12.5%, at target: unchanged
+        assert_eq!(
+            calculate_next_block_base_fee(gas_limit, gas_limit, base_fee),
+            U256::from(112_500_000_000u64)
+        );
+        assert_eq!(
+            calculate_next_block_base_fee(U256::zero(), gas_limit, base_fee),
+            U256::from(87_500_000_000u64)
+        );
+        assert_eq!(
+            calculate_next_block_base_fee(gas_limit / 2, gas_limit, base_fee),
+            base_fee
+        );
+        // increases are at least 1 wei
+        assert_eq!(
+            calculate_next_block_base_fee(gas_limit / 2 + 1, gas_limit, U256::from(7)),
+            U256::from(8)
+        );
+    }
+
+    #[test]
+    fn project_base_fees_test() {
+        let gas_limit = U256::from(30_000_000);
+        let base_fee = U256::from(1_000_000_000u64);
+        let projected = project_base_fees(gas_limit, gas_limit, base_fee, gas_limit, 3);
+        assert_eq!(
+            projected,
+            vec![
+                U256::from(1_125_000_000u64),
+                U256::from(1_265_625_000u64),
+                U256::from(1_423_828_125u64),
+            ]
+        );
+    }
+
+    #[test]
+    fn priority_fee_estimate_test() {
+        let history = FeeHistory {
+            base_fee_per_gas: Vec::new(),
+            gas_used_ratio: Vec::new(),
+            oldest_block: U256::zero(),
+            reward: vec![
+                vec![U256::from(1), U256::from(10)],
+                vec![U256::zero(), U256::zero()],
+                vec![U256::from(3), U256::from(30)],
+                vec![U256::from(2), U256::from(20)],
+            ],
+        };
+        let estimate = PriorityFeeEstimate::from_fee_history(&history, &[25.0, 75.0]);
+        assert_eq!(estimate.fees, vec![U256::from(2), U256::from(20)]);
+        assert_eq!(estimate.at(75.0), Some(U256::from(20)));
+        assert_eq!(estimate.at(50.0), None);
+    }
+}